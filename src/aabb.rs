@@ -1,6 +1,9 @@
+#[cfg(test)]
 use ray::Ray;
 use vector::Vec3;
 
+// Reads better as an acronym
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug)]
 pub struct AABB {
     min: Vec3,
@@ -20,31 +23,61 @@ impl AABB {
         self.max
     }
 
+    /// Only tests and the recursive reference traversal use this one
+    #[cfg(test)]
     pub fn hit(&self, ray: &Ray, mut tmin: f32, mut tmax: f32) -> bool {
         for i in 0..3 {
             let inv_d: f32 = 1.0 / ray.direction()[i];
             let mut t0 = (self.min()[i] - ray.origin()[i]) * inv_d;
             let mut t1 = (self.max()[i] - ray.origin()[i]) * inv_d;
-            if inv_d < 1.0 {
+            if inv_d < 0.0 {
                 std::mem::swap(&mut t0, &mut t1);
             }
 
-            if t0 > tmin {
-                tmin = t0;
-            } else {
-                tmin = tmin;
+            tmin = t0.max(tmin);
+            tmax = t1.min(tmax);
+
+            // Entering and leaving a very thin box can round to the same
+            // distance, which still counts as a hit
+            if tmax < tmin {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Same slab test as `hit` but with the reciprocal of the ray direction
+    /// computed up front, since the BVH tests the same ray against many boxes
+    pub fn hit_inv(&self, origin: Vec3, inv_dir: Vec3, mut tmin: f32, mut tmax: f32) -> bool {
+        for i in 0..3 {
+            let mut t0 = (self.min[i] - origin[i]) * inv_dir[i];
+            let mut t1 = (self.max[i] - origin[i]) * inv_dir[i];
+            if inv_dir[i] < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
             }
 
             tmin = t0.max(tmin);
             tmax = t1.min(tmax);
 
-            if tmax <= tmin {
+            // Entering and leaving a very thin box can round to the same
+            // distance, which still counts as a hit
+            if tmax < tmin {
                 return false;
             }
         }
         true
     }
 
+    /// The center of the box. Used by the BVH builder to bin primitives
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+
     pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
         let small = Vec3::new(
             box0.min().x().min(box1.min().x()),
@@ -61,3 +94,73 @@ impl AABB {
         AABB::new(small, big)
     }
 }
+
+#[test]
+fn test_measures() {
+    let aabb = AABB::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 2.0, 3.0));
+    assert_eq!(aabb.centroid(), Vec3::new(0.5, 1.0, 1.5));
+    assert_eq!(aabb.surface_area(), 22.0);
+}
+
+#[test]
+fn test_hit() {
+    let aabb = AABB::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+
+    // Direction components larger than one must not flip the slab interval
+    let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -2.0), 0.0);
+    assert!(aabb.hit(&ray, 0.001, 10.0));
+
+    let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 2.0), 0.0);
+    assert!(aabb.hit(&ray, 0.001, 10.0));
+
+    // Pointing away from the box
+    let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 2.0), 0.0);
+    assert!(!aabb.hit(&ray, 0.001, 10.0));
+
+    // Passing beside the box
+    let ray = Ray::new(Vec3::new(3.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    assert!(!aabb.hit(&ray, 0.001, 10.0));
+}
+
+#[test]
+fn test_hit_inv() {
+    let aabb = AABB::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+    let origins = [Vec3::new(0.0, 0.0, 5.0), Vec3::new(3.0, 0.5, 5.0)];
+    let directions = [Vec3::new(0.1, 0.2, -2.0), Vec3::new(-0.5, 0.0, -1.0)];
+
+    for origin in origins.iter() {
+        for direction in directions.iter() {
+            let ray = Ray::new(*origin, *direction, 0.0);
+            let inv_dir = Vec3::new(
+                1.0 / direction.x(),
+                1.0 / direction.y(),
+                1.0 / direction.z(),
+            );
+            assert_eq!(
+                aabb.hit(&ray, 0.001, 10.0),
+                aabb.hit_inv(*origin, inv_dir, 0.001, 10.0)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_hit_thin() {
+    // A wall far away from the origin that is barely thicker than a float
+    // can resolve. The ray is long enough that both of its slab distances
+    // end up being the same number
+    let aabb = AABB::new(
+        Vec3::new(0.0, 0.0, 554.99994),
+        Vec3::new(555.0, 555.0, 555.00006),
+    );
+    let origin = Vec3::new(278.0, 278.0, -800.0);
+    let direction = Vec3::new(0.3, 0.2, 10.0);
+    let ray = Ray::new(origin, direction, 0.0);
+    let inv_dir = Vec3::new(
+        1.0 / direction.x(),
+        1.0 / direction.y(),
+        1.0 / direction.z(),
+    );
+    assert!(aabb.hit(&ray, 0.001, 1000.0));
+    assert!(aabb.hit_inv(origin, inv_dir, 0.001, 1000.0));
+}
@@ -0,0 +1,247 @@
+use hitable::HitRecord;
+use ray::Ray;
+use scene::Scene;
+use vector::Vec3;
+
+/// The arbitrary output variables a render can have next to its image.
+/// They describe the first surface along every camera path that isn't a
+/// mirror or glass, and split the light into direct and indirect parts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    /// The color of the surface, tinted by any mirrors and glass in front
+    Albedo,
+    /// The shading normal, facing the ray
+    Normal,
+    /// How far the path travelled to reach the surface
+    Depth,
+    /// Where the surface is in world space
+    Position,
+    /// Numbers every shape, or mesh, from one. Zero is the background
+    ObjectId,
+    /// Numbers every distinct material from one. Zero is the background
+    MaterialId,
+    /// Light that reached the camera after at most one diffuse bounce
+    Direct,
+    /// Everything else
+    Indirect,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Direct,
+        Aov::Indirect,
+    ];
+
+    /// The name used for layers and file names
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ObjectId => "object",
+            Aov::MaterialId => "material",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.iter().cloned().find(|aov| aov.name() == name)
+    }
+}
+
+/// What a single camera path found. Integrators add all of their light
+/// here, so the radiance is the sum of the direct and indirect parts
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AovSample {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: f32,
+    pub position: Vec3,
+    pub object: u32,
+    pub material: u32,
+    pub direct: Vec3,
+    pub indirect: Vec3,
+    /// Whether the surface has been recorded yet
+    pub surface: bool,
+    /// Whether the camera ray hit anything. Paths end at their first miss,
+    /// so any hit along them means it did
+    pub hit: bool,
+}
+
+impl AovSample {
+    /// Adds light that was found after the given number of diffuse bounces
+    pub fn add_light(&mut self, light: Vec3, bounces: u32) {
+        if bounces <= 1 {
+            self.direct = self.direct + light;
+        } else {
+            self.indirect = self.indirect + light;
+        }
+    }
+
+    pub fn radiance(&self) -> Vec3 {
+        self.direct + self.indirect
+    }
+
+    /// Records the surface that was hit, unless one already has been.
+    /// The throughput is what the mirrors and glass in front of it let
+    /// through, and the distance how far the path went before this ray
+    pub fn record_surface(
+        &mut self,
+        scene: &Scene,
+        ray: &Ray,
+        rec: &HitRecord,
+        throughput: Vec3,
+        distance: f32,
+    ) {
+        if self.surface {
+            return;
+        }
+        self.surface = true;
+        self.albedo = throughput * rec.material.color(rec);
+        self.normal = Vec3::unit_vec(rec.facing_normal(ray));
+        self.depth = distance + rec.t * ray.direction().length();
+        self.position = rec.p;
+        let (object, material) = scene.ids(rec.material);
+        self.object = object;
+        self.material = material;
+    }
+}
+
+/// The AOVs of a pixel summed over its samples. IDs can't be averaged, so
+/// a pixel keeps those of the first sample that hit anything. The radiance
+/// of every other sample is also summed up on its own, which splits the
+/// samples into two independent halves
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AovPixel {
+    count: u32,
+    sum: AovSample,
+    odd: Vec3,
+}
+
+impl AovPixel {
+    pub fn add(&mut self, sample: &AovSample) {
+        if self.count % 2 == 1 {
+            self.odd = self.odd + sample.radiance();
+        }
+        self.count += 1;
+        let sum = &mut self.sum;
+        sum.albedo = sum.albedo + sample.albedo;
+        sum.normal = sum.normal + sample.normal;
+        sum.depth += sample.depth;
+        sum.position = sum.position + sample.position;
+        sum.direct = sum.direct + sample.direct;
+        sum.indirect = sum.indirect + sample.indirect;
+        if !sum.surface && sample.surface {
+            sum.surface = true;
+            sum.object = sample.object;
+            sum.material = sample.material;
+        }
+    }
+
+    /// The sample count, the sums and the summed radiance of the odd
+    /// samples, which is all it takes to keep adding to the pixel later
+    pub fn parts(&self) -> (u32, &AovSample, Vec3) {
+        (self.count, &self.sum, self.odd)
+    }
+
+    pub fn from_parts(count: u32, sum: AovSample, odd: Vec3) -> AovPixel {
+        AovPixel { count, sum, odd }
+    }
+
+    /// The average of the AOV over all samples. Single values are repeated
+    /// in every channel
+    pub fn value(&self, aov: Aov) -> Vec3 {
+        let splat = |v: f32| Vec3::new(v, v, v);
+        let n = self.count.max(1) as f32;
+        match aov {
+            Aov::Albedo => self.sum.albedo / n,
+            Aov::Normal => self.sum.normal / n,
+            Aov::Depth => splat(self.sum.depth / n),
+            Aov::Position => self.sum.position / n,
+            Aov::ObjectId => splat(self.sum.object as f32),
+            Aov::MaterialId => splat(self.sum.material as f32),
+            Aov::Direct => self.sum.direct / n,
+            Aov::Indirect => self.sum.indirect / n,
+        }
+    }
+
+    /// The average radiance of the even and the odd samples. Their
+    /// difference tells how noisy the pixel is
+    pub fn halves(&self) -> Option<(Vec3, Vec3)> {
+        if self.count < 2 {
+            return None;
+        }
+        let odd_count = self.count / 2;
+        let even = self.sum.radiance() - self.odd;
+        Some((
+            even / (self.count - odd_count) as f32,
+            self.odd / odd_count as f32,
+        ))
+    }
+
+    /// The object or material ID of the pixel
+    pub fn id(&self, aov: Aov) -> u32 {
+        match aov {
+            Aov::ObjectId => self.sum.object,
+            Aov::MaterialId => self.sum.material,
+            _ => 0,
+        }
+    }
+}
+
+#[test]
+fn test_aov_pixel() {
+    for aov in Aov::ALL.iter() {
+        assert_eq!(Aov::from_name(aov.name()), Some(*aov));
+    }
+    assert_eq!(Aov::from_name("beauty"), None);
+
+    let mut sample = AovSample::default();
+    sample.add_light(Vec3::new(1.0, 1.0, 1.0), 0);
+    sample.add_light(Vec3::new(0.5, 0.5, 0.5), 1);
+    sample.add_light(Vec3::new(0.25, 0.0, 0.0), 4);
+    assert_eq!(sample.direct, Vec3::new(1.5, 1.5, 1.5));
+    assert_eq!(sample.indirect, Vec3::new(0.25, 0.0, 0.0));
+    assert_eq!(sample.radiance(), Vec3::new(1.75, 1.5, 1.5));
+
+    // A miss doesn't take the IDs, but is part of the averages
+    let mut pixel = AovPixel::default();
+    pixel.add(&AovSample::default());
+    let hit = AovSample {
+        depth: 4.0,
+        object: 3,
+        material: 2,
+        surface: true,
+        ..AovSample::default()
+    };
+    pixel.add(&hit);
+    pixel.add(&AovSample { object: 7, ..hit });
+    assert_eq!(
+        pixel.value(Aov::Depth),
+        Vec3::new(8.0 / 3.0, 8.0 / 3.0, 8.0 / 3.0)
+    );
+    assert_eq!(pixel.id(Aov::ObjectId), 3);
+    assert_eq!(pixel.id(Aov::MaterialId), 2);
+    assert_eq!(pixel.value(Aov::ObjectId), Vec3::new(3.0, 3.0, 3.0));
+
+    // Samples alternate between the halves
+    assert_eq!(AovPixel::default().halves(), None);
+    let mut pixel = AovPixel::default();
+    for i in 0..5 {
+        let mut sample = AovSample::default();
+        sample.add_light(Vec3::new(i as f32, 0.0, 1.0), 0);
+        pixel.add(&sample);
+    }
+    assert_eq!(
+        pixel.halves(),
+        Some((Vec3::new(2.0, 0.0, 1.0), Vec3::new(2.0, 0.0, 1.0)))
+    );
+}
@@ -1,229 +1,710 @@
-extern crate rand;
-use rand::Rng;
-use std::cmp::Ordering;
 use std::fmt;
 
 use aabb::AABB;
 use hitable::{HitRecord, Hitable};
+#[cfg(test)]
+use hitable_list::HitableList;
 use ray::Ray;
+#[cfg(test)]
+use sampler::Sampler;
+use vector::Vec3;
+
+/// Relative cost of testing a ray against a node's bounding box compared
+/// to intersecting a single primitive. Used by the surface area heuristic
+const TRAVERSAL_COST: f32 = 0.125;
+const INTERSECTION_COST: f32 = 1.0;
+
+/// Deepest level the builder will split to. This bounds the size of
+/// the traversal stack
+const MAX_DEPTH: usize = 64;
 
 #[derive(Debug)]
 pub struct BvhTree<'a> {
-    nodes: Vec<BvhNode<'a>>,
-    root: NodeId,
+    hitables: &'a [Box<dyn Hitable>],
+    // Order in which the leaves reference the hitables
+    indices: Vec<usize>,
+    // Nodes in depth-first order. The root is the first node
+    nodes: Vec<LinearNode>,
+    stats: BuildStats,
 }
 
-#[derive(Debug)]
-struct BvhNode<'a> {
-    left: Option<NodeId>,
-    right: Option<NodeId>,
-    aabb: Option<AABB>,
-    hitable: Option<&'a Box<Hitable>>,
+/// A node of the flattened tree. The first child of an interior node
+/// directly follows its parent so only the offset to the second child
+/// has to be stored. Leaves instead use the offset to point at their
+/// primitives in `BvhTree::indices`. Kept at 32 bytes so two nodes
+/// fit in a cache line
+#[derive(Copy, Clone, Debug)]
+struct LinearNode {
+    aabb: AABB,
+    offset: u32,
+    count: u16,
+    axis: u8,
 }
 
+/// Parameters for the binned SAH builder
 #[derive(Copy, Clone, Debug)]
-pub struct NodeId {
-    index: usize,
+pub struct BvhConfig {
+    /// Number of buckets the centroids are sorted into along each axis
+    pub bins: usize,
+    /// Largest number of primitives a leaf may hold
+    pub max_leaf_size: usize,
 }
 
-impl<'a> BvhTree<'a> {
-    fn hit(&self, id: NodeId, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
-        let node = &self.nodes[id.index];
+impl Default for BvhConfig {
+    fn default() -> BvhConfig {
+        BvhConfig {
+            bins: 16,
+            max_leaf_size: 4,
+        }
+    }
+}
 
-        if node.aabb.is_none() || node.aabb.is_some() && node.aabb.unwrap().hit(r, tmin, tmax) {
-            match node.hitable {
-                Some(ref hitable) => return hitable.hit(r, tmin, tmax),
-                None => {}
-            }
+/// Some numbers about the finished tree to judge its quality
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BuildStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub depth: usize,
+    pub sah_cost: f32,
+}
 
-            let mut hit_left: Option<HitRecord> = None;
-            let mut hit_right: Option<HitRecord> = None;
+/// Cached info about a primitive so we only query its bounding box once
+#[derive(Copy, Clone)]
+struct PrimitiveInfo {
+    index: usize,
+    aabb: AABB,
+    centroid: Vec3,
+}
 
-            if let Some(ref left_index) = node.left {
-                hit_left = self.hit(*left_index, r, tmin, tmax);
-            }
+#[derive(Copy, Clone)]
+struct Bin {
+    count: usize,
+    aabb: Option<AABB>,
+}
 
-            if let Some(ref right_index) = node.right {
-                hit_right = self.hit(*right_index, r, tmin, tmax);
-            }
+impl<'a> BvhTree<'a> {
+    /// Walks the tree front to back with an explicit stack. The child on
+    /// the near side of the split plane is visited first and every hit
+    /// shrinks the ray interval, which prunes most of the far nodes
+    fn traverse(&self, r: &Ray, tmin: f32, tmax: f32, any: bool) -> Option<HitRecord<'_>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
 
-            match hit_left {
-                Some(left) => match hit_right {
-                    Some(right) => {
-                        if left.t < right.t {
-                            return hit_left;
-                        } else {
-                            return hit_right;
+        let origin = r.origin();
+        let direction = r.direction();
+        let inv_dir = Vec3::new(
+            1.0 / direction.x(),
+            1.0 / direction.y(),
+            1.0 / direction.z(),
+        );
+        let dir_is_neg = [inv_dir.x() < 0.0, inv_dir.y() < 0.0, inv_dir.z() < 0.0];
+
+        let mut closest_so_far = tmax;
+        let mut hit_record = None;
+        let mut stack = [0u32; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut current = 0;
+
+        loop {
+            let node = &self.nodes[current];
+            if node.aabb.hit_inv(origin, inv_dir, tmin, closest_so_far) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for index in &self.indices[start..start + node.count as usize] {
+                        if let Some(hit) = self.hitables[*index].hit(r, tmin, closest_so_far) {
+                            if any {
+                                return Some(hit);
+                            }
+                            closest_so_far = hit.t;
+                            hit_record = Some(hit);
                         }
                     }
-                    None => return hit_left,
-                },
-                None => {}
+                } else {
+                    // Push the far child and continue with the near one
+                    if dir_is_neg[node.axis as usize] {
+                        stack[stack_size] = current as u32 + 1;
+                        current = node.offset as usize;
+                    } else {
+                        stack[stack_size] = node.offset;
+                        current += 1;
+                    }
+                    stack_size += 1;
+                    continue;
+                }
             }
 
-            match hit_right {
-                Some(_right) => return hit_right,
-                None => {}
+            if stack_size == 0 {
+                break;
             }
+            stack_size -= 1;
+            current = stack[stack_size] as usize;
         }
 
-        None
+        hit_record
+    }
+
+    /// The straightforward recursive traversal we used before the tree
+    /// was flattened. Kept around to compare against in the benchmarks
+    #[cfg(test)]
+    fn hit_recursive(&self, index: usize, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord<'_>> {
+        let node = &self.nodes[index];
+        if !node.aabb.hit(r, tmin, tmax) {
+            return None;
+        }
+
+        if node.count > 0 {
+            let mut closest_so_far = tmax;
+            let mut hit_record = None;
+            let start = node.offset as usize;
+            for index in &self.indices[start..start + node.count as usize] {
+                if let Some(hit) = self.hitables[*index].hit(r, tmin, closest_so_far) {
+                    closest_so_far = hit.t;
+                    hit_record = Some(hit);
+                }
+            }
+            return hit_record;
+        }
+
+        let hit_left = self.hit_recursive(index + 1, r, tmin, tmax);
+        let hit_right = self.hit_recursive(node.offset as usize, r, tmin, tmax);
+        match (hit_left, hit_right) {
+            (Some(left), Some(right)) => {
+                if left.t < right.t {
+                    Some(left)
+                } else {
+                    Some(right)
+                }
+            }
+            (Some(left), None) => Some(left),
+            (None, right) => right,
+        }
     }
 }
 
 impl<'a> Hitable for BvhTree<'a> {
     fn bounding_box(&self) -> Option<AABB> {
-        self.nodes[self.root.index].aabb
+        self.nodes.first().map(|root| root.aabb)
+    }
+
+    fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord<'_>> {
+        self.traverse(r, tmin, tmax, false)
     }
 
-    fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
-        self.hit(self.root, r, tmin, tmax)
+    fn any_hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+        self.traverse(r, tmin, tmax, true).is_some()
     }
 }
 
 impl<'a> BvhTree<'a> {
-    pub fn new(l: &'a mut [Box<Hitable>]) -> BvhTree<'a> {
+    pub fn new(l: &'a [Box<dyn Hitable>]) -> BvhTree<'a> {
+        BvhTree::with_config(l, BvhConfig::default())
+    }
+
+    /// Builds the tree with a binned surface area heuristic. Splits are
+    /// chosen purely from the primitive bounds, so the same input always
+    /// results in the same tree
+    pub fn with_config(l: &'a [Box<dyn Hitable>], config: BvhConfig) -> BvhTree<'a> {
+        let mut primitives: Vec<PrimitiveInfo> = l
+            .iter()
+            .enumerate()
+            .map(|(index, hitable)| match hitable.bounding_box() {
+                Some(aabb) => PrimitiveInfo {
+                    index,
+                    aabb,
+                    centroid: aabb.centroid(),
+                },
+                None => panic!("No bounding box in BvhTree::with_config"),
+            })
+            .collect();
+
         let mut tree = BvhTree {
-            nodes: Vec::new(),
-            root: NodeId { index: 0 },
+            hitables: l,
+            indices: Vec::new(),
+            nodes: Vec::with_capacity(2 * primitives.len()),
+            stats: BuildStats::default(),
         };
-        tree.root = tree.build(l);
+
+        if !primitives.is_empty() {
+            let config = BvhConfig {
+                bins: config.bins.max(2),
+                max_leaf_size: config.max_leaf_size.max(1).min(u16::MAX as usize),
+            };
+            tree.build(&mut primitives, 0, &config, 1);
+        }
+        tree.indices = primitives.iter().map(|p| p.index).collect();
+        tree.stats.nodes = tree.nodes.len();
+        tree.stats.sah_cost = tree.sah_cost();
 
         tree
     }
 
-    fn build(&mut self, l: &'a mut [Box<Hitable>]) -> NodeId {
-        let axis = rand::thread_rng().gen_range::<i32>(0, 3);
+    pub fn stats(&self) -> BuildStats {
+        self.stats
+    }
+
+    /// Recursively splits the primitives. The slice is reordered so that
+    /// every leaf ends up owning a contiguous range of it. Since we only
+    /// ever see a subslice, `start` tells us where it begins in the full list.
+    /// Nodes are emitted in depth-first order and the index of the new node
+    /// is returned
+    fn build(
+        &mut self,
+        primitives: &mut [PrimitiveInfo],
+        start: usize,
+        config: &BvhConfig,
+        depth: usize,
+    ) -> usize {
+        self.stats.depth = self.stats.depth.max(depth);
+
+        let aabb = primitives.iter().skip(1).fold(primitives[0].aabb, |b, p| {
+            AABB::surrounding_box(&b, &p.aabb)
+        });
 
-        match axis {
-            0 => l.sort_by(|a, b| box_x_compare(a, b)),
-            1 => l.sort_by(|a, b| box_y_compare(a, b)),
-            2 => l.sort_by(|a, b| box_z_compare(a, b)),
-            _ => panic!("Unexpected axis"),
+        // Past the maximum depth everything that is left goes into one leaf
+        if primitives.len() == 1 || depth >= MAX_DEPTH {
+            return self.new_leaf(aabb, start, primitives.len());
         }
 
-        let left: NodeId;
-        let right: NodeId;
+        let centroid_bounds = primitives.iter().skip(1).fold(
+            AABB::new(primitives[0].centroid, primitives[0].centroid),
+            |b, p| AABB::surrounding_box(&b, &AABB::new(p.centroid, p.centroid)),
+        );
+
+        let (axis, mid) = match self.find_split(primitives, &aabb, &centroid_bounds, config) {
+            Some((axis, split, cost)) => {
+                let leaf_cost = INTERSECTION_COST * primitives.len() as f32;
+                if primitives.len() <= config.max_leaf_size && leaf_cost <= cost {
+                    return self.new_leaf(aabb, start, primitives.len());
+                }
+
+                let min = centroid_bounds.min()[axis];
+                let extent = centroid_bounds.max()[axis] - min;
+                let mid = partition(primitives, |p| {
+                    bin_index(p.centroid[axis], min, extent, config.bins) < split
+                });
+                (axis, mid)
+            }
+            None => {
+                // All centroids coincide so no split can separate them.
+                // Only split in the middle if the leaf would get too big
+                if primitives.len() <= config.max_leaf_size {
+                    return self.new_leaf(aabb, start, primitives.len());
+                }
+                (0, primitives.len() / 2)
+            }
+        };
 
-        if l.len() == 1 {
-            return self.new_leaf(&l[0]);
-        } else if l.len() == 2 {
-            left = self.new_leaf(&l[0]);
-            right = self.new_leaf(&l[1]);
+        // Leaves at the maximum depth can't count past u16::MAX, so lopsided
+        // splits can't go on forever. A side that is too big for the levels
+        // that are left gets the primitives halved by index instead
+        let capacity = capacity(depth + 1);
+        let mid = if mid > capacity || primitives.len() - mid > capacity {
+            primitives.len() / 2
         } else {
-            let half_len = l.len() / 2;
-            let (left_hitables, right_hitables) = l.split_at_mut(half_len);
+            mid
+        };
 
-            left = self.build(left_hitables);
-            right = self.build(right_hitables);
-        }
+        // The first child is built right after its parent, the offset
+        // to the second child is only known once the first is finished
+        let index = self.new_node(aabb, axis);
+        let (left_primitives, right_primitives) = primitives.split_at_mut(mid);
+        self.build(left_primitives, start, config, depth + 1);
+        let right = self.build(right_primitives, start + mid, config, depth + 1);
+        self.nodes[index].offset = right as u32;
+
+        index
+    }
+
+    /// Finds the cheapest bin boundary over all three axes. Returns the
+    /// axis, the index of the first bin on the right side and the cost
+    /// of the split
+    fn find_split(
+        &self,
+        primitives: &[PrimitiveInfo],
+        aabb: &AABB,
+        centroid_bounds: &AABB,
+        config: &BvhConfig,
+    ) -> Option<(usize, usize, f32)> {
+        let mut best: Option<(usize, usize, f32)> = None;
+        let parent_area = aabb.surface_area();
+
+        for axis in 0..3 {
+            let min = centroid_bounds.min()[axis];
+            let extent = centroid_bounds.max()[axis] - min;
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let mut bins = vec![
+                Bin {
+                    count: 0,
+                    aabb: None,
+                };
+                config.bins
+            ];
+            for p in primitives {
+                let bin = &mut bins[bin_index(p.centroid[axis], min, extent, config.bins)];
+                bin.count += 1;
+                bin.aabb = Some(match bin.aabb {
+                    Some(b) => AABB::surrounding_box(&b, &p.aabb),
+                    None => p.aabb,
+                });
+            }
+
+            // Sweep from the right to get the area and count of every
+            // possible right side, then from the left to evaluate the cost
+            let mut right_area = vec![0.0; config.bins];
+            let mut right_count = vec![0; config.bins];
+            let mut acc = Bin {
+                count: 0,
+                aabb: None,
+            };
+            for i in (1..config.bins).rev() {
+                acc = merge_bins(&acc, &bins[i]);
+                right_area[i] = acc.aabb.map_or(0.0, |b| b.surface_area());
+                right_count[i] = acc.count;
+            }
 
-        if let Some(left_box) = self.nodes[left.index].aabb {
-            if let Some(right_box) = self.nodes[right.index].aabb {
-                return self.new_node(
-                    AABB::surrounding_box(&left_box, &right_box),
-                    Some(left),
-                    Some(right),
-                );
+            let mut acc = Bin {
+                count: 0,
+                aabb: None,
+            };
+            for split in 1..config.bins {
+                acc = merge_bins(&acc, &bins[split - 1]);
+                if acc.count == 0 || right_count[split] == 0 {
+                    continue;
+                }
+
+                let left_area = acc.aabb.map_or(0.0, |b| b.surface_area());
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST
+                        * (acc.count as f32 * left_area
+                            + right_count[split] as f32 * right_area[split])
+                        / parent_area.max(f32::EPSILON);
+
+                let better = match best {
+                    Some((_, _, best_cost)) => cost < best_cost,
+                    None => true,
+                };
+                if better {
+                    best = Some((axis, split, cost));
+                }
             }
         }
 
-        panic!("No bounding box in BvhNode::build");
+        best
     }
 
-    fn new_leaf(&mut self, hitable: &'a Box<Hitable>) -> NodeId {
+    fn new_leaf(&mut self, aabb: AABB, start: usize, count: usize) -> usize {
+        assert!(count <= u16::MAX as usize, "too many primitives in a leaf");
         let next_index = self.nodes.len();
 
-        self.nodes.push(BvhNode {
-            left: None,
-            right: None,
-            aabb: hitable.bounding_box(),
-            hitable: Some(hitable),
+        self.stats.leaves += 1;
+        self.nodes.push(LinearNode {
+            aabb,
+            offset: start as u32,
+            count: count as u16,
+            axis: 0,
         });
 
-        return NodeId { index: next_index };
+        next_index
     }
 
-    fn new_node(&mut self, aabb: AABB, left: Option<NodeId>, right: Option<NodeId>) -> NodeId {
+    fn new_node(&mut self, aabb: AABB, axis: usize) -> usize {
         let next_index = self.nodes.len();
 
-        self.nodes.push(BvhNode {
-            left,
-            right,
-            aabb: Some(aabb),
-            hitable: None,
+        self.nodes.push(LinearNode {
+            aabb,
+            offset: 0,
+            count: 0,
+            axis: axis as u8,
         });
 
-        return NodeId { index: next_index };
+        next_index
     }
 
-    fn number_hittables(&self, id: NodeId) -> usize {
-        let node = &self.nodes[id.index];
-        let local_hitable = if node.hitable.is_some() { 1 } else { 0 };
-        let count_left = if let Some(left_index) = node.left {
-            self.number_hittables(left_index)
-        } else {
-            0
-        };
-        let count_right = if let Some(right_index) = node.right {
-            self.number_hittables(right_index)
-        } else {
-            0
+    /// Expected cost of intersecting a random ray with the tree according
+    /// to the surface area heuristic
+    fn sah_cost(&self) -> f32 {
+        let root_area = match self.nodes.first() {
+            Some(root) => root.aabb.surface_area().max(f32::EPSILON),
+            None => return 0.0,
         };
 
-        local_hitable + count_left + count_right
+        self.nodes
+            .iter()
+            .map(|node| {
+                let probability = node.aabb.surface_area() / root_area;
+                if node.count > 0 {
+                    probability * INTERSECTION_COST * node.count as f32
+                } else {
+                    probability * TRAVERSAL_COST
+                }
+            })
+            .sum()
+    }
+
+    fn number_hittables(&self) -> usize {
+        self.nodes.iter().map(|node| node.count as usize).sum()
     }
 }
 
 impl<'a> fmt::Display for BvhTree<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = self.stats();
         write!(
             f,
-            "BVH with {:?} hitables and {:?} nodes",
-            self.number_hittables(self.root),
-            self.nodes.len()
+            "BVH with {:?} hitables, {:?} nodes ({:?} leaves), depth {:?} and SAH cost {:.2}",
+            self.number_hittables(),
+            stats.nodes,
+            stats.leaves,
+            stats.depth,
+            stats.sah_cost
         )
     }
 }
 
-fn box_x_compare(a: &Box<Hitable>, b: &Box<Hitable>) -> Ordering {
-    if let Some(box_left) = a.bounding_box() {
-        if let Some(box_right) = b.bounding_box() {
-            if let Some(cmp) = box_left.min().x().partial_cmp(&box_right.min().x()) {
-                return cmp;
-            } else {
-                panic!("Can't compare");
-            }
+fn bin_index(centroid: f32, min: f32, extent: f32, bins: usize) -> usize {
+    let index = ((centroid - min) / extent * bins as f32) as usize;
+    index.min(bins - 1)
+}
+
+fn merge_bins(a: &Bin, b: &Bin) -> Bin {
+    let aabb = match (a.aabb, b.aabb) {
+        (Some(x), Some(y)) => Some(AABB::surrounding_box(&x, &y)),
+        (Some(x), None) => Some(x),
+        (None, y) => y,
+    };
+    Bin {
+        count: a.count + b.count,
+        aabb,
+    }
+}
+
+/// Moves all primitives for which the predicate holds to the front of the
+/// slice and returns how many there are
+fn partition<F>(primitives: &mut [PrimitiveInfo], predicate: F) -> usize
+where
+    F: Fn(&PrimitiveInfo) -> bool,
+{
+    let mut first = 0;
+    for i in 0..primitives.len() {
+        if predicate(&primitives[i]) {
+            primitives.swap(first, i);
+            first += 1;
         }
     }
+    first
+}
 
-    panic!("No bounding box in BvhNode::new");
+/// The most primitives a subtree that starts at the depth can hold, when
+/// it is halved all the way down to leaves at the maximum depth
+fn capacity(depth: usize) -> usize {
+    let levels = MAX_DEPTH.saturating_sub(depth);
+    ((u16::MAX as u128) << levels).min(usize::MAX as u128) as usize
 }
 
-fn box_y_compare(a: &Box<Hitable>, b: &Box<Hitable>) -> Ordering {
-    if let Some(box_left) = a.bounding_box() {
-        if let Some(box_right) = b.bounding_box() {
-            if let Some(cmp) = box_left.min().y().partial_cmp(&box_right.min().y()) {
-                return cmp;
-            } else {
-                panic!("Can't compare");
-            }
+#[cfg(test)]
+fn sphere_grid() -> HitableList {
+    use material::Material;
+    use sphere::Sphere;
+    use texture::Constant;
+
+    let mut list = HitableList::new();
+    for x in -5..5 {
+        for z in -5..5 {
+            list.push(Box::new(Sphere::new(
+                Vec3::new(x as f32, (x * z) as f32 * 0.1, z as f32),
+                0.3,
+                Material::Lambertian(Constant::shared(Vec3::new(0.5, 0.5, 0.5))),
+            )));
+        }
+    }
+    list
+}
+
+#[test]
+fn test_matches_list() {
+    let list = sphere_grid();
+    let bvh = BvhTree::new(&list.objs);
+
+    for i in 0..40 {
+        for j in 0..40 {
+            let target = Vec3::new(i as f32 * 0.25 - 5.0, 0.0, j as f32 * 0.25 - 5.0);
+            let origin = Vec3::new(3.0, 8.0, 7.0);
+            let ray = Ray::new(origin, target - origin, 0.0);
+
+            let expected = list.intersect(&ray, 0.001, 100.0).map(|rec| rec.t);
+            let actual = bvh.hit(&ray, 0.001, 100.0).map(|rec| rec.t);
+            let recursive = bvh.hit_recursive(0, &ray, 0.001, 100.0).map(|rec| rec.t);
+            assert_eq!(expected, actual);
+            assert_eq!(expected, recursive);
+            assert_eq!(expected.is_some(), bvh.any_hit(&ray, 0.001, 100.0));
         }
     }
+}
+
+#[test]
+fn test_node_size() {
+    assert_eq!(std::mem::size_of::<LinearNode>(), 32);
+}
+
+#[test]
+fn test_deterministic() {
+    let list = sphere_grid();
+    let first = BvhTree::new(&list.objs);
+    let second = BvhTree::new(&list.objs);
+
+    assert_eq!(first.indices, second.indices);
+    assert_eq!(format!("{}", first), format!("{}", second));
+    assert_eq!(first.number_hittables(), list.len());
+}
+
+#[test]
+fn test_leaf_size() {
+    let list = sphere_grid();
+    for max_leaf_size in 1..6 {
+        let bvh = BvhTree::with_config(
+            &list.objs,
+            BvhConfig {
+                bins: 8,
+                max_leaf_size,
+            },
+        );
+
+        assert!(bvh
+            .nodes
+            .iter()
+            .all(|node| node.count as usize <= max_leaf_size));
+        assert_eq!(
+            bvh.stats().leaves,
+            bvh.nodes.iter().filter(|n| n.count > 0).count()
+        );
+        assert_eq!(bvh.stats().nodes, 2 * bvh.stats().leaves - 1);
+    }
+}
+
+#[test]
+fn test_empty() {
+    let list = HitableList::new();
+    let bvh = BvhTree::new(&list.objs);
+    let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
 
-    panic!("No bounding box in BvhNode::new");
+    assert!(bvh.hit(&ray, 0.001, 10.0).is_none());
+    assert!(bvh.bounding_box().is_none());
 }
 
-fn box_z_compare(a: &Box<Hitable>, b: &Box<Hitable>) -> Ordering {
-    if let Some(box_left) = a.bounding_box() {
-        if let Some(box_right) = b.bounding_box() {
-            if let Some(cmp) = box_left.min().z().partial_cmp(&box_right.min().z()) {
-                return cmp;
-            } else {
-                panic!("Can't compare");
+/// Compares the flattened traversal against the recursive one. Run with
+/// `cargo test --release bench_traversal -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_traversal() {
+    use material::Material;
+    use sphere::Sphere;
+    use std::time::Instant;
+    use texture::Constant;
+
+    let mut list = HitableList::new();
+    for x in -100..100 {
+        for z in -100..100 {
+            let offset = ((x * 7 + z * 13) % 10) as f32 * 0.05;
+            list.push(Box::new(Sphere::new(
+                Vec3::new(x as f32 * 0.5 + offset, offset, z as f32 * 0.5 - offset),
+                0.2,
+                Material::Lambertian(Constant::shared(Vec3::new(0.5, 0.5, 0.5))),
+            )));
+        }
+    }
+    let bvh = BvhTree::new(&list.objs);
+    println!("{}", bvh);
+
+    let origin = Vec3::new(10.0, 15.0, 60.0);
+    let rays: Vec<Ray> = (0..500 * 500)
+        .map(|i| {
+            let target = Vec3::new(
+                (i % 500) as f32 * 0.2 - 50.0,
+                0.0,
+                (i / 500) as f32 * 0.2 - 50.0,
+            );
+            Ray::new(origin, target - origin, 0.0)
+        })
+        .collect();
+
+    let start = Instant::now();
+    let recursive_hits = rays
+        .iter()
+        .filter(|r| bvh.hit_recursive(0, r, 0.001, f32::MAX).is_some())
+        .count();
+    let recursive_time = start.elapsed();
+
+    let start = Instant::now();
+    let ordered_hits = rays
+        .iter()
+        .filter(|r| bvh.hit(r, 0.001, f32::MAX).is_some())
+        .count();
+    let ordered_time = start.elapsed();
+
+    let start = Instant::now();
+    let any_hits = rays
+        .iter()
+        .filter(|r| bvh.any_hit(r, 0.001, f32::MAX))
+        .count();
+    let any_time = start.elapsed();
+
+    assert_eq!(recursive_hits, ordered_hits);
+    assert_eq!(recursive_hits, any_hits);
+    println!("{} rays, {} hits", rays.len(), ordered_hits);
+    println!("recursive: {:?}", recursive_time);
+    println!("ordered:   {:?}", ordered_time);
+    println!("any hit:   {:?}", any_time);
+}
+
+#[test]
+fn test_mesh_matches_list() {
+    use camera::Camera;
+    use parser::Parser;
+
+    let list = Parser::OBJ(String::from("obj-data/african_head.obj")).parse();
+    let bvh = BvhTree::new(&list.objs);
+    assert_eq!(bvh.number_hittables(), list.len());
+
+    let cam = Camera::new(
+        Vec3::new(1.0, 0.5, 3.0),
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        40.0,
+        1.0,
+        0.0,
+        3.0,
+        0.0,
+        1.0,
+    );
+
+    let size = 64;
+    let mut sampler = Sampler::new(0, 0);
+    let mut hits = 0;
+    for y in 0..size {
+        for x in 0..size {
+            let ray = cam.get_ray(x as f32 / size as f32, y as f32 / size as f32, &mut sampler);
+            let expected = list.intersect(&ray, 0.001, f32::MAX).map(|rec| rec.t);
+            let actual = bvh.hit(&ray, 0.001, f32::MAX).map(|rec| rec.t);
+            assert_eq!(expected, actual);
+
+            if actual.is_some() {
+                hits += 1;
             }
         }
     }
 
-    panic!("No bounding box in BvhNode::new");
+    // Make sure we actually looked at the mesh
+    assert!(hits > size * size / 4);
+}
+
+#[test]
+fn test_capacity() {
+    assert_eq!(capacity(MAX_DEPTH), u16::MAX as usize);
+    assert_eq!(capacity(MAX_DEPTH - 1), 2 * u16::MAX as usize);
+    assert_eq!(capacity(MAX_DEPTH - 16), (u16::MAX as usize) << 16);
+    assert_eq!(capacity(0), usize::MAX);
 }
@@ -1,7 +1,5 @@
-extern crate rand;
-
-use rand::{thread_rng, Rng};
 use ray::Ray;
+use sampler::Sampler;
 use std::f32;
 use vector::Vec3;
 
@@ -23,6 +21,8 @@ pub struct Camera {
  * add more documentation on this later
  */
 impl Camera {
+    // Every one of them is needed to place the camera
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         lookfrom: Vec3,
         lookat: Vec3,
@@ -65,27 +65,24 @@ impl Camera {
         }
     }
 
-    fn random_unit_in_disk(&self) -> Vec3 {
-        let mut rng = thread_rng();
+    fn random_unit_in_disk(&self, sampler: &mut Sampler) -> Vec3 {
         let v1 = Vec3::new(1.0, 1.0, 0.0);
-        let mut p = Vec3::new(
-            rng.gen_range(0.0, 1.0),
-            rng.gen_range(0.0, 1.0),
-            rng.gen_range(0.0, 1.0),
-        );
+        let mut p = Vec3::new(sampler.next_f32(), sampler.next_f32(), sampler.next_f32());
         while p.dot(&p) >= 1.0 {
-            p = (Vec3::new(rng.gen_range(0.0, 1.0), rng.gen_range(0.0, 1.0), 0.0) * 2.0) - v1;
+            p = (Vec3::new(sampler.next_f32(), sampler.next_f32(), 0.0) * 2.0) - v1;
         }
         p
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.random_unit_in_disk() * self.lens_radius;
+    /// The ray through the point of the image plane, from a random point on
+    /// the lens at a random time within the shutter interval
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut Sampler) -> Ray {
+        let rd = self.random_unit_in_disk(sampler) * self.lens_radius;
         let offset = self.u * rd.x() + self.v * rd.y();
         let direction = self.lower_left_corner + (self.horizontal * s) + (self.vertical * t)
             - self.origin
             - offset;
-        let time = self.time0 + rand::random::<f32>() * (self.time1 - self.time0);
+        let time = self.time0 + sampler.next_f32() * (self.time1 - self.time0);
         Ray::new(self.origin + offset, direction, time)
     }
 }
@@ -0,0 +1,371 @@
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use aov::{AovPixel, AovSample};
+use film::{Estimate, Film, Pixel};
+#[cfg(test)]
+use hitable_list::HitableList;
+#[cfg(test)]
+use material::Material;
+use renderer::Progress;
+#[cfg(test)]
+use renderer::{sphere_in_view, Renderer, Sampling};
+#[cfg(test)]
+use scene::{Background, Scene};
+#[cfg(test)]
+use sphere::Sphere;
+#[cfg(test)]
+use texture::Constant;
+use vector::Vec3;
+
+/// Identifies checkpoint files and the version of their layout
+const MAGIC: &[u8; 8] = b"RPTCKPT4";
+
+/// 64-bit FNV-1a. Unlike the standard library's hasher its output is
+/// specified, so hashes stored in checkpoints stay valid across builds
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Saves a render in progress along with the hash of the scene and
+/// settings it belongs to. The file is written next to the destination
+/// first, so a crash while saving never destroys the previous checkpoint.
+/// Every sample is seeded from the render's seed, its pixel and its index,
+/// so the checkpoint needs no other sampler state: resuming it renders the
+/// exact same image as a render that was never interrupted
+pub fn save(path: &Path, hash: u64, progress: &Progress) -> io::Result<()> {
+    // The suffix is added rather than swapped in, so checkpoints that only
+    // differ in their extension don't share a temporary file
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    {
+        let mut w = BufWriter::new(File::create(&temporary)?);
+        write(&mut w, hash, progress)?;
+        w.flush()?;
+    }
+    fs::rename(&temporary, path)
+}
+
+/// Loads a render in progress and the hash it was saved with
+pub fn load(path: &Path) -> io::Result<(u64, Progress)> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn write<W: Write>(w: &mut W, hash: u64, progress: &Progress) -> io::Result<()> {
+    let film = &progress.film;
+    w.write_all(MAGIC)?;
+    w.write_all(&hash.to_le_bytes())?;
+    w.write_all(&progress.seed.to_le_bytes())?;
+    w.write_all(&progress.passes.to_le_bytes())?;
+    w.write_all(&film.width().to_le_bytes())?;
+    w.write_all(&film.height().to_le_bytes())?;
+    w.write_all(&[film.has_aovs() as u8])?;
+
+    for y in 0..film.height() {
+        for x in 0..film.width() {
+            let pixel = film.pixel(x, y);
+            let (count, mean, m2) = pixel.estimate.parts();
+            write_vec(w, pixel.sum)?;
+            w.write_all(&pixel.weight.to_le_bytes())?;
+            w.write_all(&pixel.coverage.to_le_bytes())?;
+            w.write_all(&count.to_le_bytes())?;
+            w.write_all(&mean.to_le_bytes())?;
+            w.write_all(&m2.to_le_bytes())?;
+        }
+    }
+
+    if film.has_aovs() {
+        for y in 0..film.height() {
+            for x in 0..film.width() {
+                let (count, sum, odd) = film.aov_pixel(x, y).parts();
+                w.write_all(&count.to_le_bytes())?;
+                for v in [sum.albedo, sum.normal].iter() {
+                    write_vec(w, *v)?;
+                }
+                w.write_all(&sum.depth.to_le_bytes())?;
+                write_vec(w, sum.position)?;
+                w.write_all(&sum.object.to_le_bytes())?;
+                w.write_all(&sum.material.to_le_bytes())?;
+                write_vec(w, sum.direct)?;
+                write_vec(w, sum.indirect)?;
+                w.write_all(&[sum.surface as u8])?;
+                write_vec(w, odd)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn read<R: Read>(r: &mut R) -> io::Result<(u64, Progress)> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a checkpoint file",
+        ));
+    }
+
+    let hash = read_u64(r)?;
+    let seed = read_u64(r)?;
+    let passes = read_u32(r)?;
+    let width = read_u32(r)?;
+    let height = read_u32(r)?;
+    let aovs = read_u8(r)? != 0;
+
+    let mut film = Film::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let sum = read_vec(r)?;
+            let weight = read_f32(r)?;
+            let coverage = read_f32(r)?;
+            let estimate = Estimate::from_parts(read_u32(r)?, read_f32(r)?, read_f32(r)?);
+            *film.pixel_mut(x, y) = Pixel {
+                sum,
+                weight,
+                coverage,
+                estimate,
+            };
+        }
+    }
+
+    if aovs {
+        film.enable_aovs();
+        for y in 0..height {
+            for x in 0..width {
+                let count = read_u32(r)?;
+                let sum = AovSample {
+                    albedo: read_vec(r)?,
+                    normal: read_vec(r)?,
+                    depth: read_f32(r)?,
+                    position: read_vec(r)?,
+                    object: read_u32(r)?,
+                    material: read_u32(r)?,
+                    direct: read_vec(r)?,
+                    indirect: read_vec(r)?,
+                    surface: read_u8(r)? != 0,
+                    ..AovSample::default()
+                };
+                let odd = read_vec(r)?;
+                *film.aov_pixel_mut(x, y) = AovPixel::from_parts(count, sum, odd);
+            }
+        }
+    }
+
+    Ok((hash, Progress { film, seed, passes }))
+}
+
+fn write_vec<W: Write>(w: &mut W, v: Vec3) -> io::Result<()> {
+    for value in [v.x(), v.y(), v.z()].iter() {
+        w.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    r.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(r)?))
+}
+
+fn read_vec<R: Read>(r: &mut R) -> io::Result<Vec3> {
+    Ok(Vec3::new(read_f32(r)?, read_f32(r)?, read_f32(r)?))
+}
+
+#[test]
+fn test_round_trip() {
+    let mut film = Film::new(3, 2);
+    film.pixel_mut(2, 1).add(Vec3::new(0.5, 1.5, 2.5), 1.0);
+    film.pixel_mut(2, 1).add(Vec3::new(0.25, 0.0, 1.0), 0.0);
+    film.pixel_mut(0, 0).add(Vec3::new(1.0, 1.0, 1.0), 1.0);
+    film.enable_aovs();
+    let mut sample = AovSample {
+        albedo: Vec3::new(0.5, 0.25, 0.125),
+        normal: Vec3::new(0.0, 1.0, 0.0),
+        depth: 3.0,
+        position: Vec3::new(1.0, 2.0, 3.0),
+        object: 4,
+        material: 2,
+        surface: true,
+        ..AovSample::default()
+    };
+    sample.add_light(Vec3::new(1.0, 0.5, 0.0), 1);
+    sample.add_light(Vec3::new(0.0, 0.5, 1.0), 2);
+    film.aov_pixel_mut(1, 0).add(&sample);
+    film.aov_pixel_mut(1, 0).add(&AovSample::default());
+    let progress = Progress {
+        film,
+        seed: 1234,
+        passes: 7,
+    };
+
+    let mut bytes = Vec::new();
+    write(&mut bytes, 42, &progress).unwrap();
+    let (hash, loaded) = read(&mut bytes.as_slice()).unwrap();
+    assert_eq!(hash, 42);
+    assert_eq!(loaded.seed, 1234);
+    assert_eq!(loaded.passes, 7);
+    assert_eq!(loaded.film.width(), 3);
+    assert_eq!(loaded.film.height(), 2);
+    for y in 0..2 {
+        for x in 0..3 {
+            assert_eq!(loaded.film.pixel(x, y), progress.film.pixel(x, y));
+            assert_eq!(loaded.film.aov_pixel(x, y), progress.film.aov_pixel(x, y));
+        }
+    }
+
+    // Films without AOVs don't get any
+    let plain_progress = Progress {
+        film: Film::new(3, 2),
+        seed: 1234,
+        passes: 7,
+    };
+    let mut plain = Vec::new();
+    write(&mut plain, 42, &plain_progress).unwrap();
+    assert!(!read(&mut plain.as_slice()).unwrap().1.film.has_aovs());
+
+    // Truncated and foreign files are rejected
+    assert!(read(&mut &bytes[..bytes.len() - 1]).is_err());
+    assert!(read(&mut &b"P6 3 2 255"[..]).is_err());
+}
+
+#[test]
+fn test_save() {
+    use std::env;
+
+    // Saving leaves nothing but the checkpoint behind
+    let dir = env::temp_dir().join(format!("pathtracer-save-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (seed, name) in [(1, "a.ckpt"), (2, "a.bin")].iter() {
+        save(&dir.join(name), 42, &Progress::new(2, 2, *seed)).unwrap();
+    }
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["a.bin", "a.ckpt"]);
+    assert_eq!(load(&dir.join("a.ckpt")).unwrap().1.seed, 1);
+    assert_eq!(load(&dir.join("a.bin")).unwrap().1.seed, 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resumed_render() {
+    let (_, mut world) = sphere_in_view(0.1);
+    world.push(Box::new(Sphere::new(
+        Vec3::new(1.6, 0.0, -2.0),
+        0.5,
+        Material::Metal(Constant::shared(Vec3::new(0.8, 0.8, 0.8)), 0.3),
+    )));
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+    let renderer = |samples, passes| {
+        let mut renderer = Renderer::new(sphere_in_view(0.1).0);
+        renderer.set_sampling(Sampling::Fixed(samples));
+        renderer.set_passes(passes);
+        renderer.set_aovs(true);
+        renderer
+    };
+
+    let uninterrupted = renderer(8, 4).render(Progress::new(10, 10, 5), &scene, |_| {});
+
+    // Stopping after half the passes saves the same film and AOVs that the
+    // first half of the uninterrupted render had
+    let half = renderer(4, 2).render(Progress::new(10, 10, 5), &scene, |_| {});
+    let mut bytes = Vec::new();
+    write(&mut bytes, 0, &half).unwrap();
+    let (_, loaded) = read(&mut bytes.as_slice()).unwrap();
+    let resumed = renderer(8, 4).render(loaded, &scene, |_| {});
+
+    assert_eq!(resumed.passes, uninterrupted.passes);
+    for y in 0..10 {
+        for x in 0..10 {
+            assert_eq!(resumed.film.pixel(x, y), uninterrupted.film.pixel(x, y));
+            assert_eq!(
+                resumed.film.aov_pixel(x, y),
+                uninterrupted.film.aov_pixel(x, y)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_fingerprint() {
+    fn world(fuzz: f32, center: Vec3) -> HitableList {
+        let color = Vec3::new(0.5, 0.5, 0.5);
+        let mut world = HitableList::new();
+        world.push(Box::new(Sphere::new(
+            center,
+            1.0,
+            Material::Lambertian(Constant::shared(color)),
+        )));
+        world.push(Box::new(Sphere::new(
+            Vec3::new(2.0, 0.0, 0.0),
+            0.5,
+            Material::Metal(Constant::shared(color), fuzz),
+        )));
+        world
+    }
+    let fingerprint = |world: &HitableList, background: Background| {
+        let mut hasher = Fnv::default();
+        Scene::new(world, world.lights(), background).fingerprint(&world.objs, &mut hasher);
+        hasher.finish()
+    };
+
+    let origin = Vec3::new(0.0, 0.0, 0.0);
+    let reference = fingerprint(&world(0.1, origin), Background::Sky);
+
+    // The same layout hashes the same, any change to it doesn't. Textures
+    // are left to the caller
+    assert_eq!(fingerprint(&world(0.1, origin), Background::Sky), reference);
+    assert!(fingerprint(&world(0.2, origin), Background::Sky) != reference);
+    let moved = Vec3::new(0.0, 0.01, 0.0);
+    assert!(fingerprint(&world(0.1, moved), Background::Sky) != reference);
+    let black = Background::Constant(origin);
+    assert!(fingerprint(&world(0.1, origin), black) != reference);
+
+    // Even a tiny sphere far from the others counts
+    let mut bigger = world(0.1, origin);
+    bigger.push(Box::new(Sphere::new(
+        Vec3::new(500.0, 0.0, 0.0),
+        0.01,
+        Material::Lambertian(Constant::shared(origin)),
+    )));
+    assert!(fingerprint(&bigger, Background::Sky) != reference);
+}
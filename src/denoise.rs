@@ -0,0 +1,252 @@
+extern crate rayon;
+
+use aov::Aov;
+#[cfg(test)]
+use aov::AovSample;
+use film::Film;
+#[cfg(test)]
+use sampler::Sampler;
+use vector::Vec3;
+
+use denoise::rayon::prelude::*;
+
+/// How far apart two pixels can be and still be averaged together
+const RADIUS: i32 = 7;
+/// Pixels are compared by the patches of 3 by 3 pixels around them
+const PATCH: i32 = 1;
+/// How large a difference in color is tolerated, relative to the noise.
+/// The features already keep surfaces apart, so this can be more lenient
+/// than for color alone
+const K: f32 = 0.7;
+/// How different the features of pixels can be before they are kept apart
+const SIGMA_ALBEDO: f32 = 0.1;
+const SIGMA_NORMAL: f32 = 0.3;
+/// Relative to the depth of the pixel
+const SIGMA_DEPTH: f32 = 0.05;
+
+/// Removes the noise from the colors of a film that captured AOVs, with
+/// non-local means guided by the albedo, normal and depth of every pixel.
+/// Two pixels are averaged if the patches around them look alike given
+/// how noisy they are, and if they show similar surfaces. The noise is
+/// estimated from the difference between the two halves of the samples
+pub fn denoise(film: &Film) -> Vec<Vec3> {
+    assert!(film.has_aovs(), "denoising needs the AOVs of the film");
+    let (width, height) = (film.width() as usize, film.height() as usize);
+    let albedo = film.aov_values(Aov::Albedo);
+    let normals = film.aov_values(Aov::Normal);
+    let depths: Vec<f32> = film.aov_values(Aov::Depth).iter().map(|d| d.x()).collect();
+
+    // Lighting is smoother than the textures it falls on, so the albedo
+    // is divided out before filtering and multiplied back in after
+    let modulation: Vec<Vec3> = albedo
+        .iter()
+        .map(|a| per_channel(*a, |v| if v > 0.01 { v } else { 1.0 }))
+        .collect();
+    let lighting: Vec<Vec3> = film
+        .colors()
+        .iter()
+        .zip(modulation.iter())
+        .map(|(c, m)| *c / *m)
+        .collect();
+    let variances = variances(film, &modulation, &lighting);
+
+    // Every pixel in the window is one offset. For each of them the patch
+    // distances of all pixels are computed at once, which lets the patches
+    // be summed up with a box filter
+    let neighbour = |i: usize, dx: i32, dy: i32| {
+        let x = (i % width) as i32 + dx;
+        let y = (i / width) as i32 + dy;
+        (x, y)
+    };
+    let mut sums = vec![Vec3::default(); width * height];
+    let mut weights = vec![0.0f32; width * height];
+    for dy in -RADIUS..=RADIUS {
+        for dx in -RADIUS..=RADIUS {
+            let distances: Vec<f32> = (0..width * height)
+                .into_par_iter()
+                .map(|i| {
+                    // The patches reach over the edges of the image, where
+                    // the pixels along it are repeated
+                    let (x, y) = neighbour(i, dx, dy);
+                    let x = x.clamp(0, width as i32 - 1) as usize;
+                    let y = y.clamp(0, height as i32 - 1) as usize;
+                    color_distance(&lighting, &variances, i, y * width + x)
+                })
+                .collect();
+            let distances = box_filter(&distances, width, height, PATCH);
+
+            sums.par_iter_mut()
+                .zip(weights.par_iter_mut())
+                .enumerate()
+                .for_each(|(i, (sum, weight))| {
+                    let (x, y) = neighbour(i, dx, dy);
+                    if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                        return;
+                    }
+                    let q = y as usize * width + x as usize;
+                    let w = (-distances[i].max(0.0)).exp()
+                        * feature_weight(&albedo, &normals, &depths, i, q);
+                    *sum = *sum + lighting[q] * w;
+                    *weight += w;
+                });
+        }
+    }
+
+    // A pixel is always its own neighbour, so no weight is ever zero
+    sums.iter()
+        .zip(weights.iter())
+        .zip(modulation.iter())
+        .map(|((sum, weight), m)| *sum / *weight * *m)
+        .collect()
+}
+
+fn per_channel<F: Fn(f32) -> f32>(v: Vec3, f: F) -> Vec3 {
+    Vec3::new(f(v.x()), f(v.y()), f(v.z()))
+}
+
+/// The variance of every pixel's mean, per channel. Two independent
+/// halves of the same samples differ by twice that on average. A single
+/// pixel's estimate is very noisy itself, so it is smoothed with its
+/// neighbours
+fn variances(film: &Film, modulation: &[Vec3], lighting: &[Vec3]) -> Vec<Vec3> {
+    let (width, height) = (film.width() as usize, film.height() as usize);
+    let raw: Vec<Vec3> = (0..width * height)
+        .map(|i| {
+            let (x, y) = ((i % width) as u32, (i / width) as u32);
+            match film.aov_pixel(x, y).halves() {
+                Some((even, odd)) => {
+                    let difference = (even - odd) / modulation[i];
+                    per_channel(difference, |d| d * d / 4.0)
+                }
+                // Without a second sample all we can say is that it could
+                // be way off
+                None => per_channel(lighting[i], |l| l * l),
+            }
+        })
+        .collect();
+
+    let smoothed: Vec<Vec<f32>> = (0..3)
+        .map(|k| {
+            let channel: Vec<f32> = raw.iter().map(|v| v[k]).collect();
+            box_filter(&channel, width, height, 1)
+        })
+        .collect();
+    (0..width * height)
+        .map(|i| Vec3::new(smoothed[0][i], smoothed[1][i], smoothed[2][i]))
+        .collect()
+}
+
+/// How different two pixels are beyond what their noise explains, as in
+/// Rousselle et al.'s "Robust Denoising using Feature and Color
+/// Information". Negative if they are closer than expected
+fn color_distance(lighting: &[Vec3], variances: &[Vec3], p: usize, q: usize) -> f32 {
+    let mut distance = 0.0;
+    for k in 0..3 {
+        let (vp, vq) = (variances[p][k], variances[q][k]);
+        let difference = lighting[p][k] - lighting[q][k];
+        distance += (difference * difference - (vp + vp.min(vq))) / (1e-10 + K * K * (vp + vq));
+    }
+    distance / 3.0
+}
+
+/// Keeps apart pixels that show different surfaces
+fn feature_weight(albedo: &[Vec3], normals: &[Vec3], depths: &[f32], p: usize, q: usize) -> f32 {
+    let albedo = (albedo[p] - albedo[q]).squared_length() / (SIGMA_ALBEDO * SIGMA_ALBEDO);
+    let normal = (normals[p] - normals[q]).squared_length() / (SIGMA_NORMAL * SIGMA_NORMAL);
+    let depth = (depths[p] - depths[q]) / (SIGMA_DEPTH * depths[p].max(1e-4));
+    (-(albedo + normal + depth * depth)).exp()
+}
+
+/// The average over the square of the given radius around every value.
+/// Values along the edges are repeated outwards
+fn box_filter(values: &[f32], width: usize, height: usize, radius: i32) -> Vec<f32> {
+    let size = (2 * radius + 1) as f32;
+    let clamp = |v: i32, max: usize| v.clamp(0, max as i32 - 1) as usize;
+    let horizontal: Vec<f32> = (0..width * height)
+        .map(|i| {
+            let (x, y) = ((i % width) as i32, i / width);
+            (-radius..=radius)
+                .map(|d| values[y * width + clamp(x + d, width)])
+                .sum::<f32>()
+                / size
+        })
+        .collect();
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, (i / width) as i32);
+            (-radius..=radius)
+                .map(|d| horizontal[clamp(y + d, height) * width + x])
+                .sum::<f32>()
+                / size
+        })
+        .collect()
+}
+
+/// A film of two surfaces side by side, with uniform light and noise
+#[cfg(test)]
+fn noisy_film(size: u32, samples: u32) -> Film {
+    let mut film = Film::new(size, size);
+    film.enable_aovs();
+    let mut sampler = Sampler::new(11, 0);
+    for y in 0..size {
+        for x in 0..size {
+            let (albedo, normal) = if x < size / 2 {
+                (Vec3::new(0.2, 0.2, 0.2), Vec3::new(0.0, 1.0, 0.0))
+            } else {
+                (Vec3::new(0.8, 0.4, 0.2), Vec3::new(1.0, 0.0, 0.0))
+            };
+            for _ in 0..samples {
+                let mut sample = AovSample {
+                    albedo,
+                    normal,
+                    depth: 3.0,
+                    surface: true,
+                    ..AovSample::default()
+                };
+                // Half of the paths find the light, which is twice as bright
+                let light = if sampler.next_f32() < 0.5 { 2.0 } else { 0.0 };
+                sample.add_light(albedo * light, 0);
+                film.pixel_mut(x, y).add(sample.radiance(), 1.0);
+                film.aov_pixel_mut(x, y).add(&sample);
+            }
+        }
+    }
+    film
+}
+
+#[test]
+fn test_denoise() {
+    let film = noisy_film(24, 8);
+    let denoised = denoise(&film);
+    let colors = film.colors();
+
+    // The truth is just the albedo
+    let error = |image: &[Vec3]| -> f32 {
+        image
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let albedo = film.aov_pixel((i % 24) as u32, (i / 24) as u32);
+                (*c - albedo.value(Aov::Albedo)).squared_length()
+            })
+            .sum::<f32>()
+            / image.len() as f32
+    };
+    assert!(error(&denoised) < 0.1 * error(&colors));
+
+    // Nothing leaks over the edge between the surfaces
+    for y in 0..24 {
+        let left = denoised[y * 24 + 11];
+        let right = denoised[y * 24 + 12];
+        assert!((left - Vec3::new(0.2, 0.2, 0.2)).length() < 0.05);
+        assert!((right - Vec3::new(0.8, 0.4, 0.2)).length() < 0.15);
+    }
+}
+
+#[test]
+fn test_box_filter() {
+    let values = [0.0, 3.0, 6.0, 9.0];
+    // A single row, so the vertical pass repeats it
+    assert_eq!(box_filter(&values, 4, 1, 1), vec![1.0, 3.0, 6.0, 8.0]);
+    assert_eq!(box_filter(&values, 2, 2, 0), values.to_vec());
+}
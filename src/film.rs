@@ -0,0 +1,355 @@
+use std::f32;
+
+use aov::{Aov, AovPixel};
+use filter::Filter;
+#[cfg(test)]
+use filter::Shape;
+use tile::Tile;
+use vector::Vec3;
+
+/// Running mean and variance of the luminance of a pixel's samples,
+/// following Welford's algorithm
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Estimate {
+    count: u32,
+    mean: f32,
+    m2: f32,
+}
+
+impl Estimate {
+    pub fn add(&mut self, luminance: f32) {
+        self.count += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    /// The sample count, mean and sum of squared differences, which is all
+    /// it takes to continue the estimate later
+    pub fn parts(&self) -> (u32, f32, f32) {
+        (self.count, self.mean, self.m2)
+    }
+
+    pub fn from_parts(count: u32, mean: f32, m2: f32) -> Estimate {
+        Estimate { count, mean, m2 }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Standard error of the mean relative to the mean itself. Dark pixels
+    /// are measured against a small floor so they don't sample forever
+    pub fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::MAX;
+        }
+        let variance = self.m2 / (self.count - 1) as f32;
+        (variance / self.count as f32).sqrt() / self.mean.max(0.01)
+    }
+}
+
+/// Everything a pixel has gathered so far. The sums include the samples
+/// of neighbouring pixels that the filter reaches, while the estimate only
+/// knows about the samples that were taken for this pixel
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pixel {
+    /// The samples, each multiplied by its filter weight
+    pub sum: Vec3,
+    pub weight: f32,
+    /// How many of the samples saw geometry instead of the background
+    pub coverage: f32,
+    pub estimate: Estimate,
+}
+
+impl Pixel {
+    /// Adds a sample taken for this pixel with a weight of one, like the
+    /// default box filter does, along with whether it covers the pixel
+    #[cfg(test)]
+    pub fn add(&mut self, sample: Vec3, coverage: f32) {
+        self.splat(sample, coverage, 1.0);
+        self.estimate.add(sample.luminance());
+    }
+
+    /// Adds a sample with the weight the filter gives it
+    pub fn splat(&mut self, sample: Vec3, coverage: f32, weight: f32) {
+        self.sum = self.sum + sample * weight;
+        self.coverage += coverage * weight;
+        self.weight += weight;
+    }
+
+    /// The weighted average of all samples. Filters with negative lobes
+    /// can leave a pixel with nothing to divide by, which is shown black
+    pub fn color(&self) -> Vec3 {
+        if self.weight > 0.0 {
+            self.sum / self.weight
+        } else {
+            Vec3::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    /// The fraction of samples that hit something
+    pub fn alpha(&self) -> f32 {
+        if self.weight > 0.0 {
+            (self.coverage / self.weight).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The part of the film that the samples of a tile reach, which is the
+/// tile and a margin as wide as the filter. Tiles are rendered into their
+/// own so they can run in parallel, and are then added to the film one
+/// after another in a fixed order, which keeps the sums the same no matter
+/// which thread finished first
+pub struct FilmTile {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+    filter: Filter,
+    pixels: Vec<Pixel>,
+    /// The horizontal weights of the sample being splatted
+    columns: Vec<f32>,
+}
+
+impl FilmTile {
+    pub fn new(tile: &Tile, filter: Filter, width: u32, height: u32) -> FilmTile {
+        let margin = filter.margin();
+        let (x0, y0) = (
+            tile.x0.saturating_sub(margin),
+            tile.y0.saturating_sub(margin),
+        );
+        let (x1, y1) = (
+            (tile.x1 + margin).min(width),
+            (tile.y1 + margin).min(height),
+        );
+        FilmTile {
+            x0,
+            y0,
+            x1,
+            y1,
+            filter,
+            pixels: vec![Pixel::default(); ((x1 - x0) * (y1 - y0)) as usize],
+            columns: Vec::new(),
+        }
+    }
+
+    /// Adds a sample at the given position on the film, in pixels from the
+    /// top left, to every pixel whose center is within the filter radius.
+    /// A sample that lies exactly on the edge between two pixels only goes
+    /// to the one to its right or below it
+    pub fn splat(&mut self, x: f32, y: f32, sample: Vec3, coverage: f32) {
+        let r = self.filter.radius;
+        // The pixels whose centers c satisfy x - r < c <= x + r, clipped to
+        // the tile's part of the film
+        let range = |p: f32, start: u32, end: u32| {
+            let first = ((p - r - 0.5).floor() as i64 + 1).max(start as i64);
+            let last = ((p + r - 0.5).floor() as i64).min(end as i64 - 1);
+            first..=last
+        };
+        let columns = range(x, self.x0, self.x1);
+        let filter = self.filter;
+        // The filter is separable, so every column's weight is only
+        // computed once
+        self.columns.clear();
+        self.columns.extend(
+            columns
+                .clone()
+                .map(|px| filter.weight(x - (px as f32 + 0.5))),
+        );
+
+        let width = (self.x1 - self.x0) as i64;
+        for py in range(y, self.y0, self.y1) {
+            let row = filter.weight(y - (py as f32 + 0.5));
+            if row == 0.0 {
+                continue;
+            }
+            for (px, column) in columns.clone().zip(self.columns.iter()) {
+                let weight = row * column;
+                if weight != 0.0 {
+                    let index = (py - self.y0 as i64) * width + px - self.x0 as i64;
+                    self.pixels[index as usize].splat(sample, coverage, weight);
+                }
+            }
+        }
+    }
+}
+
+/// Floating point accumulation buffer for the rendered image. Samples are
+/// summed up unquantized, so passes can be added one after another. Rows
+/// are stored top to bottom
+pub struct Film {
+    width: u32,
+    height: u32,
+    pixels: Vec<Pixel>,
+    /// Empty unless AOVs are captured
+    aovs: Vec<AovPixel>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![Pixel::default(); width as usize * height as usize],
+            aovs: Vec::new(),
+        }
+    }
+
+    /// Makes room for the AOVs of every pixel, if there isn't already
+    pub fn enable_aovs(&mut self) {
+        if self.aovs.is_empty() {
+            self.aovs = vec![AovPixel::default(); self.pixels.len()];
+        }
+    }
+
+    pub fn has_aovs(&self) -> bool {
+        !self.aovs.is_empty()
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &Pixel {
+        &self.pixels[self.index(x, y)]
+    }
+
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
+        let index = self.index(x, y);
+        &mut self.pixels[index]
+    }
+
+    /// Panics if AOVs aren't enabled
+    pub fn aov_pixel(&self, x: u32, y: u32) -> &AovPixel {
+        &self.aovs[self.index(x, y)]
+    }
+
+    pub fn aov_pixel_mut(&mut self, x: u32, y: u32) -> &mut AovPixel {
+        let index = self.index(x, y);
+        &mut self.aovs[index]
+    }
+
+    /// How many samples each pixel took
+    pub fn counts(&self) -> Vec<u32> {
+        self.pixels.iter().map(|p| p.estimate.count()).collect()
+    }
+
+    /// All samples taken over the whole image
+    pub fn total_samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.estimate.count() as u64).sum()
+    }
+
+    /// The average number of samples per pixel
+    pub fn average_samples(&self) -> f64 {
+        self.total_samples() as f64 / self.pixels.len().max(1) as f64
+    }
+
+    /// The average color of every pixel, still linear
+    pub fn colors(&self) -> Vec<Vec3> {
+        self.pixels.iter().map(|p| p.color()).collect()
+    }
+
+    /// The coverage of every pixel
+    pub fn alphas(&self) -> Vec<f32> {
+        self.pixels.iter().map(|p| p.alpha()).collect()
+    }
+
+    /// The value of the AOV for every pixel
+    pub fn aov_values(&self, aov: Aov) -> Vec<Vec3> {
+        self.aovs.iter().map(|p| p.value(aov)).collect()
+    }
+
+    /// The object or material ID of every pixel
+    pub fn aov_ids(&self, aov: Aov) -> Vec<u32> {
+        self.aovs.iter().map(|p| p.id(aov)).collect()
+    }
+
+    /// Adds the samples that were splatted into a tile
+    pub fn add_tile(&mut self, tile: &FilmTile) {
+        let mut pixels = tile.pixels.iter();
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let splatted = pixels.next().unwrap();
+                let pixel = self.pixel_mut(x, y);
+                pixel.sum = pixel.sum + splatted.sum;
+                pixel.weight += splatted.weight;
+                pixel.coverage += splatted.coverage;
+            }
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+}
+
+#[test]
+fn test_estimate() {
+    let mut estimate = Estimate::default();
+    for x in [1.0, 2.0, 3.0, 4.0].iter() {
+        estimate.add(*x);
+    }
+    assert_eq!(estimate.mean, 2.5);
+    // Sample variance 5/3 over 4 samples
+    let expected = (5.0f32 / 12.0).sqrt() / 2.5;
+    assert!((estimate.relative_error() - expected).abs() < 1e-6);
+}
+
+#[test]
+fn test_film() {
+    let mut film = Film::new(2, 1);
+    film.pixel_mut(1, 0).add(Vec3::new(1.0, 0.25, 0.0), 1.0);
+    film.pixel_mut(1, 0).add(Vec3::new(0.0, 0.25, 0.0), 0.0);
+    assert_eq!(film.pixel(1, 0).color(), Vec3::new(0.5, 0.25, 0.0));
+    assert_eq!(film.alphas(), vec![0.0, 0.5]);
+    assert_eq!(film.counts(), vec![0, 2]);
+    assert_eq!(film.total_samples(), 2);
+    assert_eq!(film.average_samples(), 1.0);
+    assert_eq!(film.colors()[1], Vec3::new(0.5, 0.25, 0.0));
+}
+
+#[test]
+fn test_splat() {
+    let tile = Tile {
+        x0: 0,
+        y0: 0,
+        x1: 2,
+        y1: 2,
+    };
+
+    // The default box keeps every sample in its own pixel, even on the edge
+    let mut film = Film::new(3, 3);
+    let mut splats = FilmTile::new(&tile, Filter::default(), 3, 3);
+    splats.splat(1.0, 0.5, Vec3::new(1.0, 1.0, 1.0), 1.0);
+    splats.splat(0.25, 1.75, Vec3::new(2.0, 2.0, 2.0), 0.0);
+    film.add_tile(&splats);
+    assert_eq!(film.pixel(1, 0).color(), Vec3::new(1.0, 1.0, 1.0));
+    assert_eq!(film.pixel(0, 1).color(), Vec3::new(2.0, 2.0, 2.0));
+    assert_eq!(film.pixel(0, 1).alpha(), 0.0);
+    assert_eq!(film.pixel(0, 0).weight, 0.0);
+    // Splatting doesn't count samples, that's up to the pixel they belong to
+    assert_eq!(film.total_samples(), 0);
+
+    // A tent reaches over the edge of the tile. A sample on the corner of
+    // four pixels counts the same for all of them, and whatever would land
+    // outside of the film is dropped
+    let mut film = Film::new(3, 3);
+    let mut splats = FilmTile::new(&tile, Filter::new(Shape::Tent, 1.0), 3, 3);
+    splats.splat(2.0, 2.0, Vec3::new(1.0, 0.0, 0.0), 1.0);
+    splats.splat(0.5, 0.5, Vec3::new(0.0, 1.0, 0.0), 1.0);
+    film.add_tile(&splats);
+    for (x, y) in [(1, 1), (2, 1), (1, 2), (2, 2)].iter() {
+        assert_eq!(film.pixel(*x, *y).weight, 0.25);
+        assert_eq!(film.pixel(*x, *y).color(), Vec3::new(1.0, 0.0, 0.0));
+    }
+    assert_eq!(film.pixel(0, 0).weight, 1.0);
+    assert_eq!(film.pixel(0, 0).color(), Vec3::new(0.0, 1.0, 0.0));
+    assert_eq!(film.pixel(0, 2).weight, 0.0);
+}
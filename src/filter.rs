@@ -0,0 +1,146 @@
+use std::f32;
+
+/// The shape of a reconstruction filter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    /// Every sample within the radius counts the same
+    Box,
+    /// Falls off linearly towards the radius
+    Tent,
+    /// A Gaussian with a standard deviation of a third of the radius,
+    /// shifted down so it reaches zero at the radius
+    Gaussian,
+    /// The Mitchell-Netravali cubic with B = C = 1/3, which sharpens a
+    /// little through its negative lobes
+    Mitchell,
+    /// A sinc windowed by a wider sinc that ends at the radius. The
+    /// sharpest, but rings around hard edges
+    Lanczos,
+}
+
+impl Shape {
+    /// The radius the shape is usually used with
+    pub fn default_radius(self) -> f32 {
+        match self {
+            Shape::Box => 0.5,
+            Shape::Tent => 1.0,
+            Shape::Gaussian => 1.5,
+            Shape::Mitchell => 2.0,
+            Shape::Lanczos => 3.0,
+        }
+    }
+}
+
+/// Decides how much a sample counts towards a pixel by how far it is from
+/// the pixel's center, in pixels. The weight is the product of the weights
+/// of the horizontal and vertical distances, and is zero from the radius
+/// on. Weights don't have to be normalized, since every pixel is divided
+/// by the sum of its own
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filter {
+    pub shape: Shape,
+    pub radius: f32,
+}
+
+impl Default for Filter {
+    /// A box that covers exactly one pixel, so every sample only counts
+    /// towards the pixel it was taken in
+    fn default() -> Filter {
+        Filter::new(Shape::Box, Shape::Box.default_radius())
+    }
+}
+
+impl Filter {
+    pub fn new(shape: Shape, radius: f32) -> Filter {
+        Filter {
+            shape,
+            radius: radius.max(1e-3),
+        }
+    }
+
+    /// How many pixels beyond its own a sample can reach in every direction
+    pub fn margin(&self) -> u32 {
+        ((self.radius + 0.5).ceil() - 1.0).max(0.0) as u32
+    }
+
+    /// The weight of the distance along one axis
+    pub fn weight(&self, d: f32) -> f32 {
+        let r = self.radius;
+        let d = d.abs();
+        if d > r {
+            return 0.0;
+        }
+        match self.shape {
+            Shape::Box => 1.0,
+            Shape::Tent => 1.0 - d / r,
+            Shape::Gaussian => {
+                let sigma = r / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(d) - gaussian(r)
+            }
+            Shape::Mitchell => mitchell(2.0 * d / r),
+            Shape::Lanczos => sinc(d) * sinc(d / r),
+        }
+    }
+}
+
+/// The Mitchell-Netravali cubic over [0, 2]
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let value = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b)
+    } else {
+        (-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    };
+    value / 6.0
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    let x = f32::consts::PI * x;
+    x.sin() / x
+}
+
+#[test]
+fn test_filters() {
+    let shapes = [
+        Shape::Box,
+        Shape::Tent,
+        Shape::Gaussian,
+        Shape::Mitchell,
+        Shape::Lanczos,
+    ];
+    for shape in shapes.iter() {
+        let filter = Filter::new(*shape, shape.default_radius());
+        let r = filter.radius;
+        // Largest in the center, symmetric and nothing beyond the radius
+        assert!(filter.weight(0.0) > 0.0);
+        for d in [0.1, 0.3, 0.45].iter() {
+            assert!(filter.weight(*d) <= filter.weight(0.0));
+            assert_eq!(filter.weight(*d), filter.weight(-*d));
+        }
+        assert_eq!(filter.weight(r + 0.01), 0.0);
+        assert_eq!(filter.weight(-r - 0.01), 0.0);
+        // The continuous shapes fade out towards the radius
+        if *shape != Shape::Box {
+            assert!(filter.weight(r).abs() < 1e-5);
+        }
+    }
+
+    // The sharpening filters have negative lobes
+    assert!(Filter::new(Shape::Mitchell, 2.0).weight(1.5) < 0.0);
+    assert!(Filter::new(Shape::Lanczos, 3.0).weight(1.5) < 0.0);
+    assert_eq!(Filter::new(Shape::Tent, 2.0).weight(1.0), 0.5);
+
+    assert_eq!(Filter::default().margin(), 0);
+    assert_eq!(Filter::new(Shape::Tent, 1.0).margin(), 1);
+    assert_eq!(Filter::new(Shape::Gaussian, 1.5).margin(), 1);
+    assert_eq!(Filter::new(Shape::Mitchell, 2.0).margin(), 2);
+}
@@ -3,14 +3,60 @@ use std::fmt;
 use aabb::AABB;
 use material::Material;
 use ray::Ray;
+use sampler::Sampler;
 use vector::Vec3;
 
 pub trait Hitable: Sync {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
     fn bounding_box(&self) -> Option<AABB>;
+
+    /// Checks if anything at all is hit within the interval. Shadow rays
+    /// do not care about the closest hit, so acceleration structures can
+    /// stop looking after the first one
+    fn any_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.hit(ray, t_min, t_max).is_some()
+    }
+
+    /// The material of a single shape. Meshes share theirs between all of
+    /// their triangles, which makes it tell objects apart as well
+    fn material(&self) -> Option<&Material> {
+        None
+    }
+
+    /// Emitters are collected into the list of lights that gets sampled
+    /// directly. Only shapes that implement the sampling functions below
+    /// should ever return true
+    fn is_emitter(&self) -> bool {
+        false
+    }
+
+    /// Picks a direction from the origin towards a random point on the
+    /// surface. None if there is no way to see the surface from there
+    fn sample_direction(&self, _origin: Vec3, _sampler: &mut Sampler) -> Option<Vec3> {
+        None
+    }
+
+    /// The density of `sample_direction` picking the direction, measured
+    /// in solid angle. Zero if the direction misses the surface
+    fn pdf_value(&self, _origin: Vec3, _direction: Vec3) -> f32 {
+        0.0
+    }
+}
+
+/// Turns the density of uniformly picking a point on a surface into a
+/// density over the directions seen from the origin of the ray that hit
+/// the point at t
+pub fn area_to_solid_angle(area: f32, direction: Vec3, t: f32, normal: Vec3) -> f32 {
+    let distance_squared = t * t * direction.squared_length();
+    let cosine = direction.dot(&normal).abs() / direction.length();
+    if cosine < 1e-6 {
+        0.0
+    } else {
+        distance_squared / (cosine * area)
+    }
 }
 
-impl fmt::Debug for Hitable {
+impl fmt::Debug for dyn Hitable {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Hitable {{ aabb: {:?} }}", self.bounding_box())
     }
@@ -19,20 +65,40 @@ impl fmt::Debug for Hitable {
 /// A data structure that holds some info about the object that was
 /// intersected in the scene
 #[derive(Copy, Clone)]
-pub struct HitRecord {
+pub struct HitRecord<'a> {
     pub t: f32,
     pub p: Vec3,
     pub normal: Vec3,
-    pub material: Material,
+    /// Barycentric coordinates of the hit with respect to the second and
+    /// third vertex. Only meaningful for triangles
+    pub barycentric: (f32, f32),
+    /// Surface coordinates used to look up textures
+    pub u: f32,
+    pub v: f32,
+    pub material: &'a Material,
 }
 
-impl HitRecord {
-    pub fn new() -> HitRecord {
+impl<'a> HitRecord<'a> {
+    pub fn new(material: &'a Material) -> HitRecord<'a> {
         HitRecord {
             t: 0.0,
             p: Vec3::new(0.0, 0.0, 0.0),
             normal: Vec3::new(0.0, 0.0, 0.0),
-            material: Material::Lambertian(Vec3::new(0.0, 0.0, 0.0)),
+            barycentric: (0.0, 0.0),
+            u: 0.0,
+            v: 0.0,
+            material,
+        }
+    }
+
+    /// Opaque surfaces reflect on whichever side they were hit. Walls made
+    /// of quads or triangles can be seen from the back, so the normal has
+    /// to be flipped towards the ray
+    pub fn facing_normal(&self, ray: &Ray) -> Vec3 {
+        if ray.direction().dot(&self.normal) > 0.0 {
+            -self.normal
+        } else {
+            self.normal
         }
     }
 }
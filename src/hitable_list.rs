@@ -1,12 +1,20 @@
+use std::sync::Arc;
+
 use aabb::AABB;
 use hitable::{HitRecord, Hitable};
+#[cfg(test)]
 use material::Material;
+use mesh::TriangleMesh;
 use ray::Ray;
+#[cfg(test)]
 use sphere::{MovingSphere, Sphere};
+#[cfg(test)]
+use texture::Constant;
+#[cfg(test)]
 use vector::Vec3;
 
 pub struct HitableList {
-    pub objs: Vec<Box<Hitable>>,
+    pub objs: Vec<Box<dyn Hitable>>,
 }
 
 /// A list that holds our intersectable objects. I tried to make
@@ -14,26 +22,31 @@ pub struct HitableList {
 /// better once I know more about Rust
 impl HitableList {
     pub fn new() -> HitableList {
-        let objs: Vec<Box<Hitable>> = Vec::new();
+        let objs: Vec<Box<dyn Hitable>> = Vec::new();
         HitableList { objs }
     }
 
-    pub fn push(&mut self, obj: Box<Hitable>) {
+    pub fn push(&mut self, obj: Box<dyn Hitable>) {
         self.objs.push(obj);
     }
 
+    /// Adds every triangle of the mesh to the list. The triangles only
+    /// reference the mesh so none of the vertex data gets copied
+    pub fn push_mesh(&mut self, mesh: TriangleMesh) {
+        let mesh = Arc::new(mesh);
+        for triangle in TriangleMesh::triangles(&mesh) {
+            self.push(Box::new(triangle));
+        }
+    }
+
     /// A function to find the object that is closest to the current view point
-    pub fn intersect(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn intersect(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let mut temp_rec = None;
         let mut closest_so_far = t_max;
         for obj in &self.objs {
-            let intersect_result = obj.hit(r, t_min, closest_so_far);
-            match intersect_result {
-                Some(hit) => {
-                    closest_so_far = hit.t;
-                    temp_rec = Some(hit);
-                }
-                None => (),
+            if let Some(hit) = obj.hit(r, t_min, closest_so_far) {
+                closest_so_far = hit.t;
+                temp_rec = Some(hit);
             }
         }
         temp_rec
@@ -43,35 +56,37 @@ impl HitableList {
         self.objs.len()
     }
 
-    /// New bounding box construction test. Will need to see if this
-    /// adaptation works correctly
-    pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
-        if self.objs.len() < 1 {
-            return None;
-        }
-        let first_true = self.objs[0].bounding_box();
+    /// Every object that gives off light and can be sampled directly
+    pub fn lights(&self) -> Vec<&dyn Hitable> {
+        self.objs
+            .iter()
+            .filter(|obj| obj.is_emitter())
+            .map(|obj| obj.as_ref())
+            .collect()
+    }
+}
 
-        let mut bb = if first_true.is_none() {
-            None
-        } else {
-            first_true
-        };
+impl Hitable for HitableList {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.intersect(r, t_min, t_max)
+    }
 
-        if bb.is_none() {
-            return bb;
-        }
+    fn any_hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.objs.iter().any(|obj| obj.any_hit(r, t_min, t_max))
+    }
 
-        // TODO Check this for correctness at some point
-        for i in 1..self.objs.len() {
-            let temp = self.objs[0].bounding_box();
-            if temp.is_some() {
-                bb = Some(AABB::surrounding_box(&bb.unwrap(), &temp.unwrap()));
-            } else {
-                return None;
-            }
+    /// The box surrounding every object in the list. If a single object
+    /// is unbounded the whole list is considered unbounded
+    fn bounding_box(&self) -> Option<AABB> {
+        let mut bb: Option<AABB> = None;
+        for obj in &self.objs {
+            let obj_box = obj.bounding_box()?;
+            bb = match bb {
+                Some(b) => Some(AABB::surrounding_box(&b, &obj_box)),
+                None => Some(obj_box),
+            };
         }
-
-        return bb;
+        bb
     }
 }
 
@@ -81,7 +96,7 @@ fn test_intersection() {
     list.push(Box::new(Sphere::new(
         Vec3::new(0.0, 0.0, 0.0),
         1.0,
-        Material::Lambertian(Vec3::new(0.0, 0.0, 0.0)),
+        Material::Lambertian(Constant::shared(Vec3::new(0.0, 0.0, 0.0))),
     )));
 
     // Setting up a ray that is in front of the sphere going directly into it
@@ -101,7 +116,7 @@ fn test_intersection() {
         0.0,
         1.0,
         1.0,
-        Material::Lambertian(Vec3::new(0.0, 0.0, 0.0)),
+        Material::Lambertian(Constant::shared(Vec3::new(0.0, 0.0, 0.0))),
     )));
 
     let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
@@ -110,3 +125,24 @@ fn test_intersection() {
     let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 5.0, 0.0), 0.0);
     assert!(list.intersect(&ray, 0.001, 10.0).is_none());
 }
+
+#[test]
+fn test_bounding_box() {
+    let mut list = HitableList::new();
+    assert!(list.bounding_box().is_none());
+
+    list.push(Box::new(Sphere::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Lambertian(Constant::shared(Vec3::new(0.0, 0.0, 0.0))),
+    )));
+    list.push(Box::new(Sphere::new(
+        Vec3::new(4.0, 0.0, 0.0),
+        1.0,
+        Material::Lambertian(Constant::shared(Vec3::new(0.0, 0.0, 0.0))),
+    )));
+
+    let bb = list.bounding_box().unwrap();
+    assert_eq!(bb.min(), Vec3::new(-1.0, -1.0, -1.0));
+    assert_eq!(bb.max(), Vec3::new(5.0, 1.0, 1.0));
+}
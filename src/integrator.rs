@@ -0,0 +1,621 @@
+use std::f32;
+
+use aov::AovSample;
+use hitable::HitRecord;
+#[cfg(test)]
+use hitable_list::HitableList;
+#[cfg(test)]
+use material::Material;
+use ray::Ray;
+use sampler::{cosine_direction, Sampler};
+#[cfg(test)]
+use scene::Background;
+use scene::Scene;
+#[cfg(test)]
+use sphere::Sphere;
+#[cfg(test)]
+use texture::Constant;
+use vector::Vec3;
+
+/// A rendering algorithm. It estimates the radiance arriving along a ray,
+/// which is averaged over many samples per pixel by the renderer
+pub trait Integrator: Sync {
+    /// The radiance arriving along the ray
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Vec3 {
+        let mut aov = AovSample::default();
+        self.trace(ray, scene, sampler, &mut aov);
+        aov.radiance()
+    }
+
+    /// Finds the same light as `li`, but adds it to the AOVs split by how
+    /// many diffuse bounces it took, and records the first surface that
+    /// isn't a mirror or glass
+    fn trace(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler, aov: &mut AovSample);
+}
+
+/// Decides how long paths get. No path is ever longer than the max depth.
+/// After the min depth every bounce may randomly end the path with Russian
+/// roulette, which is more likely the less light the path can still carry
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Termination {
+    pub max_depth: u32,
+    pub min_depth: u32,
+}
+
+impl Default for Termination {
+    fn default() -> Termination {
+        Termination {
+            max_depth: 50,
+            min_depth: 3,
+        }
+    }
+}
+
+impl Termination {
+    /// Called after a bounce. Returns the throughput to carry on with or
+    /// None if the path should end. Surviving paths are boosted by the
+    /// chance they had to survive, so on average nothing is lost
+    fn roulette(&self, depth: u32, throughput: Vec3, sampler: &mut Sampler) -> Option<Vec3> {
+        if depth < self.min_depth {
+            return Some(throughput);
+        }
+        let survival = throughput
+            .x()
+            .max(throughput.y())
+            .max(throughput.z())
+            .clamp(0.05, 1.0);
+        if sampler.next_f32() < survival {
+            Some(throughput / survival)
+        } else {
+            None
+        }
+    }
+}
+
+/// Follows the scattered rays and only picks up light that they happen
+/// to hit. Simple and unbiased but very noisy with small lights
+#[derive(Default)]
+pub struct NaivePath {
+    termination: Termination,
+}
+
+impl Integrator for NaivePath {
+    /*
+     * The function calculates a color value by taking the initial ray passed
+     * in, determines if any intersections have been made with any of the
+     * geometry in the scene and then calculating a scattered ray based on
+     * the type of material given. This process is repeated until we have
+     * reached a finite number of bounces or we are unable to intersect
+     * anymore geometry. Light is picked up from the background and from every
+     * emissive surface along the way.
+     */
+    fn trace(&self, r: &Ray, scene: &Scene, sampler: &mut Sampler, aov: &mut AovSample) {
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = *r;
+        let mut depth = 0;
+        let mut diffuse_bounces = 0;
+        let mut distance = 0.0;
+
+        loop {
+            let obj = match scene.hit(&ray) {
+                Some(obj) => obj,
+                None => {
+                    aov.add_light(throughput * scene.background(&ray), diffuse_bounces);
+                    break;
+                }
+            };
+            aov.hit = true;
+            aov.add_light(
+                throughput * obj.material.emitted(&ray, &obj),
+                diffuse_bounces,
+            );
+            if !obj.material.is_specular() {
+                aov.record_surface(scene, &ray, &obj, throughput, distance);
+                diffuse_bounces += 1;
+            }
+            distance += obj.t * ray.direction().length();
+            if depth >= self.termination.max_depth {
+                break;
+            }
+
+            // Compute where the next ray is going to bounce
+            ray = match obj.material.scatter(&ray, &obj, sampler) {
+                Some(scattered) => scattered,
+                None => break,
+            };
+            depth += 1;
+            throughput = match self.termination.roulette(
+                depth,
+                throughput * obj.material.color(&obj),
+                sampler,
+            ) {
+                Some(throughput) => throughput,
+                None => break,
+            };
+        }
+    }
+}
+
+impl NaivePath {
+    pub fn new(termination: Termination) -> NaivePath {
+        NaivePath { termination }
+    }
+}
+
+/// Path tracing with next event estimation. At every diffuse surface one
+/// light is picked and a shadow ray is traced towards it. Light that is
+/// found by both the shadow rays and the scattered rays is weighted with
+/// the power heuristic so nothing gets counted twice
+#[derive(Default)]
+pub struct NeePath {
+    termination: Termination,
+}
+
+impl Integrator for NeePath {
+    fn trace(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler, aov: &mut AovSample) {
+        nee_path(ray, scene, sampler, aov, &self.termination, u32::MAX)
+    }
+}
+
+impl NeePath {
+    pub fn new(termination: Termination) -> NeePath {
+        NeePath { termination }
+    }
+}
+
+/// Only the light that reaches a surface straight from an emitter or the
+/// background, after any number of mirror and glass bounces up to the max
+/// depth. Much faster than full path tracing, but corners stay dark
+pub struct DirectLighting {
+    termination: Termination,
+}
+
+impl Default for DirectLighting {
+    fn default() -> DirectLighting {
+        DirectLighting::new(Termination::default().max_depth)
+    }
+}
+
+impl Integrator for DirectLighting {
+    fn trace(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler, aov: &mut AovSample) {
+        nee_path(ray, scene, sampler, aov, &self.termination, 1)
+    }
+}
+
+impl DirectLighting {
+    /// Paths are short anyway, so only the max depth matters
+    pub fn new(max_depth: u32) -> DirectLighting {
+        DirectLighting {
+            termination: Termination {
+                max_depth,
+                min_depth: u32::MAX,
+            },
+        }
+    }
+}
+
+/// A classic Whitted style ray tracer. Mirrors and glass are followed up
+/// to the max depth, diffuse surfaces only see the lights through one
+/// shadow ray per light and nothing else
+pub struct Whitted {
+    max_depth: u32,
+}
+
+impl Default for Whitted {
+    fn default() -> Whitted {
+        Whitted::new(Termination::default().max_depth)
+    }
+}
+
+impl Integrator for Whitted {
+    fn trace(&self, r: &Ray, scene: &Scene, sampler: &mut Sampler, aov: &mut AovSample) {
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = *r;
+        let mut distance = 0.0;
+
+        for depth in 0.. {
+            let obj = match scene.hit(&ray) {
+                Some(obj) => obj,
+                None => {
+                    aov.add_light(throughput * scene.background(&ray), 0);
+                    break;
+                }
+            };
+            aov.hit = true;
+            aov.add_light(throughput * obj.material.emitted(&ray, &obj), 0);
+
+            if !obj.material.is_specular() {
+                aov.record_surface(scene, &ray, &obj, throughput, distance);
+                for light in &scene.lights {
+                    let direction = match light.sample_direction(obj.p, sampler) {
+                        Some(direction) => direction,
+                        None => continue,
+                    };
+                    let pdf = light.pdf_value(obj.p, direction);
+                    if pdf > 0.0 {
+                        let emitted = shadow_ray(&ray, &obj, scene, direction);
+                        aov.add_light(throughput * emitted * (1.0 / pdf), 1);
+                    }
+                }
+                break;
+            }
+
+            distance += obj.t * ray.direction().length();
+            if depth >= self.max_depth {
+                break;
+            }
+            ray = match obj.material.scatter(&ray, &obj, sampler) {
+                Some(scattered) => scattered,
+                None => break,
+            };
+            throughput = throughput * obj.material.color(&obj);
+        }
+    }
+}
+
+impl Whitted {
+    pub fn new(max_depth: u32) -> Whitted {
+        Whitted { max_depth }
+    }
+}
+
+/// Shows how much of the hemisphere above every visible point is open,
+/// without looking at lights or materials. Occluders further away than
+/// the distance do not count
+pub struct AmbientOcclusion {
+    distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn trace(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler, aov: &mut AovSample) {
+        let obj = match scene.hit(ray) {
+            Some(obj) => obj,
+            None => return aov.add_light(Vec3::new(1.0, 1.0, 1.0), 0),
+        };
+        aov.hit = true;
+        aov.record_surface(scene, ray, &obj, Vec3::new(1.0, 1.0, 1.0), 0.0);
+
+        // Cosine weighted directions make the fraction of open rays the
+        // estimate on its own
+        let normal = obj.facing_normal(ray);
+        let direction = cosine_direction(normal, sampler.next_f32(), sampler.next_f32());
+        let occlusion_ray = Ray::new(obj.p, direction, ray.time());
+        if !scene.any_hit(&occlusion_ray, self.distance) {
+            aov.add_light(Vec3::new(1.0, 1.0, 1.0), 1);
+        }
+    }
+}
+
+impl AmbientOcclusion {
+    pub fn new(distance: f32) -> AmbientOcclusion {
+        AmbientOcclusion { distance }
+    }
+}
+
+/// Path tracing with next event estimation that stops after the given
+/// number of diffuse bounces. Mirror and glass bounces do not count
+fn nee_path(
+    r: &Ray,
+    scene: &Scene,
+    sampler: &mut Sampler,
+    aov: &mut AovSample,
+    termination: &Termination,
+    max_diffuse: u32,
+) {
+    let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+    let mut ray = *r;
+    // Lights seen by the camera or through mirrors could not have been
+    // sampled, so they count fully
+    let mut specular = true;
+    let mut scatter_pdf = 0.0;
+    let mut diffuse_bounces = 0;
+    let mut depth = 0;
+    let mut distance = 0.0;
+
+    loop {
+        let obj = match scene.hit(&ray) {
+            Some(obj) => obj,
+            None => {
+                aov.add_light(throughput * scene.background(&ray), diffuse_bounces);
+                break;
+            }
+        };
+        aov.hit = true;
+
+        let emitted = obj.material.emitted(&ray, &obj);
+        if specular {
+            aov.add_light(throughput * emitted, diffuse_bounces);
+        } else if emitted != Vec3::new(0.0, 0.0, 0.0) {
+            let light_pdf = scene.light_pdf(ray.origin(), ray.direction());
+            let weight = power_heuristic(scatter_pdf, light_pdf);
+            aov.add_light(throughput * emitted * weight, diffuse_bounces);
+        }
+        if !obj.material.is_specular() {
+            aov.record_surface(scene, &ray, &obj, throughput, distance);
+        }
+        distance += obj.t * ray.direction().length();
+
+        // The surface after the last bounce only contributes what it emits
+        if diffuse_bounces == max_diffuse || depth >= termination.max_depth {
+            break;
+        }
+
+        specular = obj.material.is_specular();
+        if !specular {
+            diffuse_bounces += 1;
+            let light = sample_light(&ray, &obj, scene, sampler);
+            aov.add_light(throughput * light, diffuse_bounces);
+        }
+
+        let scattered = match obj.material.scatter(&ray, &obj, sampler) {
+            Some(scattered) => scattered,
+            None => break,
+        };
+        if !specular {
+            scatter_pdf = obj
+                .material
+                .scattering_pdf(&ray, &obj, scattered.direction());
+            if scatter_pdf <= 0.0 {
+                break;
+            }
+        }
+        depth += 1;
+        throughput =
+            match termination.roulette(depth, throughput * obj.material.color(&obj), sampler) {
+                Some(throughput) => throughput,
+                None => break,
+            };
+        ray = scattered;
+    }
+}
+
+/// Light arriving at the hit from a random point on a random light,
+/// weighted against finding the same light by scattering
+fn sample_light(r: &Ray, rec: &HitRecord, scene: &Scene, sampler: &mut Sampler) -> Vec3 {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    if scene.lights.is_empty() {
+        return black;
+    }
+
+    let light = scene.lights[sampler.next_index(scene.lights.len())];
+    let direction = match light.sample_direction(rec.p, sampler) {
+        Some(direction) => direction,
+        None => return black,
+    };
+    let light_pdf = scene.light_pdf(rec.p, direction);
+    if light_pdf <= 0.0 {
+        return black;
+    }
+
+    let weight = power_heuristic(light_pdf, rec.material.scattering_pdf(r, rec, direction));
+    shadow_ray(r, rec, scene, direction) * (weight / light_pdf)
+}
+
+/// The reflected light that arrives from an emitter in the direction,
+/// unless something is in the way. This is the BSDF times the cosine
+/// times the emitted light, without any division by a density
+fn shadow_ray(r: &Ray, rec: &HitRecord, scene: &Scene, direction: Vec3) -> Vec3 {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    let shadow_ray = Ray::new(rec.p, direction, r.time());
+    let light_rec = match scene.hit(&shadow_ray) {
+        Some(light_rec) => light_rec,
+        None => return black,
+    };
+
+    // Whatever we hit first is either the light or what is blocking it
+    let emitted = light_rec.material.emitted(&shadow_ray, &light_rec);
+    if emitted == black {
+        return black;
+    }
+    rec.material.eval(r, rec, direction) * emitted
+}
+
+/// Veach's power heuristic with an exponent of two. The weight of the
+/// strategy with density `a` when the other one has density `b`
+fn power_heuristic(a: f32, b: f32) -> f32 {
+    let (a, b) = (a * a, b * b);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// A gray floor below a spherical light. The point right under the light
+/// receives an irradiance of pi * L * sin^2(theta_max), so its radiance is
+/// albedo * L * (r / d)^2
+#[cfg(test)]
+fn lit_floor() -> HitableList {
+    use quad::Quad;
+
+    let mut world = HitableList::new();
+    world.push(Box::new(Quad::new(
+        Vec3::new(-50.0, 0.0, -50.0),
+        Vec3::new(100.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 100.0),
+        Material::Lambertian(Constant::shared(Vec3::new(0.5, 0.5, 0.5))),
+    )));
+    world.push(Box::new(Sphere::new(
+        Vec3::new(0.0, 3.0, 0.0),
+        1.0,
+        Material::DiffuseLight(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 4.0),
+    )));
+    world
+}
+
+#[cfg(test)]
+fn average(integrator: &dyn Integrator, ray: &Ray, scene: &Scene, samples: u32) -> f32 {
+    let mut sampler = Sampler::new(7, 0);
+    let sum: f32 = (0..samples)
+        .map(|_| integrator.li(ray, scene, &mut sampler).x())
+        .sum();
+    sum / samples as f32
+}
+
+#[test]
+fn test_lit_floor() {
+    let world = lit_floor();
+    let scene = Scene::new(
+        &world,
+        world.lights(),
+        Background::Constant(Vec3::new(0.0, 0.0, 0.0)),
+    );
+    assert_eq!(scene.lights.len(), 1);
+
+    // The floor is the only diffuse surface and cannot see itself, so
+    // every integrator that handles lights agrees
+    let expected = 0.5 * 4.0 / 9.0;
+    let ray = Ray::new(Vec3::new(2.0, 1.0, 0.0), Vec3::new(-2.0, -1.0, 0.0), 0.0);
+    let naive = NaivePath::default();
+    let nee = NeePath::default();
+    assert!((average(&naive, &ray, &scene, 20000) - expected).abs() < 0.02);
+    assert!((average(&nee, &ray, &scene, 20000) - expected).abs() < 0.01);
+    let direct = DirectLighting::default();
+    assert!((average(&direct, &ray, &scene, 20000) - expected).abs() < 0.01);
+    let whitted = Whitted::default();
+    assert!((average(&whitted, &ray, &scene, 20000) - expected).abs() < 0.01);
+
+    // Russian roulette from the very first bounce does not change the
+    // average, it only adds noise
+    let termination = Termination {
+        max_depth: 50,
+        min_depth: 0,
+    };
+    let naive = NaivePath::new(termination);
+    let nee = NeePath::new(termination);
+    assert!((average(&naive, &ray, &scene, 20000) - expected).abs() < 0.02);
+    assert!((average(&nee, &ray, &scene, 20000) - expected).abs() < 0.01);
+
+    // Without a single bounce the floor is black
+    let termination = Termination {
+        max_depth: 0,
+        min_depth: 0,
+    };
+    assert_eq!(
+        average(&NaivePath::new(termination), &ray, &scene, 100),
+        0.0
+    );
+    assert_eq!(average(&NeePath::new(termination), &ray, &scene, 100), 0.0);
+
+    // Looking straight at the light
+    let ray = Ray::new(Vec3::new(0.0, 6.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    assert_eq!(average(&nee, &ray, &scene, 10), 4.0);
+    assert_eq!(average(&whitted, &ray, &scene, 10), 4.0);
+}
+
+#[test]
+fn test_ambient_occlusion() {
+    let world = lit_floor();
+    let scene = Scene::new(&world, Vec::new(), Background::Sky);
+
+    // Far from the light the floor is open, below it the light blocks
+    // some of the sky unless it is out of reach
+    let far = Ray::new(Vec3::new(20.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    let below = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    assert_eq!(
+        average(&AmbientOcclusion::new(10.0), &far, &scene, 1000),
+        1.0
+    );
+    let occluded = average(&AmbientOcclusion::new(10.0), &below, &scene, 10000);
+    assert!((occluded - 8.0 / 9.0).abs() < 0.02);
+    assert_eq!(
+        average(&AmbientOcclusion::new(1.0), &below, &scene, 1000),
+        1.0
+    );
+
+    // Rays into the void are unoccluded
+    let up = Ray::new(Vec3::new(20.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+    assert_eq!(average(&AmbientOcclusion::new(10.0), &up, &scene, 10), 1.0);
+}
+
+#[test]
+fn test_power_heuristic() {
+    assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+    assert_eq!(power_heuristic(1.0, 0.0), 1.0);
+    assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+    assert!((power_heuristic(1.0, 3.0) - 0.1).abs() < 1e-6);
+}
+
+#[test]
+fn test_deep_paths() {
+    use quad::Quad;
+
+    // Two perfect mirrors facing each other trap the ray until the max
+    // depth is reached. A recursive integrator would run out of stack
+    let mirror = Material::Metal(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 0.0);
+    let mut world = HitableList::new();
+    world.push(Box::new(Quad::new(
+        Vec3::new(-1.0, -1.0, -1.0),
+        Vec3::new(2.0, 0.0, 0.0),
+        Vec3::new(0.0, 2.0, 0.0),
+        mirror.clone(),
+    )));
+    world.push(Box::new(Quad::new(
+        Vec3::new(-1.0, -1.0, 1.0),
+        Vec3::new(2.0, 0.0, 0.0),
+        Vec3::new(0.0, 2.0, 0.0),
+        mirror,
+    )));
+    let scene = Scene::new(&world, Vec::new(), Background::Sky);
+
+    let termination = Termination {
+        max_depth: 200_000,
+        min_depth: 0,
+    };
+    let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+    let mut sampler = Sampler::new(3, 0);
+    assert_eq!(
+        NaivePath::new(termination).li(&ray, &scene, &mut sampler),
+        Vec3::new(0.0, 0.0, 0.0)
+    );
+    assert_eq!(
+        NeePath::new(termination).li(&ray, &scene, &mut sampler),
+        Vec3::new(0.0, 0.0, 0.0)
+    );
+    assert_eq!(
+        Whitted::new(200_000).li(&ray, &scene, &mut sampler),
+        Vec3::new(0.0, 0.0, 0.0)
+    );
+}
+
+#[test]
+fn test_aovs() {
+    let world = lit_floor();
+    let mut scene = Scene::new(
+        &world,
+        world.lights(),
+        Background::Constant(Vec3::new(0.0, 0.0, 0.0)),
+    );
+    scene.number_objects(&world.objs);
+
+    let ray = Ray::new(Vec3::new(2.0, 1.0, 0.0), Vec3::new(-2.0, -1.0, 0.0), 0.0);
+    let integrators: Vec<Box<dyn Integrator>> = vec![
+        Box::new(NaivePath::default()),
+        Box::new(NeePath::default()),
+        Box::new(DirectLighting::default()),
+        Box::new(Whitted::default()),
+    ];
+    let mut sampler = Sampler::new(5, 0);
+    for integrator in integrators.iter() {
+        for _ in 0..100 {
+            let mut aov = AovSample::default();
+            integrator.trace(&ray, &scene, &mut sampler, &mut aov);
+            assert!(aov.surface);
+            assert_eq!(aov.albedo, Vec3::new(0.5, 0.5, 0.5));
+            assert_eq!(aov.normal, Vec3::new(0.0, 1.0, 0.0));
+            assert!((aov.depth - 5f32.sqrt()).abs() < 1e-4);
+            assert!(aov.position.length() < 1e-4);
+            assert_eq!((aov.object, aov.material), (1, 1));
+            // The floor can't see itself, so all of its light is direct
+            assert_eq!(aov.indirect, Vec3::new(0.0, 0.0, 0.0));
+        }
+    }
+
+    // Looking straight at the light
+    let up = Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+    let mut aov = AovSample::default();
+    NeePath::default().trace(&up, &scene, &mut sampler, &mut aov);
+    assert_eq!((aov.object, aov.material), (2, 2));
+    assert_eq!(aov.direct, Vec3::new(4.0, 4.0, 4.0));
+    assert!((aov.depth - 1.5).abs() < 1e-4);
+}
@@ -3,26 +3,61 @@ extern crate png;
 extern crate rand;
 
 mod aabb;
+mod aov;
 mod bvh_node;
 mod camera;
+mod checkpoint;
+mod denoise;
+mod film;
+mod filter;
 mod hitable;
 mod hitable_list;
+mod integrator;
 mod material;
+mod mesh;
+mod noise;
+mod output;
 mod parser;
+mod quad;
 mod ray;
 mod renderer;
+mod sampler;
+mod scene;
 mod sphere;
 mod texture;
+mod tile;
+mod tonemap;
 mod triangle;
 mod vector;
 
+use std::hash::Hasher;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aov::Aov;
+use bvh_node::BvhTree;
+use checkpoint::Fnv;
 use clap::{App, Arg};
+use film::Film;
+use filter::{Filter, Shape};
+use hitable::Hitable;
 use hitable_list::HitableList;
+use integrator::{
+    AmbientOcclusion, DirectLighting, Integrator, NaivePath, NeePath, Termination, Whitted,
+};
 use material::Material;
-use parser::Parser;
-use png::HasParameters;
-use rand::{thread_rng, Rng};
+use noise::{Granite, Marble, Wood};
+use output::{Compression, Depth, Format, Precision};
+use parser::{ParseOptions, Parser};
+use quad::Quad;
+use renderer::{Budget, Progress, Sampling};
+use sampler::Sampler;
+use scene::{Background, Scene};
 use sphere::{MovingSphere, Sphere};
+use texture::{Checker, Constant, TextureRef};
+use tile::TileOrder;
+use tonemap::{ColorSpace, Operator, ToneMapping};
 use triangle::Triangle;
 use vector::Vec3;
 
@@ -56,17 +91,329 @@ fn main() {
                 .help("The pixel height of the output image")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Where the image is written. The extension picks the format: .png and .ppm for gamma corrected images, .pfm, .hdr and .exr for linear high dynamic range")
+                .default_value("test.png")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bit-depth")
+                .long("bit-depth")
+                .value_name("BITS")
+                .help("Bits per channel of .png and .ppm outputs")
+                .possible_values(&["8", "16"])
+                .default_value("8")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("alpha")
+                .long("alpha")
+                .help("Adds an alpha channel to .png and .exr outputs that is transparent where camera rays miss all geometry, for compositing over other backgrounds"),
+        )
+        .arg(
+            Arg::with_name("aov")
+                .long("aov")
+                .value_name("AOV")
+                .help("Also captures these AOVs of the first surface that isn't a mirror or glass. They become layers of .exr outputs and separate files like image.albedo.png otherwise")
+                .possible_values(&["albedo", "normal", "depth", "position", "object", "material", "direct", "indirect", "all"])
+                .multiple(true)
+                .use_delimiter(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("denoise")
+                .long("denoise")
+                .help("Removes noise from the image before it is written, guided by the albedo, normal and depth of what every pixel sees. Makes low sample counts usable, especially in diffuse scenes"),
+        )
+        .arg(
+            Arg::with_name("exr-compression")
+                .long("exr-compression")
+                .value_name("COMPRESSION")
+                .help("How the pixels of .exr outputs are compressed")
+                .possible_values(&["none", "zip"])
+                .default_value("zip")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exr-precision")
+                .long("exr-precision")
+                .value_name("PRECISION")
+                .help("Whether .exr outputs store 16 or 32-bit floats")
+                .possible_values(&["half", "float"])
+                .default_value("half")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exposure")
+                .long("exposure")
+                .value_name("STOPS")
+                .help("Brightens or darkens the image before it is written, each stop doubling or halving it")
+                .default_value("0")
+                .allow_hyphen_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tonemap")
+                .long("tonemap")
+                .value_name("OPERATOR")
+                .help("How highlights are compressed for .png and .ppm outputs")
+                .possible_values(&["clamp", "reinhard", "extended-reinhard", "aces", "hable"])
+                .default_value("clamp")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("white-point")
+                .long("white-point")
+                .value_name("LUMINANCE")
+                .help("The brightness that becomes pure white with --tonemap extended-reinhard")
+                .default_value("4")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("color-space")
+                .long("color-space")
+                .value_name("SPACE")
+                .help("The primaries and, for .png and .ppm outputs, transfer function the image is written in")
+                .possible_values(&["srgb", "display-p3", "rec2020", "linear"])
+                .default_value("srgb")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("samples")
                 .short("s")
                 .long("samples")
                 .value_name("SAMPLES")
-                .help("The number of samples that will be taken for antialiasing")
+                .help("The number of samples that will be taken for antialiasing. The most a pixel can get with --noise-threshold")
+                .default_value("16")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("noise-threshold")
+                .long("noise-threshold")
+                .value_name("ERROR")
+                .help("Enables adaptive sampling. Pixels stop sampling once the relative error of their mean drops below this, e.g. 0.01")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-samples")
+                .long("min-samples")
+                .value_name("SAMPLES")
+                .help("The fewest samples a pixel takes with --noise-threshold")
+                .default_value("8")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample-heatmap")
+                .long("sample-heatmap")
+                .value_name("FILE")
+                .help("Writes a PNG that shows how many samples each pixel took, from blue for none to red for the most")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("passes")
+                .long("passes")
+                .value_name("PASSES")
+                .help("Spreads the samples over this many passes. A preview is written next to the image after each one, e.g. image.preview.png, so it can be watched as it refines")
+                .default_value("1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("FILTER")
+                .help("How samples are spread over the pixels around them. Mitchell and Lanczos sharpen, but can ring around hard edges")
+                .possible_values(&["box", "tent", "gaussian", "mitchell", "lanczos"])
+                .default_value("box")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("filter-radius")
+                .long("filter-radius")
+                .value_name("PIXELS")
+                .help("How far samples reach, from the center of their pixel. Defaults to 0.5 for box, 1 for tent, 1.5 for gaussian, 2 for mitchell and 3 for lanczos")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tile-size")
+                .long("tile-size")
+                .value_name("PIXELS")
+                .help("The width and height of the tiles that are rendered in parallel")
+                .default_value("32")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tile-order")
+                .long("tile-order")
+                .value_name("ORDER")
+                .help("The order in which tiles are rendered")
+                .possible_values(&["scanline", "spiral", "hilbert"])
+                .default_value("spiral")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("time-limit")
+                .long("time-limit")
+                .value_name("SECONDS")
+                .help("Keeps adding passes of --samples / --passes samples until the time is up. Stops before a pass that would not finish in time")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target-spp")
+                .long("target-spp")
+                .value_name("SAMPLES")
+                .help("Keeps adding passes of --samples / --passes samples until the pixels average this many. Can be combined with --time-limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("FILE")
+                .help("Saves the render to this file after every pass, so it can be picked up again with --resume")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .value_name("FILE")
+                .help("Continues a render from a checkpoint. The scene and settings have to match, but --samples and --passes may be raised")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Makes the render, and the random scene, turn out the same on every run. Resumed renders keep the seed of their checkpoint")
+                .takes_value(true)
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("crease-angle")
+                .long("crease-angle")
+                .value_name("DEGREES")
+                .help("Sharpest angle between faces that still gets smoothed when an OBJ file has no normals. 0 gives flat shading")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scene")
+                .long("scene")
+                .value_name("SCENE")
+                .help("The built-in scene that is rendered when no OBJ file is given")
+                .possible_values(&["random", "cornell"])
+                .default_value("random")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("integrator")
+                .short("i")
+                .long("integrator")
+                .value_name("INTEGRATOR")
+                .help("The rendering algorithm. path-nee samples lights directly, which helps a lot with small lights. whitted, direct and ao trade accuracy for speed")
+                .possible_values(&["naive", "path-nee", "whitted", "direct", "ao"])
+                .default_value("naive")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-depth")
+                .long("max-depth")
+                .value_name("BOUNCES")
+                .help("The most bounces a path can have")
+                .default_value("50")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-depth")
+                .long("min-depth")
+                .value_name("BOUNCES")
+                .help("Bounces before paths may be ended at random by Russian roulette")
+                .default_value("3")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ao-distance")
+                .long("ao-distance")
+                .value_name("DISTANCE")
+                .help("How far away geometry still occludes with the ao integrator")
+                .default_value("1.0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("accel")
+                .short("a")
+                .long("accel")
+                .value_name("ACCEL")
+                .help("The acceleration structure used to intersect the scene")
+                .possible_values(&["list", "bvh"])
+                .default_value("bvh")
                 .takes_value(true),
         )
         .get_matches();
 
     // Final output settings
+    let output = Path::new(matches.value_of("output").unwrap());
+    if Format::from_path(output).is_none() {
+        panic!(
+            "Unsupported output {:?}, use a .png, .ppm, .pfm, .hdr or .exr file",
+            output
+        );
+    }
+    let mut aovs: Vec<Aov> = Vec::new();
+    for name in matches.values_of("aov").into_iter().flatten() {
+        let selected = match Aov::from_name(name) {
+            Some(aov) => vec![aov],
+            None => Aov::ALL.to_vec(),
+        };
+        for aov in selected {
+            if !aovs.contains(&aov) {
+                aovs.push(aov);
+            }
+        }
+    }
+    let options = output::Options {
+        depth: match matches.value_of("bit-depth").unwrap() {
+            "16" => Depth::Sixteen,
+            _ => Depth::Eight,
+        },
+        alpha: matches.is_present("alpha"),
+        denoise: matches.is_present("denoise"),
+        compression: match matches.value_of("exr-compression").unwrap() {
+            "none" => Compression::None,
+            _ => Compression::Zip,
+        },
+        precision: match matches.value_of("exr-precision").unwrap() {
+            "float" => Precision::Float,
+            _ => Precision::Half,
+        },
+        tone: ToneMapping {
+            exposure: matches
+                .value_of("exposure")
+                .unwrap()
+                .parse::<f32>()
+                .unwrap(),
+            operator: match matches.value_of("tonemap").unwrap() {
+                "reinhard" => Operator::Reinhard,
+                "extended-reinhard" => Operator::ExtendedReinhard(
+                    matches
+                        .value_of("white-point")
+                        .unwrap()
+                        .parse::<f32>()
+                        .unwrap(),
+                ),
+                "aces" => Operator::Aces,
+                "hable" => Operator::Hable,
+                _ => Operator::Clamp,
+            },
+            color_space: match matches.value_of("color-space").unwrap() {
+                "display-p3" => ColorSpace::DisplayP3,
+                "rec2020" => ColorSpace::Rec2020,
+                "linear" => ColorSpace::Linear,
+                _ => ColorSpace::Srgb,
+            },
+        },
+    };
     let width = matches
         .value_of("width")
         .unwrap_or("1200")
@@ -78,23 +425,53 @@ fn main() {
         .parse::<u32>()
         .unwrap();
 
+    let cornell =
+        matches.value_of("file").is_none() && matches.value_of("scene") == Some("cornell");
+    // The checkpoint is loaded first, since the random world is built from
+    // its seed
+    let checkpoint = matches.value_of("resume").map(|path| {
+        checkpoint::load(Path::new(path))
+            .unwrap_or_else(|e| panic!("Could not read checkpoint {}: {}", path, e))
+    });
+    // Without a seed every run is different
+    let seed = match checkpoint {
+        Some((_, ref progress)) => progress.seed,
+        None => matches
+            .value_of("seed")
+            .map_or_else(rand::random::<u64>, |s| s.parse::<u64>().unwrap()),
+    };
     let world = if matches.value_of("file").is_some() {
         // Create our scene and add some geometry
-        Parser::OBJ(String::from(matches.value_of("file").unwrap())).parse()
+        let parser = Parser::OBJ(String::from(matches.value_of("file").unwrap()));
+        match matches.value_of("crease-angle") {
+            Some(angle) => parser.parse_with(&ParseOptions {
+                crease_angle: angle.parse::<f32>().unwrap(),
+            }),
+            None => parser.parse(),
+        }
+    } else if cornell {
+        cornell_box()
     } else {
         // No OBJ file provided. Create random world
-        random_world()
+        random_world(seed)
     };
 
     // Camera setup
-    let lookfrom = Vec3::new(13.0, 2.0, 3.0);
-    let lookat = Vec3::new(0.0, 0.0, 0.0);
+    let (lookfrom, lookat, vertical_fov) = if cornell {
+        (
+            Vec3::new(278.0, 278.0, -800.0),
+            Vec3::new(278.0, 278.0, 0.0),
+            40.0,
+        )
+    } else {
+        (Vec3::new(13.0, 2.0, 3.0), Vec3::new(0.0, 0.0, 0.0), 30.0)
+    };
     let dist_to_focus = 10.0;
     let cam = camera::Camera::new(
         lookfrom,
         lookat,
         Vec3::new(0.0, 1.0, 0.0),
-        30.0,
+        vertical_fov,
         (width / height) as f32,
         0.0,
         dist_to_focus,
@@ -102,69 +479,313 @@ fn main() {
         1.0,
     );
 
-    let renderer = renderer::Renderer::new(cam);
-    let pixels = renderer.render(width, height, &world);
+    let shape = match matches.value_of("filter").unwrap() {
+        "tent" => Shape::Tent,
+        "gaussian" => Shape::Gaussian,
+        "mitchell" => Shape::Mitchell,
+        "lanczos" => Shape::Lanczos,
+        _ => Shape::Box,
+    };
+    let filter = Filter::new(
+        shape,
+        matches
+            .value_of("filter-radius")
+            .map_or(shape.default_radius(), |r| r.parse::<f32>().unwrap()),
+    );
+
+    // Everything besides the scene itself that changes how the image
+    // looks. The sample budget is left out so resumed renders can go on
+    let settings = format!(
+        "{:?} {} {} {} {:?} {} {:?} {:?} {:?} {:?} {:?}",
+        cam,
+        width,
+        height,
+        options.alpha,
+        aovs,
+        options.denoise,
+        filter,
+        matches.value_of("integrator"),
+        matches.value_of("max-depth"),
+        matches.value_of("min-depth"),
+        matches.value_of("ao-distance")
+    );
+    let mut renderer = renderer::Renderer::new(cam);
+    let termination = Termination {
+        max_depth: matches
+            .value_of("max-depth")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        min_depth: matches
+            .value_of("min-depth")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+    };
+    let integrator: Box<dyn Integrator> = match matches.value_of("integrator").unwrap() {
+        "path-nee" => Box::new(NeePath::new(termination)),
+        "whitted" => Box::new(Whitted::new(termination.max_depth)),
+        "ao" => Box::new(AmbientOcclusion::new(
+            matches
+                .value_of("ao-distance")
+                .unwrap()
+                .parse::<f32>()
+                .unwrap(),
+        )),
+        "direct" => Box::new(DirectLighting::new(termination.max_depth)),
+        _ => Box::new(NaivePath::new(termination)),
+    };
+    renderer.set_integrator(integrator);
+
+    let samples = matches.value_of("samples").unwrap().parse::<u32>().unwrap();
+    let sampling = match matches.value_of("noise-threshold") {
+        Some(threshold) => Sampling::Adaptive {
+            min_samples: matches
+                .value_of("min-samples")
+                .unwrap()
+                .parse::<u32>()
+                .unwrap(),
+            max_samples: samples,
+            threshold: threshold.parse::<f32>().unwrap(),
+        },
+        None => Sampling::Fixed(samples),
+    };
+    renderer.set_sampling(sampling);
+
+    let passes = matches.value_of("passes").unwrap().parse::<u32>().unwrap();
+    renderer.set_passes(passes);
+    let budget = Budget {
+        time: matches
+            .value_of("time-limit")
+            .map(|s| Duration::from_secs_f64(s.parse::<f64>().unwrap())),
+        spp: matches
+            .value_of("target-spp")
+            .map(|s| s.parse::<u32>().unwrap()),
+    };
+    renderer.set_budget(budget);
+    renderer.set_filter(filter);
+    renderer.set_alpha(options.alpha);
+    // The denoiser is guided by the AOVs
+    renderer.set_aovs(!aovs.is_empty() || options.denoise);
+    let tile_order = match matches.value_of("tile-order").unwrap() {
+        "scanline" => TileOrder::Scanline,
+        "hilbert" => TileOrder::Hilbert,
+        _ => TileOrder::Spiral,
+    };
+    renderer.set_tiles(
+        matches
+            .value_of("tile-size")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        tile_order,
+    );
+
+    let bvh;
+    let accelerated: &dyn Hitable = match matches.value_of("accel").unwrap() {
+        "list" => {
+            println!("Intersecting {} objects linearly", world.len());
+            &world
+        }
+        _ => {
+            let build_start = Instant::now();
+            bvh = BvhTree::new(&world.objs);
+            println!("Built {} in {:?}", bvh, build_start.elapsed());
+            &bvh
+        }
+    };
+    // The Cornell box is only lit by its lights
+    let background = if cornell {
+        Background::Constant(Vec3::new(0.0, 0.0, 0.0))
+    } else {
+        Background::Sky
+    };
+    let mut scene = Scene::new(accelerated, world.lights(), background);
+    if !aovs.is_empty() {
+        scene.number_objects(&world.objs);
+    }
+
+    let mut hasher = Fnv::default();
+    hasher.write(settings.as_bytes());
+    // Besides the objects it ends up with, the scene is known by what it
+    // was built from
+    match matches.value_of("file") {
+        Some(path) => {
+            hasher.write_u8(0);
+            for file in Parser::OBJ(String::from(path)).files() {
+                hasher.write(file.to_string_lossy().as_bytes());
+                hasher.write(&std::fs::read(&file).unwrap());
+            }
+            hasher.write(format!("{:?}", matches.value_of("crease-angle")).as_bytes());
+        }
+        None if cornell => hasher.write_u8(1),
+        None => {
+            hasher.write_u8(2);
+            hasher.write_u64(seed);
+        }
+    }
+    scene.fingerprint(&world.objs, &mut hasher);
+    let hash = hasher.finish();
 
-    let path = std::path::Path::new("test.png");
-    let file = std::fs::File::create(path).unwrap();
-    let ref mut w = std::io::BufWriter::new(file);
+    let progress = match checkpoint {
+        Some((saved_hash, progress)) => {
+            let path = matches.value_of("resume").unwrap();
+            if saved_hash != hash {
+                panic!(
+                    "Checkpoint {} belongs to a different scene or settings",
+                    path
+                );
+            }
+            println!(
+                "Resuming {} after {} of {} passes",
+                path, progress.passes, passes
+            );
+            progress
+        }
+        None => Progress::new(width, height, seed),
+    };
+    // Resumed renders keep saving to the checkpoint they came from
+    let checkpoint_path = matches
+        .value_of("checkpoint")
+        .or_else(|| matches.value_of("resume"))
+        .map(Path::new);
 
-    let mut encoder = png::Encoder::new(w, width, height);
-    encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+    // Previews are cheap to write whatever the final image is going to be:
+    // 8-bit PNGs without AOVs or denoising
+    let preview = output.with_extension("preview.png");
+    let preview_options = output::Options {
+        depth: Depth::Eight,
+        denoise: false,
+        ..options
+    };
 
-    let mut writer = encoder.write_header().unwrap();
-    writer.write_image_data(&pixels).unwrap();
+    let render_start = Instant::now();
+    let progress = renderer.render(progress, &scene, |progress| {
+        if let Some(path) = checkpoint_path {
+            checkpoint::save(path, hash, progress)
+                .unwrap_or_else(|e| panic!("Could not write checkpoint {:?}: {}", path, e));
+        }
+        // An open ended render doesn't know which pass is the last one
+        if budget != Budget::default() || progress.passes < passes {
+            save(&preview, &progress.film, &[], preview_options);
+        }
+    });
+    let elapsed = render_start.elapsed();
+    println!(
+        "Rendered {} passes in {:?} with {:.1} samples per pixel on average and {:.2} million rays per second",
+        progress.passes,
+        elapsed,
+        progress.film.average_samples(),
+        renderer.traced_rays() as f64 / elapsed.as_secs_f64().max(1e-9) / 1e6
+    );
+    let counts = progress.film.counts();
+
+    save(output, &progress.film, &aovs, options);
+    // The image replaces the preview, if there was one
+    let _ = std::fs::remove_file(&preview);
+    if let Some(path) = matches.value_of("sample-heatmap") {
+        let colors: Vec<u16> =
+            renderer::heatmap(&counts, counts.iter().cloned().max().unwrap_or(0))
+                .into_iter()
+                .map(|c| c as u16)
+                .collect();
+        let file = std::fs::File::create(path).unwrap();
+        let w = &mut std::io::BufWriter::new(file);
+        output::write_png(w, width, height, &colors, false, Depth::Eight).unwrap();
+        println!("Sample counts written to {:?}", path);
+    }
+}
+
+/// Writes the film in the format of the path's extension
+fn save(path: &Path, film: &Film, aovs: &[Aov], options: output::Options) {
+    output::save(path, film, aovs, options)
+        .unwrap_or_else(|e| panic!("Could not write {:?}: {}", path, e));
     println!("Image written to {:?}", path);
 }
 
-fn random_world() -> HitableList {
-    let mut rng = thread_rng();
+fn random_world(seed: u64) -> HitableList {
+    let mut sampler = Sampler::new(seed, 0);
     let mut world = hitable_list::HitableList::new();
+    let checker = Checker::new(
+        Constant::shared(Vec3::new(0.2, 0.3, 0.1)),
+        Constant::shared(Vec3::new(0.9, 0.9, 0.9)),
+        1.0,
+    );
     world.push(Box::new(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
         1000.0,
-        Material::Lambertian(Vec3::new(0.5, 0.4, 0.5)),
+        Material::Lambertian(Arc::new(checker)),
     )));
     for a in -11..11 {
         for b in -11..11 {
-            let mat_type = rng.gen_range(0.0, 1.0);
+            let mat_type = sampler.next_f32();
             let center = Vec3::new(
-                a as f32 + 0.9 * rng.gen_range(0.0, 1.0),
+                a as f32 + 0.9 * sampler.next_f32(),
                 0.2,
-                b as f32 + 0.9 * rng.gen_range(0.0, 1.0),
+                b as f32 + 0.9 * sampler.next_f32(),
             );
             if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                if mat_type < 0.8 {
+                if mat_type < 0.15 {
+                    // Procedural stone and wood
+                    let seed = sampler.next_u32() as u64;
+                    let texture: TextureRef = if mat_type < 0.05 {
+                        Arc::new(Marble::new(
+                            seed,
+                            8.0,
+                            Vec3::new(0.9, 0.9, 0.85),
+                            Vec3::new(0.2, 0.2, 0.25),
+                        ))
+                    } else if mat_type < 0.1 {
+                        Arc::new(Wood::new(
+                            seed,
+                            12.0,
+                            Vec3::new(0.75, 0.5, 0.3),
+                            Vec3::new(0.35, 0.2, 0.1),
+                        ))
+                    } else {
+                        Arc::new(Granite::new(
+                            seed,
+                            10.0,
+                            Vec3::new(0.8, 0.75, 0.7),
+                            Vec3::new(0.15, 0.15, 0.15),
+                        ))
+                    };
+                    world.push(Box::new(Sphere::new(
+                        center,
+                        0.2,
+                        Material::Lambertian(texture),
+                    )));
+                } else if mat_type < 0.8 {
                     world.push(Box::new(MovingSphere::new(
                         center,
-                        center + Vec3::new(0.0, 0.5 * rng.gen_range(0.0, 1.0), 0.0),
+                        center + Vec3::new(0.0, 0.5 * sampler.next_f32(), 0.0),
                         0.0,
                         1.0,
                         0.2,
-                        Material::Lambertian(Vec3::new(
-                            rng.gen_range(0.0, 1.0) * rng.gen_range(0.0, 1.0),
-                            rng.gen_range(0.0, 1.0) * rng.gen_range(0.0, 1.0),
-                            rng.gen_range(0.0, 1.0) * rng.gen_range(0.0, 1.0),
-                        )),
+                        Material::Lambertian(Constant::shared(Vec3::new(
+                            sampler.next_f32() * sampler.next_f32(),
+                            sampler.next_f32() * sampler.next_f32(),
+                            sampler.next_f32() * sampler.next_f32(),
+                        ))),
                     )));
                 } else if mat_type < 0.95 {
                     world.push(Box::new(Sphere::new(
                         center,
                         0.2,
                         Material::Metal(
-                            Vec3::new(
-                                0.5 * (1.0 + rng.gen_range(0.0, 1.0)),
-                                0.5 * (1.0 + rng.gen_range(0.0, 1.0)),
-                                0.5 * (1.0 + rng.gen_range(0.0, 1.0)),
-                            ),
-                            0.5 * rng.gen_range(0.0, 1.0),
+                            Constant::shared(Vec3::new(
+                                0.5 * (1.0 + sampler.next_f32()),
+                                0.5 * (1.0 + sampler.next_f32()),
+                                0.5 * (1.0 + sampler.next_f32()),
+                            )),
+                            0.5 * sampler.next_f32(),
                         ),
                     )));
                 } else {
                     world.push(Box::new(Sphere::new(
                         center,
                         0.2,
-                        Material::Dielectric(Vec3::new(1.0, 1.0, 1.0), 1.5),
+                        Material::Dielectric(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 1.5),
                     )));
                 }
             }
@@ -174,19 +795,96 @@ fn random_world() -> HitableList {
     world.push(Box::new(Sphere::new(
         Vec3::new(0.0, 0.5, 0.0),
         0.5,
-        Material::Lambertian(Vec3::new(0.1, 0.2, 0.5)),
+        Material::Lambertian(Constant::shared(Vec3::new(0.1, 0.2, 0.5))),
     )));
 
     world.push(Box::new(Sphere::new(
         Vec3::new(2.0, 0.5, 0.0),
         0.5,
-        Material::Dielectric(Vec3::new(1.0, 1.0, 1.0), 1.5),
+        Material::Dielectric(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 1.5),
     )));
 
     world.push(Box::new(Sphere::new(
         Vec3::new(4.0, 0.5, 0.0),
         0.5,
-        Material::Metal(Vec3::new(0.7, 0.6, 0.5), 0.0),
+        Material::Metal(Constant::shared(Vec3::new(0.7, 0.6, 0.5)), 0.0),
+    )));
+
+    world
+}
+
+/// The classic Cornell box. It is lit by a quad in the ceiling, a triangle
+/// on the back wall and a small glowing sphere on the floor
+fn cornell_box() -> HitableList {
+    let mut world = hitable_list::HitableList::new();
+    let red = Material::Lambertian(Constant::shared(Vec3::new(0.65, 0.05, 0.05)));
+    let white = Material::Lambertian(Constant::shared(Vec3::new(0.73, 0.73, 0.73)));
+    let green = Material::Lambertian(Constant::shared(Vec3::new(0.12, 0.45, 0.15)));
+    let light = Material::DiffuseLight(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 15.0);
+
+    // Walls, floor and ceiling
+    world.push(Box::new(Quad::new(
+        Vec3::new(555.0, 0.0, 0.0),
+        Vec3::new(0.0, 555.0, 0.0),
+        Vec3::new(0.0, 0.0, 555.0),
+        green,
+    )));
+    world.push(Box::new(Quad::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 555.0, 0.0),
+        Vec3::new(0.0, 0.0, 555.0),
+        red,
+    )));
+    world.push(Box::new(Quad::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(555.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 555.0),
+        white.clone(),
+    )));
+    world.push(Box::new(Quad::new(
+        Vec3::new(555.0, 555.0, 555.0),
+        Vec3::new(-555.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -555.0),
+        white.clone(),
+    )));
+    world.push(Box::new(Quad::new(
+        Vec3::new(0.0, 0.0, 555.0),
+        Vec3::new(555.0, 0.0, 0.0),
+        Vec3::new(0.0, 555.0, 0.0),
+        white.clone(),
+    )));
+
+    // The ceiling light faces down and the triangle faces the camera
+    world.push(Box::new(Quad::new(
+        Vec3::new(343.0, 554.0, 332.0),
+        Vec3::new(-130.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -105.0),
+        light,
+    )));
+    world.push(Box::new(Triangle::new(
+        Vec3::new(200.0, 380.0, 554.0),
+        Vec3::new(277.5, 480.0, 554.0),
+        Vec3::new(355.0, 380.0, 554.0),
+        Material::DiffuseLight(Constant::shared(Vec3::new(0.4, 0.6, 1.0)), 4.0),
+    )));
+    world.push(Box::new(Sphere::new(
+        Vec3::new(420.0, 40.0, 120.0),
+        40.0,
+        Material::DiffuseLight(Constant::shared(Vec3::new(1.0, 0.6, 0.2)), 4.0),
+    )));
+
+    // A tall block and a glass ball
+    for side in quad::cuboid(
+        Vec3::new(265.0, 0.0, 295.0),
+        Vec3::new(430.0, 330.0, 460.0),
+        &white,
+    ) {
+        world.push(Box::new(side));
+    }
+    world.push(Box::new(Sphere::new(
+        Vec3::new(190.0, 90.0, 190.0),
+        90.0,
+        Material::Dielectric(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 1.5),
     )));
 
     world
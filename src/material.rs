@@ -1,14 +1,23 @@
+use std::f32;
+
 use hitable::HitRecord;
-use texture::Texture;
-use rand::{thread_rng, Rng};
 use ray::Ray;
+use sampler::{cosine_direction, Sampler};
+#[cfg(test)]
+use texture::Constant;
+use texture::TextureRef;
 use vector::Vec3;
 
-#[derive(Copy, Clone)]
+/// Every material takes a texture for its albedo. The textures are shared
+/// handles, so cloning a material is cheap
+#[derive(Clone)]
 pub enum Material {
-    Lambertian(Texture),
-    Metal(Vec3, f32),
-    Dielectric(Vec3, f32),
+    Lambertian(TextureRef),
+    Metal(TextureRef, f32),
+    Dielectric(TextureRef, f32),
+    /// Emits the texture's color scaled by the intensity from its front
+    /// side and does not reflect any light
+    DiffuseLight(TextureRef, f32),
 }
 
 impl Material {
@@ -18,46 +27,113 @@ impl Material {
     /// 1. Lambertian diffuse surface
     /// 2. Metal surface with a tune-able fuzzy factor
     /// 3. Dielectric surfaces with specular reflection
+    /// 4. Diffuse lights, which absorb everything
+    ///
     /// The return type of Option<Ray> allows us to indicate if ray was
     /// reflected or not. In case of the metal material, the light might
     /// not be reflected
-    pub fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<Ray> {
+    pub fn scatter(&self, ray: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Option<Ray> {
         match &self {
-            Material::Lambertian(_) => self.lambertian(ray, rec),
+            Material::Lambertian(_) => self.lambertian(ray, rec, sampler),
             Material::Metal(_, fuzz) => {
                 // Going to check if fuzz was properly set
                 // and give it an upper bound of 1.0
                 let fuzz_factor = if *fuzz <= 1.0 { *fuzz } else { 1.0 };
-                self.metal(ray, fuzz_factor, rec)
+                self.metal(ray, fuzz_factor, rec, sampler)
+            }
+            Material::Dielectric(_, ri) => self.dielectric(*ri, ray, rec, sampler),
+            Material::DiffuseLight(_, _) => None,
+        }
+    }
+
+    /// The light given off by the surface towards the incoming ray. Only
+    /// lights emit anything and only on the side the normal points to
+    pub fn emitted(&self, ray: &Ray, rec: &HitRecord) -> Vec3 {
+        match self {
+            Material::DiffuseLight(texture, intensity)
+                if ray.direction().dot(&rec.normal) < 0.0 =>
+            {
+                texture.value(rec.u, rec.v, rec.p) * *intensity
+            }
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Scatter function for a Lambertian diffuse surface. Directions are
+    /// picked proportional to the cosine with the normal, which cancels
+    /// out the cosine in the rendering equation
+    fn lambertian(&self, ray: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Option<Ray> {
+        let direction = cosine_direction(
+            rec.facing_normal(ray),
+            sampler.next_f32(),
+            sampler.next_f32(),
+        );
+        Some(Ray::new(rec.p, direction, ray.time()))
+    }
+
+    /// Mirrors and glass only scatter into a single direction, so there is
+    /// no point in sampling lights for them
+    pub fn is_specular(&self) -> bool {
+        matches!(self, Material::Metal(_, _) | Material::Dielectric(_, _))
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        matches!(self, Material::DiffuseLight(_, _))
+    }
+
+    /// The BSDF times the cosine with the normal for light arriving from
+    /// the direction. Only defined for non-specular surfaces
+    pub fn eval(&self, ray: &Ray, rec: &HitRecord, direction: Vec3) -> Vec3 {
+        match self {
+            Material::Lambertian(_) => {
+                let cosine = rec.facing_normal(ray).dot(&Vec3::unit_vec(direction));
+                if cosine > 0.0 {
+                    self.color(rec) * (cosine / f32::consts::PI)
+                } else {
+                    Vec3::new(0.0, 0.0, 0.0)
+                }
             }
-            Material::Dielectric(_, ri) => self.dielectric(*ri, ray, rec),
+            _ => Vec3::new(0.0, 0.0, 0.0),
         }
     }
 
-    /// Scatter function for a Lambertian diffuse surface.
-    fn lambertian(&self, ray: &Ray, rec: &HitRecord) -> Option<Ray> {
-        let target = rec.p + rec.normal + self.random_unit_in_sphere();
-        Some(Ray::new(rec.p, target - rec.p, ray.time()))
+    /// The solid angle density of `scatter` picking the direction. Only
+    /// defined for non-specular surfaces
+    pub fn scattering_pdf(&self, ray: &Ray, rec: &HitRecord, direction: Vec3) -> f32 {
+        match self {
+            Material::Lambertian(_) => {
+                let cosine = rec.facing_normal(ray).dot(&Vec3::unit_vec(direction));
+                cosine.max(0.0) / f32::consts::PI
+            }
+            _ => 0.0,
+        }
     }
 
     /// Scatter function for a metal surface. We are able to adjust
     /// the "roughess" of the surface through a fuzzy factor that
     /// makes it so the surface scatters more light and the reflection
     /// starts to become more diffuse.
-    fn metal(&self, ray: &Ray, fuzz: f32, rec: &HitRecord) -> Option<Ray> {
-        let reflected = Vec3::unit_vec(ray.direction()).reflect(rec.normal);
-        let fuzzed_reflector = reflected + self.random_unit_in_sphere() * fuzz;
+    fn metal(&self, ray: &Ray, fuzz: f32, rec: &HitRecord, sampler: &mut Sampler) -> Option<Ray> {
+        let normal = rec.facing_normal(ray);
+        let reflected = Vec3::unit_vec(ray.direction()).reflect(normal);
+        let fuzzed_reflector = reflected + self.random_unit_in_sphere(sampler) * fuzz;
         let scattered = Ray::new(rec.p, fuzzed_reflector, ray.time());
 
-        return if scattered.direction().dot(&rec.normal) > 0.0 {
+        if scattered.direction().dot(&normal) > 0.0 {
             Some(scattered)
         } else {
             None
-        };
+        }
     }
 
     /// Calculates the next outgoing ray for a dielectric surface.
-    fn dielectric(&self, ref_idx: f32, ray: &Ray, rec: &HitRecord) -> Option<Ray> {
+    fn dielectric(
+        &self,
+        ref_idx: f32,
+        ray: &Ray,
+        rec: &HitRecord,
+        sampler: &mut Sampler,
+    ) -> Option<Ray> {
         let reflected = ray.direction().reflect(rec.normal);
         let (outward_normal, ni_over_nt, cosine) = if ray.direction().dot(&rec.normal) > 0.0 {
             let outward_normal = rec.normal * -1.0;
@@ -78,8 +154,7 @@ impl Material {
             1.0
         };
 
-        let mut rng = thread_rng();
-        if rng.gen_range(0.0, 1.0) < reflect_prob {
+        if sampler.next_f32() < reflect_prob {
             Some(Ray::new(rec.p, reflected, ray.time()))
         } else {
             Some(Ray::new(rec.p, refracted.unwrap(), ray.time()))
@@ -88,18 +163,13 @@ impl Material {
 
     /// This is used in the diffuse and metal surface reflection calculations
     /// to find a new random vector to reflect to
-    fn random_unit_in_sphere(&self) -> Vec3 {
-        let mut rng = thread_rng();
-        let mut p = Vec3::new(
-            rng.gen_range(0.0, 1.0),
-            rng.gen_range(0.0, 1.0),
-            rng.gen_range(0.0, 1.0),
-        );
+    fn random_unit_in_sphere(&self, sampler: &mut Sampler) -> Vec3 {
+        let mut p = Vec3::new(sampler.next_f32(), sampler.next_f32(), sampler.next_f32());
 
         while p.squared_length() >= 1.0 {
-            let rand_x: f32 = rng.gen_range(0.0, 1.0);
-            let rand_y: f32 = rng.gen_range(0.0, 1.0);
-            let rand_z: f32 = rng.gen_range(0.0, 1.0);
+            let rand_x = sampler.next_f32();
+            let rand_y = sampler.next_f32();
+            let rand_z = sampler.next_f32();
             p = Vec3::new(rand_x, rand_y, rand_z) * 2.0;
             p = p - Vec3::new(1.0, 1.0, 1.0);
         }
@@ -111,12 +181,12 @@ impl Material {
         let uv = Vec3::unit_vec(*v);
         let dt = uv.dot(normal);
         let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
-        return if discriminant > 0.0 {
+        if discriminant > 0.0 {
             let r = (uv - *normal * dt) * ni_over_nt - (*normal * discriminant.sqrt());
             Some(r)
         } else {
             None
-        };
+        }
     }
 
     /// Calculates the Fresnel factor in a specular reflection
@@ -126,18 +196,48 @@ impl Material {
         r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
     }
 
-    pub fn color(self) -> Vec3 {
+    /// The color of the surface at the point that was hit
+    pub fn color(&self, rec: &HitRecord) -> Vec3 {
         match self {
-            Material::Lambertian(texture) => texture.value(0.0, 0.0, Vec3::new(0.0, 0.0, 0.0)),
-            Material::Metal(color, _) => color,
-            Material::Dielectric(color, _) => color,
+            Material::Lambertian(texture) => texture.value(rec.u, rec.v, rec.p),
+            Material::Metal(texture, _) => texture.value(rec.u, rec.v, rec.p),
+            Material::Dielectric(texture, _) => texture.value(rec.u, rec.v, rec.p),
+            Material::DiffuseLight(texture, _) => texture.value(rec.u, rec.v, rec.p),
         }
     }
 }
 
 #[test]
 fn test_materials() {
-    let mat = Material::Lambertian(Vec3::new(1.0, 0.0, 0.0));
-    let v = mat.random_unit_in_sphere();
-    assert!(v.squared_length() < 1.0);
+    let mat = Material::Lambertian(Constant::shared(Vec3::new(1.0, 0.0, 0.0)));
+    let mut sampler = Sampler::new(5, 0);
+    for _ in 0..100 {
+        let v = mat.random_unit_in_sphere(&mut sampler);
+        assert!(v.squared_length() < 1.0);
+    }
+}
+
+#[test]
+fn test_emitted() {
+    let light = Material::DiffuseLight(Constant::shared(Vec3::new(1.0, 0.5, 0.25)), 4.0);
+    let mut rec = HitRecord::new(&light);
+    rec.normal = Vec3::new(0.0, 1.0, 0.0);
+
+    // Lights are seen from the front but not from behind
+    let down = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    let up = Ray::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+    assert_eq!(light.emitted(&down, &rec), Vec3::new(4.0, 2.0, 1.0));
+    assert_eq!(light.emitted(&up, &rec), Vec3::new(0.0, 0.0, 0.0));
+    let mut sampler = Sampler::new(5, 0);
+    assert!(light.scatter(&down, &rec, &mut sampler).is_none());
+
+    // Nothing else glows
+    let diffuse = Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0)));
+    assert_eq!(diffuse.emitted(&down, &rec), Vec3::new(0.0, 0.0, 0.0));
+
+    // Diffuse surfaces hit from behind scatter back towards the ray
+    for _ in 0..10 {
+        let scattered = diffuse.scatter(&up, &rec, &mut sampler).unwrap();
+        assert!(scattered.direction().y() <= 0.0);
+    }
 }
@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::f32;
+use std::sync::Arc;
+
+use aabb::AABB;
+use hitable::{HitRecord, Hitable};
+use material::Material;
+use ray::Ray;
+use sampler::Sampler;
+#[cfg(test)]
+use texture::Constant;
+use triangle;
+use vector::Vec3;
+
+/// An indexed triangle mesh. Every vertex attribute is only stored once
+/// and shared between all the triangles that reference it. Normals and
+/// texture coordinates are optional, but if present there has to be one
+/// per position
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    // Three consecutive indices make up a triangle
+    indices: Vec<u32>,
+    material: Material,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f32, f32)>,
+        indices: Vec<u32>,
+        material: Material,
+    ) -> TriangleMesh {
+        assert!(
+            indices.len().is_multiple_of(3),
+            "Index buffer is not made of triangles"
+        );
+        assert!(
+            indices.iter().all(|i| (*i as usize) < positions.len()),
+            "Index out of bounds in TriangleMesh"
+        );
+        assert!(normals.is_empty() || normals.len() == positions.len());
+        assert!(uvs.is_empty() || uvs.len() == positions.len());
+
+        TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            indices,
+            material,
+        }
+    }
+
+    /// The number of triangles in the mesh
+    pub fn len(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn has_normals(&self) -> bool {
+        !self.normals.is_empty()
+    }
+
+    /// Computes smooth vertex normals by averaging the normals of the faces
+    /// around each vertex, weighted by the angle of the face at that vertex.
+    /// Faces whose normals differ by more than the crease angle (in degrees)
+    /// are not averaged, so the vertices on such an edge get split up and the
+    /// edge stays sharp. Any normals the mesh already had are replaced
+    pub fn generate_normals(self, crease_angle: f32) -> TriangleMesh {
+        let cos_crease = (crease_angle.clamp(0.0, 180.0) * f32::consts::PI / 180.0).cos();
+        let face_count = self.len();
+
+        // Unit normal of every face and the angle of every corner
+        let mut face_normals = Vec::with_capacity(face_count);
+        let mut corner_angles = Vec::with_capacity(self.indices.len());
+        for face in 0..face_count as u32 {
+            let (v0, v1, v2) = self.vertices(face);
+            let normal = (v1 - v0).cross(&(v2 - v0));
+            face_normals.push(if normal.length() > 0.0 {
+                Vec3::unit_vec(normal)
+            } else {
+                normal
+            });
+            corner_angles.push(angle_between(v1 - v0, v2 - v0));
+            corner_angles.push(angle_between(v2 - v1, v0 - v1));
+            corner_angles.push(angle_between(v0 - v2, v1 - v2));
+        }
+
+        // All the corners that share a position. We go by the coordinates
+        // rather than the index since vertices get duplicated along texture seams
+        let mut adjacent: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+        for (corner, index) in self.indices.iter().enumerate() {
+            adjacent
+                .entry(bits(self.positions[*index as usize]))
+                .or_default()
+                .push(corner);
+        }
+
+        let mut positions = Vec::with_capacity(self.positions.len());
+        let mut normals = Vec::with_capacity(self.positions.len());
+        let mut uvs = Vec::new();
+        let mut indices = Vec::with_capacity(self.indices.len());
+        let mut vertex_map: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+
+        for (corner, index) in self.indices.iter().enumerate() {
+            let face_normal = face_normals[corner / 3];
+            let mut normal = Vec3::new(0.0, 0.0, 0.0);
+            for other in &adjacent[&bits(self.positions[*index as usize])] {
+                let other_normal = face_normals[other / 3];
+                if *other == corner || face_normal.dot(&other_normal) >= cos_crease {
+                    normal = normal + other_normal * corner_angles[*other];
+                }
+            }
+            let normal = if normal.length() > 0.0 {
+                Vec3::unit_vec(normal)
+            } else {
+                face_normal
+            };
+
+            // Corners that end up with the same normal can keep sharing a vertex
+            let key = (*index, bits(normal));
+            let next_index = positions.len() as u32;
+            let vertex = *vertex_map.entry(key).or_insert(next_index);
+            if vertex == next_index {
+                positions.push(self.positions[*index as usize]);
+                normals.push(normal);
+                if !self.uvs.is_empty() {
+                    uvs.push(self.uvs[*index as usize]);
+                }
+            }
+            indices.push(vertex);
+        }
+
+        TriangleMesh::new(positions, normals, uvs, indices, self.material)
+    }
+
+    /// Splits the mesh into references to its individual triangles,
+    /// so they can be put into a BVH just like any other hitable
+    pub fn triangles(mesh: &Arc<TriangleMesh>) -> impl Iterator<Item = MeshTriangle> {
+        let mesh = mesh.clone();
+        (0..mesh.len() as u32).map(move |index| MeshTriangle {
+            mesh: mesh.clone(),
+            index,
+        })
+    }
+
+    fn vertices(&self, triangle: u32) -> (Vec3, Vec3, Vec3) {
+        let i = 3 * triangle as usize;
+        (
+            self.positions[self.indices[i] as usize],
+            self.positions[self.indices[i + 1] as usize],
+            self.positions[self.indices[i + 2] as usize],
+        )
+    }
+
+    /// Interpolates the vertex normals at the given barycentric coordinates
+    fn shading_normal(&self, triangle: u32, u: f32, v: f32) -> Vec3 {
+        let i = 3 * triangle as usize;
+        let n0 = self.normals[self.indices[i] as usize];
+        let n1 = self.normals[self.indices[i + 1] as usize];
+        let n2 = self.normals[self.indices[i + 2] as usize];
+        Vec3::unit_vec(n0 * (1.0 - u - v) + n1 * u + n2 * v)
+    }
+
+    /// Interpolates the texture coordinates at the given barycentric
+    /// coordinates. Without any we fall back to the barycentrics themselves
+    fn texture_coordinates(&self, triangle: u32, u: f32, v: f32) -> (f32, f32) {
+        if self.uvs.is_empty() {
+            return (u, v);
+        }
+
+        let i = 3 * triangle as usize;
+        let uv0 = self.uvs[self.indices[i] as usize];
+        let uv1 = self.uvs[self.indices[i + 1] as usize];
+        let uv2 = self.uvs[self.indices[i + 2] as usize];
+        let w = 1.0 - u - v;
+        (
+            uv0.0 * w + uv1.0 * u + uv2.0 * v,
+            uv0.1 * w + uv1.1 * u + uv2.1 * v,
+        )
+    }
+}
+
+/// The exact bit pattern of a vector, so it can be used as a hash map key
+fn bits(v: Vec3) -> [u32; 3] {
+    [v.x().to_bits(), v.y().to_bits(), v.z().to_bits()]
+}
+
+fn angle_between(a: Vec3, b: Vec3) -> f32 {
+    let lengths = a.length() * b.length();
+    if lengths > 0.0 {
+        (a.dot(&b) / lengths).clamp(-1.0, 1.0).acos()
+    } else {
+        0.0
+    }
+}
+
+/// A single triangle of a mesh. It only knows which triangle it is,
+/// the vertex data is looked up in the shared buffers
+pub struct MeshTriangle {
+    mesh: Arc<TriangleMesh>,
+    index: u32,
+}
+
+impl Hitable for MeshTriangle {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let (v0, v1, v2) = self.mesh.vertices(self.index);
+        let (t, u, v) = triangle::intersect(v0, v1, v2, r, t_min, t_max)?;
+
+        let mut record = HitRecord::new(&self.mesh.material);
+        record.t = t;
+        record.p = r.point_at_t(t);
+        // Without vertex normals we behave like a standalone triangle
+        // and use the face normal, following the winding order
+        record.normal = if self.mesh.has_normals() {
+            self.mesh.shading_normal(self.index, u, v)
+        } else {
+            Vec3::unit_vec((v1 - v0).cross(&(v2 - v0)))
+        };
+        record.barycentric = (u, v);
+        let (tex_u, tex_v) = self.mesh.texture_coordinates(self.index, u, v);
+        record.u = tex_u;
+        record.v = tex_v;
+        Some(record)
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        let (v0, v1, v2) = self.mesh.vertices(self.index);
+        Some(triangle::bounding_box(v0, v1, v2))
+    }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.mesh.material)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.mesh.material.is_emissive()
+    }
+
+    fn sample_direction(&self, origin: Vec3, sampler: &mut Sampler) -> Option<Vec3> {
+        let (v0, v1, v2) = self.mesh.vertices(self.index);
+        Some(triangle::sample(v0, v1, v2, sampler) - origin)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3) -> f32 {
+        let (v0, v1, v2) = self.mesh.vertices(self.index);
+        triangle::pdf(v0, v1, v2, origin, direction)
+    }
+}
+
+#[test]
+fn test_triangles() {
+    use hitable_list::HitableList;
+    use triangle::Triangle;
+
+    // A unit quad made of two triangles sharing an edge
+    let positions = vec![
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(1.0, 0.0, 0.0),
+        Vec3::new(1.0, 1.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+    ];
+    let material = Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0)));
+    let mesh = TriangleMesh::new(
+        positions.clone(),
+        Vec::new(),
+        Vec::new(),
+        vec![0, 1, 2, 0, 2, 3],
+        material.clone(),
+    );
+    assert_eq!(mesh.len(), 2);
+
+    let mut from_mesh = HitableList::new();
+    from_mesh.push_mesh(mesh);
+    let mut standalone = HitableList::new();
+    standalone.push(Box::new(Triangle::new(
+        positions[0],
+        positions[1],
+        positions[2],
+        material.clone(),
+    )));
+    standalone.push(Box::new(Triangle::new(
+        positions[0],
+        positions[2],
+        positions[3],
+        material,
+    )));
+
+    for i in 0..10 {
+        for j in 0..10 {
+            let origin = Vec3::new(i as f32 * 0.15 - 0.2, j as f32 * 0.15 - 0.2, 1.0);
+            let ray = Ray::new(origin, Vec3::new(0.1, 0.0, -1.0), 0.0);
+            let expected = standalone.intersect(&ray, 0.001, 10.0);
+            let actual = from_mesh.intersect(&ray, 0.001, 10.0);
+            assert_eq!(expected.map(|r| r.t), actual.map(|r| r.t));
+            assert_eq!(expected.map(|r| r.normal), actual.map(|r| r.normal));
+        }
+    }
+
+    let bb = from_mesh.bounding_box().unwrap();
+    assert_eq!(bb.max().x(), 1.0);
+    assert_eq!(bb.max().y(), 1.0);
+}
+
+#[cfg(test)]
+fn tent() -> TriangleMesh {
+    // Two faces meeting at a right angle along the edge from 0 to 1
+    TriangleMesh::new(
+        vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ],
+        Vec::new(),
+        Vec::new(),
+        vec![0, 1, 2, 0, 3, 1],
+        Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0))),
+    )
+}
+
+#[test]
+fn test_generate_normals() {
+    // Below the crease angle the shared edge gets averaged normals
+    let smooth = tent().generate_normals(120.0);
+    assert_eq!(smooth.positions.len(), 4);
+    let n = smooth.normals[smooth.indices[0] as usize];
+    assert!((n - Vec3::unit_vec(Vec3::new(1.0, 1.0, 0.0))).length() < 1e-6);
+
+    // Above it the edge is split and stays sharp
+    let sharp = tent().generate_normals(60.0);
+    assert_eq!(sharp.positions.len(), 6);
+    for (corner, index) in sharp.indices.iter().enumerate() {
+        let expected = if corner < 3 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        assert_eq!(sharp.normals[*index as usize], expected);
+    }
+}
+
+#[test]
+fn test_interpolated_normal() {
+    let mesh = Arc::new(tent().generate_normals(120.0));
+    let triangles: Vec<MeshTriangle> = TriangleMesh::triangles(&mesh).collect();
+
+    // Close to the shared edge the normal leans towards the other face
+    let ray = Ray::new(Vec3::new(0.01, 1.0, 0.5), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    let near_edge = triangles[0].hit(&ray, 0.001, 10.0).unwrap();
+    assert!(near_edge.normal.x() > 0.6);
+
+    // Far away from it the normal is almost the face normal
+    let ray = Ray::new(Vec3::new(0.98, 1.0, 0.01), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    let far_from_edge = triangles[0].hit(&ray, 0.001, 10.0).unwrap();
+    assert!(far_from_edge.normal.y() > 0.99);
+    assert!((far_from_edge.normal.length() - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_texture_coordinates() {
+    let make_mesh = || {
+        TriangleMesh::new(
+            vec![
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+            ],
+            Vec::new(),
+            vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+            vec![0, 1, 2],
+            Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0))),
+        )
+    };
+    let mesh = Arc::new(make_mesh());
+    let triangle = TriangleMesh::triangles(&mesh).next().unwrap();
+
+    let ray = Ray::new(Vec3::new(0.5, 1.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let hit = triangle.hit(&ray, 0.001, 10.0).unwrap();
+    assert_eq!((hit.u, hit.v), (0.25, 0.5));
+
+    // Generating normals has to carry the texture coordinates along
+    let with_normals = make_mesh().generate_normals(60.0);
+    assert_eq!(with_normals.uvs, vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]);
+}
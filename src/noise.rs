@@ -0,0 +1,305 @@
+use std::f32;
+
+use sampler::splitmix64;
+use texture::Texture;
+use vector::Vec3;
+
+/// Gradient noise as described in Ken Perlin's "Improving Noise". The
+/// lattice is shuffled with our own generator instead of `rand`, so the
+/// same seed always gives the same pattern, no matter which version of
+/// `rand` we end up linking against
+#[derive(Clone)]
+pub struct Perlin {
+    // The permutation is repeated once so lookups never have to wrap
+    perm: Vec<u8>,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut table: Vec<u8> = (0..=255).collect();
+        let mut state = seed;
+        // Fisher-Yates shuffle
+        for i in (1..table.len()).rev() {
+            let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        let mut perm = table.clone();
+        perm.extend_from_slice(&table);
+        Perlin { perm }
+    }
+
+    /// Noise value at a point, roughly within [-1, 1]. It is zero at every
+    /// integer coordinate
+    pub fn noise(&self, p: Vec3) -> f32 {
+        let (x, y, z) = (p.x().floor(), p.y().floor(), p.z().floor());
+        let (fx, fy, fz) = (p.x() - x, p.y() - y, p.z() - z);
+        let xi = (x as i64 & 255) as usize;
+        let yi = (y as i64 & 255) as usize;
+        let zi = (z as i64 & 255) as usize;
+
+        let perm = &self.perm;
+        let a = perm[xi] as usize + yi;
+        let aa = perm[a] as usize + zi;
+        let ab = perm[a + 1] as usize + zi;
+        let b = perm[xi + 1] as usize + yi;
+        let ba = perm[b] as usize + zi;
+        let bb = perm[b + 1] as usize + zi;
+
+        let (u, v, w) = (fade(fx), fade(fy), fade(fz));
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(
+                    u,
+                    grad(perm[aa], fx, fy, fz),
+                    grad(perm[ba], fx - 1.0, fy, fz),
+                ),
+                lerp(
+                    u,
+                    grad(perm[ab], fx, fy - 1.0, fz),
+                    grad(perm[bb], fx - 1.0, fy - 1.0, fz),
+                ),
+            ),
+            lerp(
+                v,
+                lerp(
+                    u,
+                    grad(perm[aa + 1], fx, fy, fz - 1.0),
+                    grad(perm[ba + 1], fx - 1.0, fy, fz - 1.0),
+                ),
+                lerp(
+                    u,
+                    grad(perm[ab + 1], fx, fy - 1.0, fz - 1.0),
+                    grad(perm[bb + 1], fx - 1.0, fy - 1.0, fz - 1.0),
+                ),
+            ),
+        )
+    }
+
+    /// Fractional Brownian motion. Every octave doubles the frequency and
+    /// halves the amplitude. The sum is normalized to stay roughly
+    /// within [-1, 1]
+    pub fn fbm(&self, p: Vec3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut point = p;
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(point);
+            total += amplitude;
+            amplitude *= 0.5;
+            point = point * 2.0;
+        }
+        if total > 0.0 {
+            sum / total
+        } else {
+            0.0
+        }
+    }
+
+    /// Like fBm but sums up the absolute values, which gives the creases
+    /// that marble veins are made of. The result is within [0, 1]
+    pub fn turbulence(&self, p: Vec3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut point = p;
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(point).abs();
+            total += amplitude;
+            amplitude *= 0.5;
+            point = point * 2.0;
+        }
+        if total > 0.0 {
+            (sum / total).min(1.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Stripes that get bent by turbulence
+#[derive(Clone)]
+pub struct Marble {
+    noise: Perlin,
+    scale: f32,
+    base: Vec3,
+    vein: Vec3,
+}
+
+impl Texture for Marble {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
+        let p = p * self.scale;
+        let phase = p.z() + 10.0 * self.noise.turbulence(p, 7);
+        let t = 0.5 * (1.0 + phase.sin());
+        mix(self.vein, self.base, t)
+    }
+}
+
+impl Marble {
+    /// The scale is the frequency of the pattern, larger values give
+    /// finer veins
+    pub fn new(seed: u64, scale: f32, base: Vec3, vein: Vec3) -> Marble {
+        Marble {
+            noise: Perlin::new(seed),
+            scale,
+            base,
+            vein,
+        }
+    }
+}
+
+/// Growth rings around the y axis that are slightly distorted by noise
+#[derive(Clone)]
+pub struct Wood {
+    noise: Perlin,
+    scale: f32,
+    light: Vec3,
+    dark: Vec3,
+}
+
+impl Texture for Wood {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
+        let p = p * self.scale;
+        let radius = (p.x() * p.x() + p.z() * p.z()).sqrt();
+        let rings = radius + 0.4 * self.noise.fbm(p * 0.5, 4);
+        let ring = rings - rings.floor();
+        // Wide light bands with narrow dark edges
+        mix(self.light, self.dark, smoothstep(0.6, 0.95, ring))
+    }
+}
+
+impl Wood {
+    /// The scale is the number of rings per unit distance from the axis
+    pub fn new(seed: u64, scale: f32, light: Vec3, dark: Vec3) -> Wood {
+        Wood {
+            noise: Perlin::new(seed),
+            scale,
+            light,
+            dark,
+        }
+    }
+}
+
+/// A speckled stone pattern made of high frequency fBm
+#[derive(Clone)]
+pub struct Granite {
+    noise: Perlin,
+    scale: f32,
+    light: Vec3,
+    dark: Vec3,
+}
+
+impl Texture for Granite {
+    fn value(&self, _u: f32, _v: f32, p: Vec3) -> Vec3 {
+        let p = p * self.scale;
+        let grain = self.noise.fbm(p, 6);
+        let speckle = smoothstep(0.2, 0.35, self.noise.noise(p * 4.0).abs());
+        let t = (0.5 + grain).clamp(0.0, 1.0) * speckle;
+        mix(self.dark, self.light, t)
+    }
+}
+
+impl Granite {
+    /// The scale is the frequency of the grain
+    pub fn new(seed: u64, scale: f32, light: Vec3, dark: Vec3) -> Granite {
+        Granite {
+            noise: Perlin::new(seed),
+            scale,
+            light,
+            dark,
+        }
+    }
+}
+
+/// Smooth interpolation curve with zero first and second derivatives at
+/// both ends, so the lattice does not show up in the noise
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn mix(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+    a * (1.0 - t) + b * t
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Dot product of the offset with one of twelve gradients pointing at the
+/// edges of a cube, picked by the hash
+fn grad(hash: u8, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    let u = if h & 1 == 0 { u } else { -u };
+    let v = if h & 2 == 0 { v } else { -v };
+    u + v
+}
+
+#[test]
+fn test_perlin() {
+    let perlin = Perlin::new(7);
+    // Zero on the lattice
+    assert_eq!(perlin.noise(Vec3::new(3.0, -2.0, 5.0)), 0.0);
+
+    let mut min = f32::MAX;
+    let mut max = f32::MIN;
+    for i in 0..1000 {
+        let p = Vec3::new(i as f32 * 0.173, i as f32 * 0.311, i as f32 * -0.057);
+        let n = perlin.noise(p);
+        min = min.min(n);
+        max = max.max(n);
+        let fbm = perlin.fbm(p, 5);
+        assert!((-1.0..=1.0).contains(&fbm));
+        let turbulence = perlin.turbulence(p, 5);
+        assert!((0.0..=1.0).contains(&turbulence));
+    }
+    assert!(min >= -1.0 && max <= 1.0);
+    // It should not be flat either
+    assert!(max - min > 0.5);
+}
+
+#[test]
+fn test_determinism() {
+    let p = Vec3::new(1.3, 2.7, -0.4);
+    assert_eq!(Perlin::new(42).noise(p), Perlin::new(42).noise(p));
+    assert!(Perlin::new(42).noise(p) != Perlin::new(43).noise(p));
+
+    // Pinned values that catch any change to the noise, since that would
+    // make previously rendered images drift
+    let perlin = Perlin::new(1);
+    let samples: Vec<f32> = (0..4)
+        .map(|i| perlin.noise(Vec3::new(0.5 + i as f32, 0.25, 0.75)))
+        .collect();
+    let expected = [0.106194496, 0.21519995, 0.4268365, -0.55012083];
+    for (sample, expected) in samples.iter().zip(expected.iter()) {
+        assert!((sample - expected).abs() < 1e-6);
+    }
+
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let textures: Vec<Box<dyn Texture>> = vec![
+        Box::new(Marble::new(3, 4.0, white, black)),
+        Box::new(Wood::new(3, 4.0, white, black)),
+        Box::new(Granite::new(3, 4.0, white, black)),
+    ];
+    for texture in &textures {
+        let color = texture.value(0.0, 0.0, p);
+        assert_eq!(color, texture.value(0.0, 0.0, p));
+        assert!(color.x() >= 0.0 && color.x() <= 1.0);
+    }
+}
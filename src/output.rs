@@ -0,0 +1,941 @@
+extern crate deflate;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use aov::Aov;
+use denoise;
+use film::Film;
+use png::HasParameters;
+use tonemap::{ColorSpace, Operator, ToneMapping};
+use vector::Vec3;
+
+/// The image formats we can write, picked by the file extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// 8 or 16-bit gamma corrected RGB, optionally with alpha
+    Png,
+    /// Binary portable pixmap, 8 or 16-bit gamma corrected RGB
+    Ppm,
+    /// Portable float map, linear RGB
+    Pfm,
+    /// Radiance RGBE, linear and high dynamic range
+    Hdr,
+    /// OpenEXR, linear and high dynamic range, optionally with alpha
+    Exr,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "png" => Some(Format::Png),
+            "ppm" => Some(Format::Ppm),
+            "pfm" => Some(Format::Pfm),
+            "hdr" => Some(Format::Hdr),
+            "exr" => Some(Format::Exr),
+            _ => None,
+        }
+    }
+}
+
+/// Bits per channel of the low dynamic range formats
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Depth {
+    Eight,
+    Sixteen,
+}
+
+impl Depth {
+    fn max_value(self) -> u16 {
+        match self {
+            Depth::Eight => 255,
+            Depth::Sixteen => 65535,
+        }
+    }
+}
+
+/// How OpenEXR pixel data is compressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    /// Deflate over blocks of 16 scanlines
+    Zip,
+}
+
+/// How OpenEXR channels are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    /// 16-bit floats, which is plenty for color and halves the file size
+    Half,
+    Float,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Options {
+    pub depth: Depth,
+    /// Adds the coverage of the film as an alpha channel to the formats
+    /// that have one
+    pub alpha: bool,
+    pub compression: Compression,
+    pub precision: Precision,
+    /// Exposure and color space apply to every format, the tone mapping
+    /// operator and transfer function only to those that are quantized
+    pub tone: ToneMapping,
+    /// Denoises the image before anything else, if the film has the AOVs
+    /// to guide it
+    pub denoise: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            depth: Depth::Eight,
+            alpha: false,
+            compression: Compression::Zip,
+            precision: Precision::Half,
+            tone: ToneMapping::default(),
+            denoise: false,
+        }
+    }
+}
+
+/// Writes the film to a file in the format that matches its extension.
+/// OpenEXR files get the AOVs as extra layers, any other format writes
+/// each of them to its own file next to the image, e.g. image.albedo.png
+pub fn save(path: &Path, film: &Film, aovs: &[Aov], options: Options) -> io::Result<()> {
+    let format = Format::from_path(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the output has to end in .png, .ppm, .pfm, .hdr or .exr",
+        )
+    })?;
+    let (width, height) = (film.width(), film.height());
+    // A film only has AOVs if they were rendered
+    let aovs = if film.has_aovs() { aovs } else { &[] };
+    let colors = if options.denoise && film.has_aovs() {
+        denoise::denoise(film)
+    } else {
+        film.colors()
+    };
+    let alphas = if options.alpha {
+        Some(film.alphas())
+    } else {
+        None
+    };
+
+    if format == Format::Exr {
+        let colors = linear(&colors, &options.tone);
+        let mut channels = color_channels("", &["R", "G", "B"], &colors);
+        // OpenEXR expects colors to be premultiplied, like the film's
+        if let Some(alphas) = alphas {
+            channels.push(("A".to_string(), Samples::Float(alphas)));
+        }
+        for aov in aovs {
+            channels.extend(aov_channels(film, *aov, &options.tone));
+        }
+        let w = &mut BufWriter::new(File::create(path)?);
+        write_exr(w, width, height, &channels, options)?;
+        return w.flush();
+    }
+
+    write_image(
+        path,
+        format,
+        (width, height),
+        &colors,
+        alphas.as_deref(),
+        &options.tone,
+        options,
+    )?;
+    for aov in aovs {
+        let (values, tone) = match aov_tone(*aov, &options.tone) {
+            Some(tone) => (film.aov_values(*aov), tone),
+            // Data is written as it is, only squeezed into the range of
+            // the formats that get quantized
+            None if format == Format::Png || format == Format::Ppm => {
+                (visualize(film, *aov), raw_tone())
+            }
+            None => (film.aov_values(*aov), raw_tone()),
+        };
+        write_image(
+            &aov_path(path, *aov),
+            format,
+            (width, height),
+            &values,
+            None,
+            &tone,
+            options,
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes colors in any format but OpenEXR
+fn write_image(
+    path: &Path,
+    format: Format,
+    (width, height): (u32, u32),
+    colors: &[Vec3],
+    alphas: Option<&[f32]>,
+    tone: &ToneMapping,
+    options: Options,
+) -> io::Result<()> {
+    let w = &mut BufWriter::new(File::create(path)?);
+    match format {
+        Format::Png => {
+            let samples = quantize(colors, alphas, options.depth, tone);
+            write_png(w, width, height, &samples, alphas.is_some(), options.depth)?
+        }
+        Format::Ppm => {
+            let samples = quantize(colors, None, options.depth, tone);
+            write_ppm(w, width, height, &samples, options.depth)?
+        }
+        Format::Pfm => write_pfm(w, width, height, &linear(colors, tone))?,
+        Format::Hdr => write_hdr(w, width, height, &linear(colors, tone))?,
+        Format::Exr => unreachable!("OpenEXR files are written with all of their layers"),
+    }
+    w.flush()
+}
+
+/// Where an AOV is written when it can't be a layer of the image
+pub fn aov_path(path: &Path, aov: Aov) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}.{}", stem, aov.name(), extension))
+}
+
+/// How an AOV that holds light or reflectance is brought out, or None
+/// for data that has to stay as it is. Albedo is the same at any exposure
+/// and never needs tone mapping
+fn aov_tone(aov: Aov, tone: &ToneMapping) -> Option<ToneMapping> {
+    match aov {
+        Aov::Direct | Aov::Indirect => Some(*tone),
+        Aov::Albedo => Some(ToneMapping {
+            exposure: 0.0,
+            operator: Operator::Clamp,
+            color_space: tone.color_space,
+        }),
+        _ => None,
+    }
+}
+
+/// Leaves values untouched, apart from clamping them when quantized
+fn raw_tone() -> ToneMapping {
+    ToneMapping {
+        color_space: ColorSpace::Linear,
+        ..ToneMapping::default()
+    }
+}
+
+/// The OpenEXR channels of an AOV, as a layer named after it
+fn aov_channels(film: &Film, aov: Aov, tone: &ToneMapping) -> Vec<(String, Samples)> {
+    let layer = aov.name();
+    match aov {
+        Aov::ObjectId | Aov::MaterialId => {
+            vec![(format!("{}.id", layer), Samples::Uint(film.aov_ids(aov)))]
+        }
+        Aov::Depth => {
+            let depth = film.aov_values(aov).iter().map(|v| v.x()).collect();
+            vec![(format!("{}.Z", layer), Samples::Float(depth))]
+        }
+        Aov::Normal | Aov::Position => {
+            color_channels(layer, &["X", "Y", "Z"], &film.aov_values(aov))
+        }
+        _ => {
+            let tone = aov_tone(aov, tone).unwrap();
+            let values = linear(&film.aov_values(aov), &tone);
+            color_channels(layer, &["R", "G", "B"], &values)
+        }
+    }
+}
+
+/// One channel for every component of the values, in the layer if it
+/// has a name
+fn color_channels(layer: &str, names: &[&str; 3], values: &[Vec3]) -> Vec<(String, Samples)> {
+    (0..3)
+        .map(|k| {
+            let name = if layer.is_empty() {
+                names[k].to_string()
+            } else {
+                format!("{}.{}", layer, names[k])
+            };
+            (name, Samples::Float(values.iter().map(|v| v[k]).collect()))
+        })
+        .collect()
+}
+
+/// Squeezes data AOVs into [0, 1] so they can be looked at as images.
+/// Normals are mapped like in a normal map, depth and position are scaled
+/// by their range over the image and every ID gets its own color
+pub fn visualize(film: &Film, aov: Aov) -> Vec<Vec3> {
+    let values = film.aov_values(aov);
+    match aov {
+        Aov::Normal => values
+            .iter()
+            .map(|n| *n * 0.5 + Vec3::new(0.5, 0.5, 0.5))
+            .collect(),
+        Aov::Depth | Aov::Position => {
+            let mut min = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
+            let mut max = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
+            for v in values.iter() {
+                min = Vec3::new(min.x().min(v.x()), min.y().min(v.y()), min.z().min(v.z()));
+                max = Vec3::new(max.x().max(v.x()), max.y().max(v.y()), max.z().max(v.z()));
+            }
+            values
+                .iter()
+                .map(|v| {
+                    let scale = |k: usize| {
+                        let range = max[k] - min[k];
+                        if range > 0.0 {
+                            (v[k] - min[k]) / range
+                        } else {
+                            0.0
+                        }
+                    };
+                    Vec3::new(scale(0), scale(1), scale(2))
+                })
+                .collect()
+        }
+        Aov::ObjectId | Aov::MaterialId => film.aov_ids(aov).into_iter().map(id_color).collect(),
+        _ => values,
+    }
+}
+
+/// A color that is different enough for every ID. Zero stays black
+fn id_color(id: u32) -> Vec3 {
+    if id == 0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    let mut state = id as u64;
+    let hash = ::sampler::splitmix64(&mut state);
+    let channel = |shift: u32| 0.2 + 0.8 * ((hash >> shift) & 0xff) as f32 / 255.0;
+    Vec3::new(channel(0), channel(8), channel(16))
+}
+
+/// The colors with exposure and primaries applied, but still linear and
+/// unbounded
+fn linear(colors: &[Vec3], tone: &ToneMapping) -> Vec<Vec3> {
+    colors.iter().map(|c| tone.linear(*c)).collect()
+}
+
+/// Tone maps and quantizes colors into interleaved RGB samples, or RGBA
+/// if there are alphas. The colors are then divided by them again, since
+/// the formats we write store straight alpha
+pub fn quantize(
+    colors: &[Vec3],
+    alphas: Option<&[f32]>,
+    depth: Depth,
+    tone: &ToneMapping,
+) -> Vec<u16> {
+    let max = depth.max_value() as f32;
+    let quantize = move |value: f32| ((max + 0.99) * value).min(max) as u16;
+    colors
+        .iter()
+        .enumerate()
+        .flat_map(|(i, color)| {
+            let coverage = alphas.map(|alphas| alphas[i]);
+            let color = match coverage {
+                Some(coverage) if coverage > 0.0 => *color / coverage,
+                _ => *color,
+            };
+            let color = tone.display(color);
+            let mut samples = vec![
+                quantize(color.x()),
+                quantize(color.y()),
+                quantize(color.z()),
+            ];
+            if let Some(coverage) = coverage {
+                samples.push(quantize(coverage));
+            }
+            samples
+        })
+        .collect()
+}
+
+/// Writes interleaved RGB or RGBA samples as a PNG
+pub fn write_png<W: Write>(
+    w: &mut W,
+    width: u32,
+    height: u32,
+    samples: &[u16],
+    alpha: bool,
+    depth: Depth,
+) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(w, width, height);
+    let color = if alpha {
+        png::ColorType::RGBA
+    } else {
+        png::ColorType::RGB
+    };
+    let bits = match depth {
+        Depth::Eight => png::BitDepth::Eight,
+        Depth::Sixteen => png::BitDepth::Sixteen,
+    };
+    encoder.set(color).set(bits);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pack(samples, depth))?;
+    Ok(())
+}
+
+/// Writes interleaved RGB samples as a binary PPM
+pub fn write_ppm<W: Write>(
+    w: &mut W,
+    width: u32,
+    height: u32,
+    samples: &[u16],
+    depth: Depth,
+) -> io::Result<()> {
+    write!(w, "P6\n{} {}\n{}\n", width, height, depth.max_value())?;
+    w.write_all(&pack(samples, depth))
+}
+
+/// Samples as bytes. Both PNG and PPM store 16-bit values big endian
+fn pack(samples: &[u16], depth: Depth) -> Vec<u8> {
+    match depth {
+        Depth::Eight => samples.iter().map(|s| *s as u8).collect(),
+        Depth::Sixteen => samples.iter().flat_map(|s| s.to_be_bytes()).collect(),
+    }
+}
+
+/// Writes linear colors, top row first, as a little endian PFM. The
+/// format itself stores the bottom row first
+pub fn write_pfm<W: Write>(w: &mut W, width: u32, height: u32, pixels: &[Vec3]) -> io::Result<()> {
+    // A negative scale means little endian
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in pixels.chunks(width.max(1) as usize).rev() {
+        for pixel in row {
+            for k in 0..3 {
+                w.write_all(&pixel[k].to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes linear colors, top row first, as a Radiance picture. The
+/// scanlines are stored flat, which every reader understands
+pub fn write_hdr<W: Write>(w: &mut W, width: u32, height: u32, pixels: &[Vec3]) -> io::Result<()> {
+    write!(
+        w,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+    for pixel in pixels {
+        w.write_all(&rgbe(*pixel))?;
+    }
+    Ok(())
+}
+
+/// Shares one exponent between the three channels. Negative and NaN
+/// channels end up as zero
+fn rgbe(color: Vec3) -> [u8; 4] {
+    let max = color.x().max(color.y()).max(color.z());
+    if max.is_nan() || max < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+
+    // The mantissa of the brightest channel has to land in [128, 256)
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if max / 2f32.powi(exponent) >= 1.0 {
+        exponent += 1;
+    }
+    let exponent = exponent.min(127);
+    let scale = 256.0 / 2f32.powi(exponent);
+    [
+        (color.x() * scale) as u8,
+        (color.y() * scale) as u8,
+        (color.z() * scale) as u8,
+        (exponent + 128) as u8,
+    ]
+}
+
+/// The values of an OpenEXR channel, top row first
+pub enum Samples {
+    /// Linear values, stored at the precision of the options
+    Float(Vec<f32>),
+    /// Stored as 32-bit unsigned ints, so IDs stay exact
+    Uint(Vec<u32>),
+}
+
+/// Writes named channels, top row first, as a single part scanline
+/// OpenEXR file
+pub fn write_exr<W: Write>(
+    w: &mut W,
+    width: u32,
+    height: u32,
+    channels: &[(String, Samples)],
+    options: Options,
+) -> io::Result<()> {
+    // Channels have to be sorted by name
+    let mut channels: Vec<&(String, Samples)> = channels.iter().collect();
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let pixel_type = |samples: &Samples| match (samples, options.precision) {
+        (Samples::Uint(_), _) => 0i32,
+        (Samples::Float(_), Precision::Half) => 1,
+        (Samples::Float(_), Precision::Float) => 2,
+    };
+    let pixel_size: usize = channels
+        .iter()
+        .map(|(_, samples)| match pixel_type(samples) {
+            1 => 2,
+            _ => 4,
+        })
+        .sum();
+    let (compression, lines_per_block) = match options.compression {
+        Compression::None => (0, 1),
+        Compression::Zip => (3, 16),
+    };
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+    // Version 2 with no flags, which means a single part scanline image
+    header.extend_from_slice(&2u32.to_le_bytes());
+
+    let mut list = Vec::new();
+    for (name, samples) in channels.iter() {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+        list.extend_from_slice(&pixel_type(samples).to_le_bytes());
+        // Not perceptually linear, and three reserved bytes
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        // No subsampling
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+    attribute(&mut header, "channels", "chlist", &list);
+    attribute(&mut header, "compression", "compression", &[compression]);
+
+    let mut window = Vec::new();
+    for value in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    // Increasing y, so the top row comes first
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    let mut center = Vec::new();
+    center.extend_from_slice(&0f32.to_le_bytes());
+    center.extend_from_slice(&0f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &center);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    // Every block holds its scanlines one after another, and within a
+    // scanline all values of one channel before the next
+    let mut blocks = Vec::new();
+    for y0 in (0..height).step_by(lines_per_block) {
+        let y1 = (y0 + lines_per_block as u32).min(height);
+        let mut data = Vec::with_capacity((y1 - y0) as usize * width as usize * pixel_size);
+        for y in y0..y1 {
+            let row = (y * width) as usize..((y + 1) * width) as usize;
+            for (_, samples) in channels.iter() {
+                match samples {
+                    Samples::Uint(values) => {
+                        for value in values[row.clone()].iter() {
+                            data.extend_from_slice(&value.to_le_bytes());
+                        }
+                    }
+                    Samples::Float(values) => {
+                        for value in values[row.clone()].iter().cloned() {
+                            match options.precision {
+                                Precision::Half => {
+                                    data.extend_from_slice(&to_half(value).to_le_bytes())
+                                }
+                                Precision::Float => data.extend_from_slice(&value.to_le_bytes()),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if options.compression == Compression::Zip {
+            let compressed = deflate::deflate_bytes_zlib(&zip_predict(&data));
+            // Blocks that don't get smaller are stored as they are
+            if compressed.len() < data.len() {
+                data = compressed;
+            }
+        }
+
+        let mut block = Vec::with_capacity(data.len() + 8);
+        block.extend_from_slice(&(y0 as i32).to_le_bytes());
+        block.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        block.extend_from_slice(&data);
+        blocks.push(block);
+    }
+
+    // The offset table points at every block from the start of the file
+    w.write_all(&header)?;
+    let mut offset = (header.len() + blocks.len() * 8) as u64;
+    for block in blocks.iter() {
+        w.write_all(&offset.to_le_bytes())?;
+        offset += block.len() as u64;
+    }
+    for block in blocks.iter() {
+        w.write_all(block)?;
+    }
+    Ok(())
+}
+
+/// Appends a header attribute made up of its name, type, size and value
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Prepares data for ZIP compression the way OpenEXR expects it. The low
+/// and high bytes of the values are split into two halves, and every byte
+/// is replaced by its difference to the one before
+fn zip_predict(data: &[u8]) -> Vec<u8> {
+    let half = data.len().div_ceil(2);
+    let mut reordered = vec![0; data.len()];
+    for (i, byte) in data.iter().enumerate() {
+        let target = if i % 2 == 0 { i / 2 } else { half + i / 2 };
+        reordered[target] = *byte;
+    }
+
+    let mut previous = reordered.first().cloned().unwrap_or(0);
+    for byte in reordered.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    reordered
+}
+
+/// Converts to a 16-bit float, rounding to the nearest even value. Too
+/// large values become infinity
+pub fn to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // Infinity stays infinity and NaN stays NaN
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        // Too small for a normal half, so it becomes subnormal or zero
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = mantissa >> shift;
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round = remainder > halfway || (remainder == halfway && half & 1 == 1);
+        return sign | (half + round as u32) as u16;
+    }
+
+    // A carry out of the mantissa correctly bumps the exponent
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    let remainder = mantissa & 0x1fff;
+    let round = remainder > 0x1000 || (remainder == 0x1000 && half & 1 == 1);
+    sign | (half + round as u32) as u16
+}
+
+#[test]
+fn test_half() {
+    assert_eq!(to_half(0.0), 0x0000);
+    assert_eq!(to_half(-0.0), 0x8000);
+    assert_eq!(to_half(1.0), 0x3c00);
+    assert_eq!(to_half(0.5), 0x3800);
+    assert_eq!(to_half(-2.0), 0xc000);
+    assert_eq!(to_half(0.1), 0x2e66);
+    assert_eq!(to_half(65504.0), 0x7bff);
+    assert_eq!(to_half(65520.0), 0x7c00);
+    assert_eq!(to_half(f32::INFINITY), 0x7c00);
+    assert!(to_half(f32::NAN) & 0x3ff != 0);
+    // The smallest subnormal, and half of it rounding to even
+    assert_eq!(to_half(2f32.powi(-24)), 0x0001);
+    assert_eq!(to_half(2f32.powi(-25)), 0x0000);
+    assert_eq!(to_half(1.5 * 2f32.powi(-24)), 0x0002);
+    // Just below two rounds up into the next exponent
+    assert_eq!(to_half(1.9999), 0x4000);
+}
+
+#[test]
+fn test_rgbe() {
+    assert_eq!(rgbe(Vec3::new(0.0, 0.0, 0.0)), [0, 0, 0, 0]);
+    assert_eq!(rgbe(Vec3::new(1.0, 0.5, 0.0)), [128, 64, 0, 129]);
+    assert_eq!(rgbe(Vec3::new(0.25, 0.0, -1.0)), [128, 0, 0, 127]);
+
+    // Decoding gets close to the original
+    let color = Vec3::new(123.4, 5.6, 0.07);
+    let encoded = rgbe(color);
+    let scale = 2f32.powi(encoded[3] as i32 - 136);
+    for k in 0..3 {
+        let decoded = (encoded[k] as f32 + 0.5) * scale;
+        assert!((decoded - color[k]).abs() <= 0.5 * scale);
+    }
+
+    let mut bytes = Vec::new();
+    write_hdr(&mut bytes, 2, 1, &[color, color]).unwrap();
+    let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n";
+    assert!(bytes.starts_with(header));
+    assert_eq!(bytes.len(), header.len() + 8);
+}
+
+#[cfg(test)]
+fn read_i32(bytes: &[u8], at: usize) -> i32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[at..at + 4]);
+    i32::from_le_bytes(word)
+}
+
+#[test]
+fn test_exr() {
+    extern crate inflate;
+
+    let (width, height) = (5, 20);
+    let pixels: Vec<Vec3> = (0..width * height)
+        .map(|i| Vec3::new(i as f32, 0.5, -(i as f32) * 0.25))
+        .collect();
+
+    // Written in any order, stored sorted as B, G and R
+    let channels = vec![
+        (
+            "R".to_string(),
+            Samples::Float(pixels.iter().map(|c| c.x()).collect()),
+        ),
+        (
+            "G".to_string(),
+            Samples::Float(pixels.iter().map(|c| c.y()).collect()),
+        ),
+        (
+            "B".to_string(),
+            Samples::Float(pixels.iter().map(|c| c.z()).collect()),
+        ),
+    ];
+
+    for precision in [Precision::Half, Precision::Float].iter() {
+        for compression in [Compression::None, Compression::Zip].iter() {
+            let options = Options {
+                compression: *compression,
+                precision: *precision,
+                ..Options::default()
+            };
+            let mut bytes = Vec::new();
+            write_exr(&mut bytes, width, height, &channels, options).unwrap();
+            assert_eq!(&bytes[0..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+            // Find the end of the header, which is an empty attribute name
+            let mut at = 8;
+            while bytes[at] != 0 {
+                let name_end = at + bytes[at..].iter().position(|b| *b == 0).unwrap();
+                let kind_end =
+                    name_end + 1 + bytes[name_end + 1..].iter().position(|b| *b == 0).unwrap();
+                at = kind_end + 5 + read_i32(&bytes, kind_end + 1) as usize;
+            }
+            at += 1;
+
+            let lines = if *compression == Compression::Zip {
+                16
+            } else {
+                1
+            };
+            let blocks = (height as usize).div_ceil(lines);
+            let sample_size = if *precision == Precision::Half { 2 } else { 4 };
+            for block in 0..blocks {
+                let offset = read_i32(&bytes, at + block * 8) as usize;
+                assert_eq!(read_i32(&bytes, offset), (block * lines) as i32);
+                let size = read_i32(&bytes, offset + 4) as usize;
+                let mut data = bytes[offset + 8..offset + 8 + size].to_vec();
+
+                let rows = lines.min(height as usize - block * lines);
+                let expected_size = rows * width as usize * 3 * sample_size;
+                if data.len() < expected_size {
+                    // Undo the compression and the prediction
+                    let predicted = inflate::inflate_bytes_zlib(&data).unwrap();
+                    let mut deltas = predicted.clone();
+                    for i in 1..deltas.len() {
+                        deltas[i] = deltas[i - 1].wrapping_add(predicted[i]).wrapping_sub(128);
+                    }
+                    let half = deltas.len().div_ceil(2);
+                    data = (0..deltas.len())
+                        .map(|i| {
+                            if i % 2 == 0 {
+                                deltas[i / 2]
+                            } else {
+                                deltas[half + i / 2]
+                            }
+                        })
+                        .collect();
+                }
+                assert_eq!(data.len(), expected_size);
+
+                // The first value of a block is the blue channel of its
+                // first pixel, followed by the rest of the blue channel
+                let first = &pixels[block * lines * width as usize];
+                let mut blue = [0; 4];
+                blue[..sample_size].copy_from_slice(&data[0..sample_size]);
+                match *precision {
+                    Precision::Half => {
+                        assert_eq!(u16::from_le_bytes([blue[0], blue[1]]), to_half(first.z()))
+                    }
+                    Precision::Float => assert_eq!(f32::from_le_bytes(blue), first.z()),
+                }
+                // Green is always 0.5
+                let green = width as usize * sample_size;
+                match *precision {
+                    Precision::Half => assert_eq!(&data[green..green + 2], &[0x00, 0x38]),
+                    Precision::Float => {
+                        assert_eq!(&data[green..green + 4], &0.5f32.to_le_bytes())
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_ldr() {
+    let tone = ToneMapping::default();
+    let mut film = Film::new(2, 1);
+    film.pixel_mut(0, 0).add(Vec3::new(0.25, 1.0, 4.0), 1.0);
+    // Half covered, so its straight color is twice the premultiplied one
+    film.pixel_mut(1, 0).add(Vec3::new(0.5, 0.0, 0.125), 1.0);
+    film.pixel_mut(1, 0).add(Vec3::new(0.0, 0.0, 0.0), 0.0);
+    let (colors, alphas) = (film.colors(), film.alphas());
+    let alphas = Some(alphas.as_slice());
+
+    assert_eq!(
+        quantize(&colors, None, Depth::Eight, &tone),
+        vec![137, 255, 255, 137, 0, 70]
+    );
+    assert_eq!(
+        quantize(&colors, alphas, Depth::Eight, &tone),
+        vec![137, 255, 255, 255, 188, 0, 99, 127]
+    );
+    assert_eq!(
+        quantize(&colors, None, Depth::Sixteen, &tone)[0..3],
+        [35199, 65535, 65535]
+    );
+
+    // 16-bit samples are big endian
+    let mut ppm = Vec::new();
+    let samples = quantize(&colors, None, Depth::Sixteen, &tone);
+    write_ppm(&mut ppm, 2, 1, &samples, Depth::Sixteen).unwrap();
+    assert!(ppm.starts_with(b"P6\n2 1\n65535\n"));
+    assert_eq!(&ppm[ppm.len() - 12..ppm.len() - 10], &[0x89, 0x7f]);
+
+    // The PNG decodes to what went in
+    for &(depth, alpha) in [(Depth::Eight, true), (Depth::Sixteen, false)].iter() {
+        let samples = quantize(&colors, if alpha { alphas } else { None }, depth, &tone);
+        let mut bytes = Vec::new();
+        write_png(&mut bytes, 2, 1, &samples, alpha, depth).unwrap();
+        let mut decoder = png::Decoder::new(bytes.as_slice());
+        // Keep 16-bit samples as they are
+        decoder.set(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut decoded = vec![0; info.buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        assert_eq!(decoded, pack(&samples, depth));
+        let color = if alpha {
+            png::ColorType::RGBA
+        } else {
+            png::ColorType::RGB
+        };
+        assert_eq!(info.color_type, color);
+    }
+}
+
+#[test]
+fn test_pfm() {
+    let pixels = [
+        Vec3::new(1.0, 2.0, 3.0),
+        Vec3::new(4.0, 5.0, 6.0),
+        Vec3::new(-1.0, 0.5, 100.0),
+        Vec3::new(0.0, 0.0, 0.0),
+    ];
+    let mut bytes = Vec::new();
+    write_pfm(&mut bytes, 2, 2, &pixels).unwrap();
+    let header = b"PF\n2 2\n-1.0\n";
+    assert!(bytes.starts_with(header));
+    assert_eq!(bytes.len(), header.len() + 4 * 3 * 4);
+    // The bottom row comes first
+    let first = &bytes[header.len()..header.len() + 4];
+    assert_eq!(first, &(-1.0f32).to_le_bytes());
+}
+
+#[test]
+fn test_aovs() {
+    use aov::AovSample;
+
+    assert_eq!(
+        aov_path(Path::new("renders/image.png"), Aov::Albedo),
+        Path::new("renders/image.albedo.png")
+    );
+
+    let mut film = Film::new(2, 1);
+    film.enable_aovs();
+    film.aov_pixel_mut(0, 0).add(&AovSample {
+        normal: Vec3::new(0.0, 1.0, 0.0),
+        depth: 2.0,
+        object: 1,
+        surface: true,
+        ..AovSample::default()
+    });
+    film.aov_pixel_mut(1, 0).add(&AovSample {
+        normal: Vec3::new(-1.0, 0.0, 0.0),
+        depth: 6.0,
+        object: 2,
+        surface: true,
+        ..AovSample::default()
+    });
+    assert_eq!(
+        visualize(&film, Aov::Normal),
+        vec![Vec3::new(0.5, 1.0, 0.5), Vec3::new(0.0, 0.5, 0.5)]
+    );
+    assert_eq!(
+        visualize(&film, Aov::Depth),
+        vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0)]
+    );
+    let ids = visualize(&film, Aov::ObjectId);
+    assert!(ids[0] != ids[1]);
+    assert_eq!(id_color(0), Vec3::new(0.0, 0.0, 0.0));
+
+    // IDs are stored as unsigned ints after the half floats, since
+    // uppercase names sort first
+    let options = Options {
+        compression: Compression::None,
+        ..Options::default()
+    };
+    let mut bytes = Vec::new();
+    let mut channels = aov_channels(&film, Aov::ObjectId, &options.tone);
+    channels.push(("R".to_string(), Samples::Float(vec![1.0, 2.0])));
+    write_exr(&mut bytes, 2, 1, &channels, options).unwrap();
+    let data = &bytes[bytes.len() - 12..];
+    assert_eq!(read_i32(&bytes, bytes.len() - 16), 12);
+    assert_eq!(&data[0..4], &[0x00, 0x3c, 0x00, 0x40]);
+    assert_eq!(read_i32(data, 4), 1);
+    assert_eq!(read_i32(data, 8), 2);
+}
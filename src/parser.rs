@@ -1,25 +1,81 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use mesh::TriangleMesh;
+use texture::{Constant, ImageTexture, TextureRef, WrapMode};
 use HitableList;
 use Material;
-use Triangle;
 use Vec3;
 
 pub enum Parser {
+    #[allow(clippy::upper_case_acronyms)]
     OBJ(String),
 }
 
+/// Settings that influence how a scene file is turned into geometry
+#[derive(Copy, Clone, Debug)]
+pub struct ParseOptions {
+    /// When a mesh comes without normals, faces meeting at a sharper angle
+    /// than this (in degrees) keep a hard edge in the generated normals.
+    /// Zero skips generating them, which gives flat shading
+    pub crease_angle: f32,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions { crease_angle: 60.0 }
+    }
+}
+
+/// The indices that make up one corner of an OBJ face, already
+/// resolved to start at zero
+#[derive(Copy, Clone)]
+struct FaceVertex {
+    position: u32,
+    uv: Option<u32>,
+    normal: Option<u32>,
+}
+
 impl Parser {
     pub fn parse(&self) -> HitableList {
+        self.parse_with(&ParseOptions::default())
+    }
+
+    pub fn parse_with(&self, options: &ParseOptions) -> HitableList {
         match &self {
-            Parser::OBJ(path) => self.parse_obj(path),
+            Parser::OBJ(path) => self.parse_obj(path, options),
         }
     }
 
-    fn parse_obj(&self, path_name: &str) -> HitableList {
+    /// Every file the scene is read from: the scene file itself, followed
+    /// by the material libraries it uses and the images they reference
+    pub fn files(&self) -> Vec<PathBuf> {
+        match &self {
+            Parser::OBJ(path) => {
+                let path = PathBuf::from(path);
+                let mut files = vec![path.clone()];
+                // A line can list several libraries, but image names come
+                // last after any options
+                for name in arguments(&path, "mtllib ").concat() {
+                    let mtl = relative_path(&path, &name);
+                    let images: Vec<PathBuf> = arguments(&mtl, "map_Kd ")
+                        .iter()
+                        .filter_map(|args| args.last())
+                        .map(|name| relative_path(&mtl, name))
+                        .collect();
+                    files.push(mtl);
+                    files.extend(images);
+                }
+                files
+            }
+        }
+    }
+
+    fn parse_obj(&self, path_name: &str, options: &ParseOptions) -> HitableList {
         let path = Path::new(path_name);
         let file = match File::open(path) {
             Err(_) => panic!("Couldn't open {:?}", path),
@@ -29,43 +85,243 @@ impl Parser {
         let reader = BufReader::new(file);
         let lines = reader.lines().map(|l| l.unwrap());
         let mut list = HitableList::new();
-        let mut vector_table: Vec<Vec3> = Vec::new();
+        let mut positions: Vec<Vec3> = Vec::new();
+        let mut uvs: Vec<(f32, f32)> = Vec::new();
+        let mut normals: Vec<Vec3> = Vec::new();
+        let mut materials: HashMap<String, Material> = HashMap::new();
+        // Faces are grouped by the name of the material they use
+        let mut groups: Vec<(Option<String>, Vec<FaceVertex>)> = vec![(None, Vec::new())];
+        let mut group = 0;
         for line in lines {
-            if line.starts_with("v ") {
+            if line.starts_with("mtllib ") {
+                // Material libraries are relative to the OBJ file
+                for name in line.split_whitespace().skip(1) {
+                    materials.extend(parse_mtl(&relative_path(path, name)));
+                }
+            } else if line.starts_with("usemtl ") {
+                let name = line.split_whitespace().nth(1).map(String::from);
+                group = match groups.iter().position(|g| g.0 == name) {
+                    Some(index) => index,
+                    None => {
+                        groups.push((name, Vec::new()));
+                        groups.len() - 1
+                    }
+                };
+            } else if line.starts_with("v ") {
+                positions.push(parse_vec3(&line));
+            } else if line.starts_with("vt ") {
+                // A third texture coordinate is allowed but we have no use for it
                 let split: Vec<&str> = line.split_whitespace().skip(1).collect();
-                let new_vec = Vec3::new(
+                uvs.push((
                     split[0].parse::<f32>().unwrap(),
-                    split[1].parse::<f32>().unwrap(),
-                    split[2].parse::<f32>().unwrap(),
-                );
-                vector_table.push(new_vec);
-            } else if line.starts_with("f") {
-                // Only going to parse out the vertex indices for now
-                // TODO Support parsing of vertex normals and texture coordinates
-                let split: Vec<&str> = line[2..line.len()]
-                    .split(" ")
+                    split.get(1).map_or(0.0, |v| v.parse::<f32>().unwrap()),
+                ));
+            } else if line.starts_with("vn ") {
+                normals.push(parse_vec3(&line));
+            } else if line.starts_with("f ") {
+                // Corners are given as v, v/vt, v//vn or v/vt/vn
+                let face: Vec<FaceVertex> = line
+                    .split_whitespace()
+                    .skip(1)
                     .map(|group| {
-                        if group.contains("/") {
-                            &group[..group.find("/").unwrap()]
-                        } else {
-                            group
+                        let mut split = group.split('/').map(|index| index.parse::<i64>().ok());
+                        let position = split.next().and_then(|i| i).unwrap();
+                        let uv = split.next().and_then(|i| i);
+                        let normal = split.next().and_then(|i| i);
+                        FaceVertex {
+                            position: resolve_index(position, positions.len()),
+                            uv: uv.map(|index| resolve_index(index, uvs.len())),
+                            normal: normal.map(|index| resolve_index(index, normals.len())),
                         }
                     })
                     .collect();
 
-                let triangle = Triangle::new(
-                    vector_table[split[0].parse::<usize>().unwrap() - 1],
-                    vector_table[split[1].parse::<usize>().unwrap() - 1],
-                    vector_table[split[2].parse::<usize>().unwrap() - 1],
-                    Material::Lambertian(Vec3::new(1.0, 0.0, 0.0)),
-                );
-                list.push(Box::new(triangle));
+                // Polygons with more than three vertices are split into a fan.
+                // Anything with fewer has no area and is skipped
+                let corners = &mut groups[group].1;
+                for i in 1..face.len().saturating_sub(1) {
+                    corners.push(face[0]);
+                    corners.push(face[i]);
+                    corners.push(face[i + 1]);
+                }
             }
         }
+
+        // Every material gets its own mesh. Faces without a known material
+        // are red
+        for (name, mut corners) in groups {
+            if corners.is_empty() {
+                continue;
+            }
+
+            // Attributes are only used if every corner has them. If some normals
+            // are missing we come up with our own for the whole mesh
+            let has_uvs = corners.iter().all(|c| c.uv.is_some());
+            let has_normals = corners.iter().all(|c| c.normal.is_some());
+            for corner in corners.iter_mut() {
+                if !has_uvs {
+                    corner.uv = None;
+                }
+                if !has_normals {
+                    corner.normal = None;
+                }
+            }
+
+            let material = match name.and_then(|name| materials.get(&name).cloned()) {
+                Some(material) => material,
+                None => Material::Lambertian(Constant::shared(Vec3::new(1.0, 0.0, 0.0))),
+            };
+            let mut mesh = indexed_mesh(&positions, &uvs, &normals, &corners, material);
+            if !has_normals && options.crease_angle > 0.0 {
+                mesh = mesh.generate_normals(options.crease_angle);
+            }
+
+            list.push_mesh(mesh);
+        }
         list
     }
 }
 
+/// Reads the materials of an MTL file. Only the diffuse color (Kd) and the
+/// diffuse texture (map_Kd) are supported and every material becomes
+/// Lambertian. A texture takes the place of the diffuse color. Textures
+/// repeat unless they have `-clamp on`, or `-mirror on` to flip every
+/// other copy
+fn parse_mtl(path: &Path) -> HashMap<String, Material> {
+    let file = match File::open(path) {
+        Err(_) => panic!("Couldn't open {:?}", path),
+        Ok(file) => file,
+    };
+
+    let reader = BufReader::new(file);
+    let mut materials = HashMap::new();
+    // Materials that use the same image share the texture
+    let mut textures: HashMap<(PathBuf, WrapMode), TextureRef> = HashMap::new();
+    let mut current: Option<(String, TextureRef)> = None;
+    for line in reader.lines().map(|l| l.unwrap()) {
+        let line = line.trim();
+        if line.starts_with("newmtl ") {
+            if let Some((name, texture)) = current.take() {
+                materials.insert(name, Material::Lambertian(texture));
+            }
+            let name = line.split_whitespace().nth(1).unwrap_or("");
+            let white = Constant::shared(Vec3::new(1.0, 1.0, 1.0));
+            current = Some((String::from(name), white));
+        } else if line.starts_with("Kd ") {
+            if let Some((_, ref mut texture)) = current {
+                *texture = Constant::shared(parse_vec3(line));
+            }
+        } else if line.starts_with("map_Kd ") {
+            // The file name comes last, after any options
+            let split: Vec<&str> = line.split_whitespace().skip(1).collect();
+            let enabled = |option| {
+                split
+                    .iter()
+                    .position(|s| *s == option)
+                    .and_then(|i| split.get(i + 1))
+                    == Some(&"on")
+            };
+            let wrap = if enabled("-clamp") {
+                WrapMode::Clamp
+            } else if enabled("-mirror") {
+                WrapMode::Mirror
+            } else {
+                WrapMode::Repeat
+            };
+            let image_path = relative_path(path, split[split.len() - 1]);
+            let image = textures
+                .entry((image_path.clone(), wrap))
+                .or_insert_with(|| Arc::new(ImageTexture::open(&image_path, wrap)))
+                .clone();
+            if let Some((_, ref mut texture)) = current {
+                *texture = image;
+            }
+        }
+    }
+    if let Some((name, texture)) = current {
+        materials.insert(name, Material::Lambertian(texture));
+    }
+
+    materials
+}
+
+/// The arguments of every line that starts with the keyword
+fn arguments(path: &Path, keyword: &str) -> Vec<Vec<String>> {
+    let file = match File::open(path) {
+        Err(_) => panic!("Couldn't open {:?}", path),
+        Ok(file) => file,
+    };
+    BufReader::new(file)
+        .lines()
+        .map(|l| l.unwrap())
+        .filter(|line| line.trim().starts_with(keyword))
+        .map(|line| line.split_whitespace().skip(1).map(String::from).collect())
+        .collect()
+}
+
+/// Resolves a path found inside of a file relative to that file's directory
+fn relative_path(file: &Path, name: &str) -> PathBuf {
+    match file.parent() {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// OBJ faces index positions, texture coordinates and normals separately,
+/// while our meshes use a single index per vertex. Every distinct
+/// combination becomes a vertex
+fn indexed_mesh(
+    positions: &[Vec3],
+    uvs: &[(f32, f32)],
+    normals: &[Vec3],
+    corners: &[FaceVertex],
+    material: Material,
+) -> TriangleMesh {
+    let mut vertex_map: HashMap<(u32, Option<u32>, Option<u32>), u32> = HashMap::new();
+    let mut mesh_positions = Vec::new();
+    let mut mesh_uvs = Vec::new();
+    let mut mesh_normals = Vec::new();
+    let mut indices = Vec::with_capacity(corners.len());
+
+    for corner in corners {
+        let next_index = mesh_positions.len() as u32;
+        let vertex = *vertex_map
+            .entry((corner.position, corner.uv, corner.normal))
+            .or_insert(next_index);
+        if vertex == next_index {
+            mesh_positions.push(positions[corner.position as usize]);
+            if let Some(uv) = corner.uv {
+                mesh_uvs.push(uvs[uv as usize]);
+            }
+            if let Some(normal) = corner.normal {
+                mesh_normals.push(Vec3::unit_vec(normals[normal as usize]));
+            }
+        }
+        indices.push(vertex);
+    }
+
+    TriangleMesh::new(mesh_positions, mesh_normals, mesh_uvs, indices, material)
+}
+
+fn parse_vec3(line: &str) -> Vec3 {
+    let split: Vec<&str> = line.split_whitespace().skip(1).collect();
+    Vec3::new(
+        split[0].parse::<f32>().unwrap(),
+        split[1].parse::<f32>().unwrap(),
+        split[2].parse::<f32>().unwrap(),
+    )
+}
+
+/// OBJ indices start at one. Negative indices are relative to the
+/// end of the list of elements that have been read so far
+fn resolve_index(index: i64, len: usize) -> u32 {
+    if index < 0 {
+        (len as i64 + index) as u32
+    } else {
+        (index - 1) as u32
+    }
+}
+
 #[test]
 fn test_parse() {
     let parsed = Parser::OBJ(String::from("obj-data/african_head.obj")).parse();
@@ -76,3 +332,182 @@ fn test_parse() {
     let parsed = Parser::OBJ(String::from("obj-data/gourd.obj")).parse();
     assert_eq!(parsed.len(), 648);
 }
+
+#[test]
+fn test_parse_normals() {
+    use ray::Ray;
+
+    // The head comes with its own normals, the octahedron has none and
+    // gets one normal per face since all its edges are sharper than 60 degrees
+    let ray = Ray::new(Vec3::new(0.05, 0.1, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    for file in ["obj-data/african_head.obj", "obj-data/octahedron.obj"].iter() {
+        let parsed = Parser::OBJ(String::from(*file)).parse();
+        let hit = parsed.intersect(&ray, 0.001, 100.0).unwrap();
+        assert!((hit.normal.length() - 1.0).abs() < 1e-5);
+        assert!(hit.normal.z() > 0.0);
+    }
+
+    // Without a crease angle there are no vertex normals to smooth with
+    let flat = Parser::OBJ(String::from("obj-data/gourd.obj"))
+        .parse_with(&ParseOptions { crease_angle: 0.0 });
+    let smooth = Parser::OBJ(String::from("obj-data/gourd.obj")).parse();
+    let ray = Ray::new(Vec3::new(0.1, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let flat_hit = flat.intersect(&ray, 0.001, 100.0).unwrap();
+    let smooth_hit = smooth.intersect(&ray, 0.001, 100.0).unwrap();
+    assert_eq!(flat_hit.t, smooth_hit.t);
+    assert!(flat_hit.normal != smooth_hit.normal);
+}
+
+#[test]
+fn test_resolve_index() {
+    assert_eq!(resolve_index(1, 3), 0);
+    assert_eq!(resolve_index(3, 3), 2);
+    assert_eq!(resolve_index(-1, 3), 2);
+    assert_eq!(resolve_index(-3, 3), 0);
+}
+
+#[test]
+fn test_parse_texture_coordinates() {
+    use ray::Ray;
+
+    // The head is fully textured, so hits have to land inside the texture
+    let parsed = Parser::OBJ(String::from("obj-data/african_head.obj")).parse();
+    for i in 0..10 {
+        let origin = Vec3::new(i as f32 * 0.1 - 0.5, 0.2, 5.0);
+        let ray = Ray::new(origin, Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = parsed.intersect(&ray, 0.001, 100.0).unwrap();
+        assert!(hit.u >= 0.0 && hit.u <= 1.0);
+        assert!(hit.v >= 0.0 && hit.v <= 1.0);
+        assert!(hit.u != hit.barycentric.0 || hit.v != hit.barycentric.1);
+    }
+}
+
+#[test]
+fn test_parse_materials() {
+    use png::{BitDepth, ColorType};
+    use ray::Ray;
+    use std::env;
+    use std::fs;
+    use texture::encode_png;
+
+    // A textured and a plain quad next to each other. The texture is
+    // white on the left and black on the right
+    let dir = env::temp_dir().join(format!("pathtracer-materials-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let image = encode_png(
+        2,
+        1,
+        ColorType::RGB,
+        BitDepth::Eight,
+        &[255, 255, 255, 0, 0, 0],
+    );
+    fs::write(dir.join("stripes.png"), image).unwrap();
+    fs::write(
+        dir.join("quads.mtl"),
+        "newmtl textured\nKd 0.5 0.5 0.5\nmap_Kd -clamp on stripes.png\n\
+         newmtl blue\nKd 0.0 0.0 1.0\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("quads.obj"),
+        "mtllib quads.mtl\n\
+         v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 2 0 0\nv 2 1 0\n\
+         vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+         usemtl textured\nf 1/1 2/2 3/3 4/4\n\
+         usemtl blue\nf 2 5 6 3\n",
+    )
+    .unwrap();
+
+    let parser = Parser::OBJ(dir.join("quads.obj").to_str().unwrap().to_string());
+    let parsed = parser.parse();
+    let files = parser.files();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        files,
+        vec![
+            dir.join("quads.obj"),
+            dir.join("quads.mtl"),
+            dir.join("stripes.png")
+        ]
+    );
+    assert_eq!(parsed.len(), 4);
+
+    let color_at = |x: f32| {
+        let ray = Ray::new(Vec3::new(x, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = parsed.intersect(&ray, 0.001, 10.0).unwrap();
+        hit.material.color(&hit)
+    };
+    assert_eq!(color_at(0.1), Vec3::new(1.0, 1.0, 1.0));
+    assert_eq!(color_at(0.9), Vec3::new(0.0, 0.0, 0.0));
+    assert_eq!(color_at(1.5), Vec3::new(0.0, 0.0, 1.0));
+}
+
+#[test]
+fn test_parse_wrap_modes() {
+    use png::{BitDepth, ColorType};
+    use ray::Ray;
+    use std::env;
+    use std::fs;
+    use texture::encode_png;
+
+    // Three quads on top of each other that show the same white and black
+    // texture with a different wrap mode, from u = -0.5 to u = 2
+    let dir = env::temp_dir().join(format!("pathtracer-wrap-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let image = encode_png(
+        2,
+        1,
+        ColorType::RGB,
+        BitDepth::Eight,
+        &[255, 255, 255, 0, 0, 0],
+    );
+    fs::write(dir.join("stripes.png"), image).unwrap();
+    fs::write(
+        dir.join("wrap.mtl"),
+        "newmtl repeat\nmap_Kd stripes.png\n\
+         newmtl clamp\nmap_Kd -clamp on stripes.png\n\
+         newmtl mirror\nmap_Kd -mirror on stripes.png\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("wrap.obj"),
+        "mtllib wrap.mtl\n\
+         v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 1 2 0\nv 0 2 0\nv 1 3 0\nv 0 3 0\n\
+         vt -0.5 0\nvt 2 0\nvt 2 1\nvt -0.5 1\n\
+         usemtl repeat\nf 1/1 2/2 3/3 4/4\n\
+         usemtl clamp\nf 4/1 3/2 5/3 6/4\n\
+         usemtl mirror\nf 6/1 5/2 7/3 8/4\n",
+    )
+    .unwrap();
+
+    let parsed = Parser::OBJ(dir.join("wrap.obj").to_str().unwrap().to_string()).parse();
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Whether the texture is bright at u = -0.25 and u = 1.75
+    let bright_at = |x: f32, y: f32| {
+        let ray = Ray::new(Vec3::new(x, y, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = parsed.intersect(&ray, 0.001, 10.0).unwrap();
+        hit.material.color(&hit).x() > 0.5
+    };
+    let modes = |y: f32| (bright_at(0.1, y), bright_at(0.9, y));
+    assert_eq!(modes(0.5), (false, false));
+    assert_eq!(modes(1.5), (true, false));
+    assert_eq!(modes(2.5), (true, true));
+}
+
+#[test]
+fn test_parse_degenerate_faces() {
+    use std::env;
+    use std::fs;
+
+    // Faces without enough corners to make a triangle are left out
+    let path = env::temp_dir().join(format!("pathtracer-faces-{}.obj", std::process::id()));
+    fs::write(
+        &path,
+        "v 0 0 0\nv 1 0 0\nv 0 1 0\nf \nf 1\nf 1 2\nf 1 2 3\n",
+    )
+    .unwrap();
+    let parsed = Parser::OBJ(path.to_str().unwrap().to_string()).parse();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(parsed.len(), 1);
+}
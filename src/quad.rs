@@ -0,0 +1,231 @@
+use std::f32;
+
+use aabb::AABB;
+use hitable::{area_to_solid_angle, HitRecord, Hitable};
+use material::Material;
+use ray::Ray;
+use sampler::Sampler;
+#[cfg(test)]
+use texture::Constant;
+use triangle;
+use vector::Vec3;
+
+/// A parallelogram spanned by two edges starting at a corner. The normal
+/// points along u x v, so the edges decide which side is the front
+pub struct Quad {
+    corner: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    // Projects a point in the plane onto the edges
+    w: Vec3,
+    material: Material,
+}
+
+impl Hitable for Quad {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let denom = self.normal.dot(&r.direction());
+        // The ray runs parallel to the plane
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = self.normal.dot(&(self.corner - r.origin())) / denom;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let p = r.point_at_t(t);
+        let planar = p - self.corner;
+        let alpha = self.w.dot(&planar.cross(&self.v));
+        let beta = self.w.dot(&self.u.cross(&planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        let mut record = HitRecord::new(&self.material);
+        record.t = t;
+        record.p = p;
+        record.normal = self.normal;
+        record.u = alpha;
+        record.v = beta;
+        Some(record)
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        // Covering both halves takes care of flat boxes for us
+        let (a, b, c, d) = self.corners();
+        Some(AABB::surrounding_box(
+            &triangle::bounding_box(a, b, c),
+            &triangle::bounding_box(b, d, c),
+        ))
+    }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sample_direction(&self, origin: Vec3, sampler: &mut Sampler) -> Option<Vec3> {
+        let point = self.corner + self.u * sampler.next_f32() + self.v * sampler.next_f32();
+        Some(point - origin)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3) -> f32 {
+        match self.hit(&Ray::new(origin, direction, 0.0), 0.001, f32::MAX) {
+            Some(rec) => {
+                let area = self.u.cross(&self.v).length();
+                area_to_solid_angle(area, direction, rec.t, self.normal)
+            }
+            None => 0.0,
+        }
+    }
+}
+
+impl Quad {
+    pub fn new(corner: Vec3, u: Vec3, v: Vec3, material: Material) -> Quad {
+        let n = u.cross(&v);
+        Quad {
+            corner,
+            u,
+            v,
+            normal: Vec3::unit_vec(n),
+            w: n / n.dot(&n),
+            material,
+        }
+    }
+
+    /// The corners in the order corner, corner + u, corner + v, corner + u + v
+    fn corners(&self) -> (Vec3, Vec3, Vec3, Vec3) {
+        let a = self.corner;
+        (a, a + self.u, a + self.v, a + self.u + self.v)
+    }
+}
+
+/// The six sides of an axis aligned box between two opposite corners,
+/// all facing outwards
+pub fn cuboid(a: Vec3, b: Vec3, material: &Material) -> Vec<Quad> {
+    let min = Vec3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z()));
+    let max = Vec3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z()));
+    let dx = Vec3::new(max.x() - min.x(), 0.0, 0.0);
+    let dy = Vec3::new(0.0, max.y() - min.y(), 0.0);
+    let dz = Vec3::new(0.0, 0.0, max.z() - min.z());
+
+    vec![
+        // Front and back
+        Quad::new(
+            Vec3::new(min.x(), min.y(), max.z()),
+            dx,
+            dy,
+            material.clone(),
+        ),
+        Quad::new(
+            Vec3::new(max.x(), min.y(), min.z()),
+            -dx,
+            dy,
+            material.clone(),
+        ),
+        // Right and left
+        Quad::new(
+            Vec3::new(max.x(), min.y(), max.z()),
+            -dz,
+            dy,
+            material.clone(),
+        ),
+        Quad::new(
+            Vec3::new(min.x(), min.y(), min.z()),
+            dz,
+            dy,
+            material.clone(),
+        ),
+        // Top and bottom
+        Quad::new(
+            Vec3::new(min.x(), max.y(), max.z()),
+            dx,
+            -dz,
+            material.clone(),
+        ),
+        Quad::new(
+            Vec3::new(min.x(), min.y(), min.z()),
+            dx,
+            dz,
+            material.clone(),
+        ),
+    ]
+}
+
+#[test]
+fn test_hit() {
+    let material = Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0)));
+    let quad = Quad::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(2.0, 0.0, 0.0),
+        Vec3::new(0.0, 4.0, 0.0),
+        material,
+    );
+
+    let ray = Ray::new(Vec3::new(0.5, 1.0, 3.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let hit = quad.hit(&ray, 0.001, 10.0).unwrap();
+    assert_eq!(hit.t, 3.0);
+    assert_eq!(hit.normal, Vec3::new(0.0, 0.0, 1.0));
+    assert_eq!((hit.u, hit.v), (0.25, 0.25));
+
+    // Misses next to it, behind the ray and parallel to it
+    let ray = Ray::new(Vec3::new(2.5, 1.0, 3.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    assert!(quad.hit(&ray, 0.001, 10.0).is_none());
+    let ray = Ray::new(Vec3::new(0.5, 1.0, 3.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+    assert!(quad.hit(&ray, 0.001, 10.0).is_none());
+    let ray = Ray::new(Vec3::new(0.5, 1.0, 3.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+    assert!(quad.hit(&ray, 0.001, 10.0).is_none());
+
+    // The box is padded since the quad is flat
+    let bb = quad.bounding_box().unwrap();
+    assert!(bb.max().z() > bb.min().z());
+    assert_eq!(bb.max().y(), 4.0);
+}
+
+#[test]
+fn test_cuboid() {
+    let material = Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0)));
+    let sides = cuboid(
+        Vec3::new(1.0, 1.0, 1.0),
+        Vec3::new(-1.0, -1.0, -1.0),
+        &material,
+    );
+    assert_eq!(sides.len(), 6);
+
+    // Every side faces away from the center
+    for side in &sides {
+        let center = side.corner + (side.u + side.v) * 0.5;
+        assert!(side.normal.dot(&center) > 0.0);
+        let ray = Ray::new(center * 2.0, -center, 0.0);
+        assert!(side.hit(&ray, 0.001, 10.0).is_some());
+    }
+}
+
+#[test]
+fn test_sampling() {
+    let quad = Quad::new(
+        Vec3::new(-0.5, -0.5, -10.0),
+        Vec3::new(1.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        Material::DiffuseLight(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 1.0),
+    );
+    assert!(quad.is_emitter());
+
+    let origin = Vec3::new(0.0, 0.0, 0.0);
+    let mut sampler = Sampler::new(3, 0);
+    let mut solid_angle = 0.0;
+    for _ in 0..1000 {
+        let direction = quad.sample_direction(origin, &mut sampler).unwrap();
+        assert!(quad
+            .hit(&Ray::new(origin, direction, 0.0), 0.001, 10.0)
+            .is_some());
+        solid_angle += 1.0 / quad.pdf_value(origin, direction);
+    }
+    assert!((solid_angle / 1000.0 - 0.01).abs() < 0.0002);
+    assert_eq!(quad.pdf_value(origin, Vec3::new(0.0, 0.0, 1.0)), 0.0);
+}
@@ -1,113 +1,416 @@
 extern crate indicatif;
-extern crate rand;
 extern crate rayon;
 
-use std::f32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
+#[cfg(test)]
+use aov::Aov;
+use aov::{AovPixel, AovSample};
 use camera::Camera;
-use hitable::HitRecord;
+use film::{Estimate, Film, FilmTile};
+use filter::Filter;
+#[cfg(test)]
+use filter::Shape;
+#[cfg(test)]
 use hitable_list::HitableList;
+use integrator::{Integrator, NaivePath};
+#[cfg(test)]
 use material::Material;
-use ray::Ray;
+use sampler::Sampler;
+#[cfg(test)]
+use scene::Background;
+use scene::{self, Scene};
+#[cfg(test)]
 use sphere::Sphere;
+#[cfg(test)]
+use texture::Constant;
+use tile::{self, Tile, TileOrder};
 use vector::Vec3;
 
 use self::indicatif::{ProgressBar, ProgressStyle};
 use renderer::rayon::prelude::*;
 
+/// How many samples every pixel gets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    /// The same number of samples for every pixel
+    Fixed(u32),
+    /// Keeps sampling a pixel until the estimated relative error of its
+    /// mean drops below the threshold, within the given bounds
+    Adaptive {
+        min_samples: u32,
+        max_samples: u32,
+        threshold: f32,
+    },
+}
+
+impl Sampling {
+    pub fn max_samples(&self) -> u32 {
+        match *self {
+            Sampling::Fixed(samples) => samples,
+            Sampling::Adaptive { max_samples, .. } => max_samples,
+        }
+    }
+}
+
+/// Limits for an open ended render. Once there is a budget, the samples
+/// and passes only decide how many samples each pass adds, and passes are
+/// added until the first limit is reached
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Budget {
+    /// Stops before a pass that is not expected to finish in time
+    pub time: Option<Duration>,
+    /// Stops once the pixels average at least this many samples
+    pub spp: Option<u32>,
+}
+
+impl Budget {
+    fn is_open(&self) -> bool {
+        self.time.is_some() || self.spp.is_some()
+    }
+}
+
+/// A render that can be picked up again. The seed determines the random
+/// numbers of every sample, so a render that is picked up again takes the
+/// same samples it would have taken without the break
+pub struct Progress {
+    pub film: Film,
+    pub seed: u64,
+    pub passes: u32,
+}
+
+impl Progress {
+    /// A render that hasn't started yet
+    pub fn new(dim_x: u32, dim_y: u32, seed: u64) -> Progress {
+        Progress {
+            film: Film::new(dim_x, dim_y),
+            seed,
+            passes: 0,
+        }
+    }
+}
+
+/// What all tiles of a pass share
+struct Pass<'a> {
+    /// The film as it was before the pass
+    film: &'a Film,
+    scene: &'a Scene<'a>,
+    /// How many samples every pixel has at the end of the pass
+    target: u32,
+    seed: u64,
+}
+
 pub struct Renderer {
     camera: Camera,
+    integrator: Box<dyn Integrator>,
+    sampling: Sampling,
+    passes: u32,
+    tile_size: u32,
+    tile_order: TileOrder,
+    budget: Budget,
+    filter: Filter,
+    alpha: bool,
+    aovs: bool,
+    rays: AtomicU64,
 }
 
 impl Renderer {
     pub fn new(camera: Camera) -> Renderer {
-        Renderer { camera }
+        Renderer {
+            camera,
+            integrator: Box::new(NaivePath::default()),
+            sampling: Sampling::Fixed(16),
+            passes: 1,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            budget: Budget::default(),
+            filter: Filter::default(),
+            alpha: false,
+            aovs: false,
+            rays: AtomicU64::new(0),
+        }
     }
 
-    pub fn render(&self, dim_x: u32, dim_y: u32, world: &HitableList) -> Vec<u8> {
-        // Options pertaining to the actual path tracing
-        let depth: u32 = 0;
-        let num_samples: u16 = 16;
-        let progress_bar = &Box::new(ProgressBar::new(dim_x as u64 * dim_y as u64));
-        progress_bar.set_message("Rendered Pixels");
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+    }
+
+    /// How samples are spread over the pixels around them. The AOVs are
+    /// never filtered, since IDs can't be blended
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Makes camera rays that miss all geometry transparent instead of
+    /// showing the background. The film then holds colors premultiplied
+    /// by the coverage
+    pub fn set_alpha(&mut self, alpha: bool) {
+        self.alpha = alpha;
+    }
+
+    /// Captures the AOVs of every pixel next to its color
+    pub fn set_aovs(&mut self, aovs: bool) {
+        self.aovs = aovs;
+    }
+
+    /// How many rays this renderer has traced into scenes so far
+    pub fn traced_rays(&self) -> u64 {
+        self.rays.load(Ordering::Relaxed)
+    }
+
+    /// Splits the samples into this many passes over the whole image
+    pub fn set_passes(&mut self, passes: u32) {
+        self.passes = passes.max(1);
+    }
+
+    pub fn set_tiles(&mut self, size: u32, order: TileOrder) {
+        self.tile_size = size.max(1);
+        self.tile_order = order;
+    }
+
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+    }
+
+    pub fn set_integrator(&mut self, integrator: Box<dyn Integrator>) {
+        self.integrator = integrator;
+    }
+
+    /// Renders the scene into the progress's floating point film, in passes
+    /// that each add more samples to every pixel. Only the passes that are
+    /// still missing are rendered, and raising the samples or passes of a
+    /// finished render keeps adding to it. With a budget, passes are added
+    /// until it is used up. The callback gets to look at the progress after
+    /// every pass
+    pub fn render<F>(&self, mut progress: Progress, scene: &Scene, mut on_pass: F) -> Progress
+    where
+        F: FnMut(&Progress),
+    {
+        let (dim_x, dim_y) = (progress.film.width(), progress.film.height());
+        if self.aovs {
+            progress.film.enable_aovs();
+        }
+        let tiles = tile::tiles(dim_x, dim_y, self.tile_size, self.tile_order);
+        let remaining = if self.budget.is_open() {
+            1
+        } else {
+            self.passes.saturating_sub(progress.passes)
+        };
+        let progress_bar = &Box::new(ProgressBar::new(tiles.len() as u64 * remaining as u64));
+        progress_bar.set_message("Rendered Tiles");
         progress_bar.set_style(
             ProgressStyle::default_bar()
                 .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
                 .progress_chars("##-"),
         );
 
-        let pixels = (0..dim_y)
-            .into_par_iter()
-            .rev()
-            .flat_map(|y| {
-                (0..dim_x).into_par_iter().flat_map(move |x| {
-                    let mut col = Vec3::new(0.0, 0.0, 0.0);
+        let start = Instant::now();
+        let first_pass = progress.passes;
+        let mut pass_time = Duration::from_secs(0);
+        for pass in first_pass.. {
+            if !self.budget.is_open() && pass >= self.passes {
+                break;
+            }
+            if let Some(spp) = self.budget.spp {
+                if progress.film.average_samples() >= spp as f64 {
+                    break;
+                }
+            }
+            // Always render at least one pass, so there is something to show
+            if let Some(time) = self.budget.time {
+                if pass > first_pass && start.elapsed() + pass_time > time {
+                    break;
+                }
+            }
+            if self.budget.is_open() && pass > first_pass {
+                progress_bar.set_length(tiles.len() as u64 * (pass - first_pass + 1) as u64);
+            }
+
+            let pass_start = Instant::now();
+            let samples_before = progress.film.total_samples();
+            // The samples are spread evenly over the passes
+            let target = (self.sampling.max_samples() as u64 * (pass as u64 + 1)
+                / self.passes as u64) as u32;
 
-                    // Sample a set number of times to determine color
-                    for _ in 0..num_samples {
-                        let u = (x as f32 + rand::random::<f32>()) / (dim_x as f32);
-                        let v = (y as f32 + rand::random::<f32>()) / (dim_y as f32);
+            let current = Pass {
+                film: &progress.film,
+                scene,
+                target,
+                seed: progress.seed,
+            };
+            let rendered: Vec<(FilmTile, Vec<(Estimate, AovPixel)>)> = tiles
+                .par_iter()
+                .map(|tile| {
+                    let pixels = self.render_tile(tile, &current);
+                    progress_bar.inc(1);
+                    pixels
+                })
+                .collect();
 
-                        let ray = &self.camera.get_ray(u, v);
-                        col = col + self.color(&ray, world, depth);
+            // Tiles overlap through their margins, so they are added in the
+            // order they were handed out rather than the one they finished in
+            for (tile, (splats, pixels)) in tiles.iter().zip(rendered) {
+                let mut pixels = pixels.into_iter();
+                for y in tile.y0..tile.y1 {
+                    for x in tile.x0..tile.x1 {
+                        let (estimate, aov) = pixels.next().unwrap();
+                        progress.film.pixel_mut(x, y).estimate = estimate;
+                        if self.aovs {
+                            *progress.film.aov_pixel_mut(x, y) = aov;
+                        }
                     }
+                }
+                progress.film.add_tile(&splats);
+            }
+            progress.passes = pass + 1;
+            on_pass(&progress);
+            pass_time = pass_start.elapsed();
 
-                    // Apply antialising by taking average of samples
-                    col = col / (num_samples as f32);
+            // Adaptive sampling may have nothing left to do
+            if progress.film.total_samples() == samples_before {
+                break;
+            }
+        }
+        progress_bar.finish();
 
-                    // Apply some gamme correction
-                    col = Vec3::new(col.x().sqrt(), col.y().sqrt(), col.z().sqrt());
+        progress
+    }
 
-                    progress_bar.inc(1);
-                    (0..3)
-                        .into_par_iter()
-                        .map(move |k| (255.99 * col[k as usize]).min(255.0) as u8)
-                })
-            })
-            .collect();
-        progress_bar.finish();
+    /// Takes the pixels of a tile further, row by row, until they have
+    /// `target` samples or are converged. Returns the new samples splatted
+    /// over the tile and its margin, along with the updated estimates and
+    /// AOVs of the tile's own pixels
+    fn render_tile(&self, tile: &Tile, pass: &Pass) -> (FilmTile, Vec<(Estimate, AovPixel)>) {
+        let film = pass.film;
+        let rays = scene::traced_rays();
+        let mut splats = FilmTile::new(tile, self.filter, film.width(), film.height());
+        let mut pixels = Vec::with_capacity(((tile.x1 - tile.x0) * (tile.y1 - tile.y0)) as usize);
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let mut estimate = film.pixel(x, y).estimate;
+                let mut aov = if self.aovs {
+                    *film.aov_pixel(x, y)
+                } else {
+                    AovPixel::default()
+                };
+                self.render_pixel(pass, x, y, &mut estimate, &mut splats, &mut aov);
+                pixels.push((estimate, aov));
+            }
+        }
+        // A tile is rendered on a single thread from start to end
+        self.rays
+            .fetch_add(scene::traced_rays() - rays, Ordering::Relaxed);
+        (splats, pixels)
+    }
+
+    /// Takes samples for a single pixel, splats them around it and adds
+    /// them to its estimate and AOVs. Rows are counted from the top
+    fn render_pixel(
+        &self,
+        pass: &Pass,
+        x: u32,
+        y: u32,
+        estimate: &mut Estimate,
+        splats: &mut FilmTile,
+        aov: &mut AovPixel,
+    ) {
+        let (target, seed, scene) = (pass.target, pass.seed, pass.scene);
+        let (min_samples, threshold) = match self.sampling {
+            Sampling::Fixed(_) => (target, 0.0),
+            Sampling::Adaptive {
+                min_samples,
+                threshold,
+                ..
+            } => (min_samples, threshold),
+        };
+        let (dim_x, dim_y) = (pass.film.width(), pass.film.height());
 
-        pixels
-    }
-
-    // Computes the next ray based on the material that the Hitable object possesses
-    // We could theoretically move this back into the color() function. I might
-    // go ahead and do that later
-    fn compute_scatter_ray(&self, intersected: &HitRecord, r: &Ray) -> Option<Ray> {
-        intersected.material.scatter(r, &intersected)
-    }
-
-    /*
-     * This is the function that currently does the most work for my path tracer.
-     * The function calculates a color value by taking the initial ray passed in
-     * through the main function, determines if any intersections have been made
-     * with any of the geometry in the scene and then calculating a scattered
-     * ray based on the type of material given. This process is recursively
-     * executed until we have reached a finite number of bounces or we are
-     * unable to intersect anymore geometry.
-     */
-    fn color(&self, r: &Ray, world: &HitableList, depth: u32) -> Vec3 {
-        let hit_object = world.intersect(r, 0.001, f32::MAX);
-        if hit_object.is_some() {
-            // Retrieve intersected object properties
-            let obj = hit_object.unwrap();
-            // Compute where the next ray is going to bounce
-            let scattered = self.compute_scatter_ray(&obj, r);
-            // TODO Make the depth parameter adjustable
-            if depth < 50 && scattered.is_some() {
-                return self.color(&scattered.unwrap(), world, depth + 1) * obj.material.color();
+        let index = y as u64 * dim_x as u64 + x as u64;
+        while estimate.count() < target {
+            if estimate.count() >= min_samples && estimate.relative_error() < threshold {
+                break;
+            }
+
+            // Every sample of every pixel gets its own random numbers
+            let mut sampler = Sampler::for_sample(seed, index, estimate.count());
+
+            // The camera counts rows from the bottom
+            let film_x = x as f32 + sampler.next_f32();
+            let film_y = y as f32 + sampler.next_f32();
+            let u = film_x / dim_x as f32;
+            let v = (dim_y as f32 - film_y) / dim_y as f32;
+
+            let ray = self.camera.get_ray(u, v, &mut sampler);
+            let mut sample = AovSample::default();
+            let radiance = if self.aovs || self.alpha {
+                self.integrator
+                    .trace(&ray, scene, &mut sampler, &mut sample);
+                // Nothing of the background shows up with alpha, not even
+                // in the AOVs
+                if self.alpha && !sample.hit {
+                    sample = AovSample::default();
+                }
+                sample.radiance()
             } else {
-                // If we do not intercept anymore geometry we are finished
-                return Vec3::new(0.0, 0.0, 0.0);
+                self.integrator.li(&ray, scene, &mut sampler)
+            };
+            let coverage = if self.alpha && !sample.hit { 0.0 } else { 1.0 };
+            splats.splat(film_x, film_y, radiance, coverage);
+            estimate.add(radiance.luminance());
+            if self.aovs {
+                aov.add(&sample);
             }
         }
-
-        let unit_direction = Vec3::unit_vec(r.direction());
-        let t: f32 = (unit_direction.y() + 1.0) * 0.5;
-        Vec3::new(1.0, 1.0, 1.0) * (1.0 - t) + t * Vec3::new(0.5, 0.7, 1.0)
     }
 }
 
+/// Colors sample counts from blue for the fewest up to red for `max_samples`,
+/// as 8-bit RGB pixels
+pub fn heatmap(counts: &[u32], max_samples: u32) -> Vec<u8> {
+    counts
+        .iter()
+        .flat_map(|&count| {
+            let t = if max_samples == 0 {
+                0.0
+            } else {
+                (count as f32 / max_samples as f32).min(1.0)
+            };
+            // Blue to green to red
+            let col = if t < 0.5 {
+                Vec3::new(0.0, 2.0 * t, 1.0 - 2.0 * t)
+            } else {
+                Vec3::new(2.0 * t - 1.0, 2.0 - 2.0 * t, 0.0)
+            };
+            (0..3).map(move |k| (255.99 * col[k]).min(255.0) as u8)
+        })
+        .collect()
+}
+
+/// A gray sphere two units in front of a camera at the origin with a 90
+/// degree field of view. The sphere is in focus, however large the aperture
+#[cfg(test)]
+pub fn sphere_in_view(aperture: f32) -> (Camera, HitableList) {
+    let cam = Camera::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -1.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        90.0,
+        1.0,
+        aperture,
+        2.0,
+        0.0,
+        1.0,
+    );
+    let mut world = HitableList::new();
+    world.push(Box::new(Sphere::new(
+        Vec3::new(0.0, 0.0, -2.0),
+        1.0,
+        Material::Lambertian(Constant::shared(Vec3::new(0.5, 0.5, 0.5))),
+    )));
+    (cam, world)
+}
+
 #[test]
 fn test_hit() {
     // Camera setup
@@ -133,11 +436,343 @@ fn test_hit() {
     world.push(Box::new(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
         1000.0,
-        Material::Lambertian(Vec3::new(0.5, 0.4, 0.5)),
+        Material::Lambertian(Constant::shared(Vec3::new(0.5, 0.4, 0.5))),
     )));
 
     // See if the renderer runs
     let renderer = Renderer::new(cam);
-    let pixels = renderer.render(width, height, &world);
-    assert!(!pixels.is_empty());
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+    let pixels = renderer
+        .render(Progress::new(width, height, 1), &scene, |_| {})
+        .film
+        .colors();
+    assert_eq!(pixels.len(), (width * height) as usize);
+}
+
+#[test]
+fn test_emission() {
+    use quad::Quad;
+
+    // A camera facing a large light sees nothing but the light
+    let (cam, _) = sphere_in_view(0.0);
+    let mut world = HitableList::new();
+    let light = Material::DiffuseLight(Constant::shared(Vec3::new(0.25, 0.25, 0.25)), 1.0);
+    world.push(Box::new(Quad::new(
+        Vec3::new(-10.0, -10.0, -1.0),
+        Vec3::new(20.0, 0.0, 0.0),
+        Vec3::new(0.0, 20.0, 0.0),
+        light,
+    )));
+    let scene = Scene::new(
+        &world,
+        world.lights(),
+        Background::Constant(Vec3::new(0.0, 0.0, 0.0)),
+    );
+
+    let mut renderer = Renderer::new(cam);
+    let film = renderer.render(Progress::new(4, 4, 1), &scene, |_| {}).film;
+    assert!(film
+        .colors()
+        .iter()
+        .all(|p| *p == Vec3::new(0.25, 0.25, 0.25)));
+    assert!(film.alphas().iter().all(|a| *a == 1.0));
+
+    // Every pixel sees the same light through its AOVs
+    renderer.set_aovs(true);
+    let mut scene = scene;
+    scene.number_objects(&world.objs);
+    let film = renderer.render(Progress::new(4, 4, 1), &scene, |_| {}).film;
+    for aov in [Aov::Direct, Aov::ObjectId, Aov::Normal].iter() {
+        let expected = match aov {
+            Aov::Direct => Vec3::new(0.25, 0.25, 0.25),
+            Aov::ObjectId => Vec3::new(1.0, 1.0, 1.0),
+            _ => Vec3::new(0.0, 0.0, 1.0),
+        };
+        assert!(film.aov_values(*aov).iter().all(|v| *v == expected));
+    }
+    renderer.set_aovs(false);
+
+    // The light covers every pixel. Finding that out takes no extra rays,
+    // so every render so far traced one per sample
+    renderer.set_alpha(true);
+    let film = renderer.render(Progress::new(4, 4, 1), &scene, |_| {}).film;
+    assert!(film.alphas().iter().all(|a| *a == 1.0));
+    assert_eq!(renderer.traced_rays(), 3 * 4 * 4 * 16);
+
+    // Looking away from the light only shows the black background
+    let cam = Camera::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 1.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        90.0,
+        1.0,
+        0.0,
+        1.0,
+        0.0,
+        1.0,
+    );
+    let mut renderer = Renderer::new(cam);
+    let film = renderer.render(Progress::new(4, 4, 1), &scene, |_| {}).film;
+    assert!(film.colors().iter().all(|p| *p == Vec3::new(0.0, 0.0, 0.0)));
+
+    // Only background is transparent. Without a light, the sky is all there
+    // is to see, but none of it shows up
+    renderer.set_alpha(true);
+    let film = renderer.render(Progress::new(4, 4, 1), &scene, |_| {}).film;
+    assert!(film.alphas().iter().all(|a| *a == 0.0));
+    let sky = Scene::new(&world, world.lights(), Background::Sky);
+    let film = renderer.render(Progress::new(4, 4, 1), &sky, |_| {}).film;
+    assert!(film.colors().iter().all(|p| *p == Vec3::new(0.0, 0.0, 0.0)));
+    assert!(film.alphas().iter().all(|a| *a == 0.0));
+}
+
+#[test]
+fn test_sampling() {
+    use quad::Quad;
+
+    let (cam, _) = sphere_in_view(0.0);
+    // The left half of the view is a light, the right half a noisy diffuse
+    // wall lit by the background
+    let mut world = HitableList::new();
+    world.push(Box::new(Quad::new(
+        Vec3::new(-10.0, -10.0, -1.0),
+        Vec3::new(10.0, 0.0, 0.0),
+        Vec3::new(0.0, 20.0, 0.0),
+        Material::DiffuseLight(Constant::shared(Vec3::new(0.25, 0.25, 0.25)), 1.0),
+    )));
+    world.push(Box::new(Quad::new(
+        Vec3::new(0.0, -10.0, -1.0),
+        Vec3::new(10.0, 0.0, 0.0),
+        Vec3::new(0.0, 20.0, 0.0),
+        Material::Metal(Constant::shared(Vec3::new(0.8, 0.8, 0.8)), 1.0),
+    )));
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+
+    let mut renderer = Renderer::new(cam);
+    renderer.set_sampling(Sampling::Fixed(5));
+    let film = renderer.render(Progress::new(4, 4, 1), &scene, |_| {}).film;
+    let counts = film.counts();
+    assert_eq!(film.colors().len(), 4 * 4);
+    assert!(counts.iter().all(|c| *c == 5));
+
+    // The light converges right away while the wall keeps sampling
+    renderer.set_sampling(Sampling::Adaptive {
+        min_samples: 4,
+        max_samples: 64,
+        threshold: 0.001,
+    });
+    let counts = renderer
+        .render(Progress::new(4, 4, 1), &scene, |_| {})
+        .film
+        .counts();
+    for row in counts.chunks(4) {
+        assert_eq!(row[0], 4);
+        assert_eq!(row[3], 64);
+    }
+
+    let colors = heatmap(&[0, 32, 64], 64);
+    assert_eq!(&colors[0..3], &[0, 0, 255]);
+    assert_eq!(&colors[3..6], &[0, 255, 0]);
+    assert_eq!(&colors[6..9], &[255, 0, 0]);
+}
+
+#[test]
+fn test_progressive() {
+    let (cam, world) = sphere_in_view(0.0);
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+
+    // Tiles that don't divide the image still cover all of it, and every
+    // pass adds its share of samples
+    let mut renderer = Renderer::new(cam);
+    renderer.set_sampling(Sampling::Fixed(10));
+    renderer.set_passes(4);
+    renderer.set_tiles(3, TileOrder::Hilbert);
+    let mut passes = Vec::new();
+    let film = renderer
+        .render(Progress::new(7, 5, 1), &scene, |progress| {
+            let counts = progress.film.counts();
+            assert!(counts.iter().all(|c| *c == counts[0]));
+            passes.push((progress.passes, counts[0]));
+        })
+        .film;
+    assert_eq!(passes, vec![(1, 2), (2, 5), (3, 7), (4, 10)]);
+    assert!(film.counts().iter().all(|c| *c == 10));
+
+    // Rows are stored top to bottom, and the sky is bluer towards the top
+    let top = film.pixel(0, 0).color();
+    let bottom = film.pixel(0, 4).color();
+    assert!(top.x() < bottom.x());
+}
+
+#[test]
+fn test_resume() {
+    let (cam, world) = sphere_in_view(0.0);
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+
+    // A render that stopped after two passes
+    let mut renderer = Renderer::new(cam);
+    renderer.set_sampling(Sampling::Fixed(8));
+    renderer.set_passes(2);
+    let interrupted = renderer.render(Progress::new(4, 4, 1), &scene, |_| {});
+    assert_eq!(interrupted.passes, 2);
+    assert!(interrupted.film.counts().iter().all(|c| *c == 8));
+
+    // Picking it up with a larger budget adds the remaining passes
+    renderer.set_sampling(Sampling::Fixed(16));
+    renderer.set_passes(4);
+    let mut resumed_passes = Vec::new();
+    let resumed = renderer.render(interrupted, &scene, |progress| {
+        resumed_passes.push(progress.passes)
+    });
+    assert_eq!(resumed_passes, vec![3, 4]);
+    assert!(resumed.film.counts().iter().all(|c| *c == 16));
+
+    // A finished render has nothing left to do
+    let finished = renderer.render(resumed, &scene, |_| panic!("no pass is left"));
+    assert_eq!(finished.passes, 4);
+}
+
+#[test]
+fn test_budget() {
+    let (cam, world) = sphere_in_view(0.0);
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+
+    // Two samples per pass until the pixels average seven
+    let mut renderer = Renderer::new(cam);
+    renderer.set_sampling(Sampling::Fixed(2));
+    renderer.set_budget(Budget {
+        time: None,
+        spp: Some(7),
+    });
+    let progress = renderer.render(Progress::new(4, 4, 1), &scene, |_| {});
+    assert_eq!(progress.passes, 4);
+    assert!(progress.film.counts().iter().all(|c| *c == 8));
+    // At least one ray per sample
+    assert!(renderer.traced_rays() >= 4 * 4 * 8);
+
+    // No time at all still gives a single pass
+    renderer.set_budget(Budget {
+        time: Some(Duration::from_secs(0)),
+        spp: None,
+    });
+    let progress = renderer.render(Progress::new(4, 4, 1), &scene, |_| {});
+    assert_eq!(progress.passes, 1);
+
+    // Converged adaptive renders stop on their own
+    renderer.set_sampling(Sampling::Adaptive {
+        min_samples: 4,
+        max_samples: 4,
+        threshold: 1.0,
+    });
+    renderer.set_budget(Budget {
+        time: None,
+        spp: Some(1000),
+    });
+    let progress = renderer.render(Progress::new(4, 4, 1), &scene, |_| {});
+    assert!(progress.passes < 1000 / 4);
+}
+
+#[test]
+fn test_filters() {
+    use quad::Quad;
+
+    let (cam, _) = sphere_in_view(0.0);
+    let shapes = [
+        Shape::Box,
+        Shape::Tent,
+        Shape::Gaussian,
+        Shape::Mitchell,
+        Shape::Lanczos,
+    ];
+
+    // Every filter is normalized by its weights, so a uniform light stays
+    // uniform, right up to the edges of the image
+    let mut world = HitableList::new();
+    world.push(Box::new(Quad::new(
+        Vec3::new(-10.0, -10.0, -1.0),
+        Vec3::new(20.0, 0.0, 0.0),
+        Vec3::new(0.0, 20.0, 0.0),
+        Material::DiffuseLight(Constant::shared(Vec3::new(0.25, 0.25, 0.25)), 1.0),
+    )));
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+    let mut renderer = Renderer::new(cam);
+    renderer.set_sampling(Sampling::Fixed(4));
+    renderer.set_tiles(3, TileOrder::Spiral);
+    for shape in shapes.iter() {
+        renderer.set_filter(Filter::new(*shape, shape.default_radius()));
+        let film = renderer.render(Progress::new(7, 5, 1), &scene, |_| {}).film;
+        assert!(film.counts().iter().all(|c| *c == 4));
+        for color in film.colors() {
+            assert!((color - Vec3::new(0.25, 0.25, 0.25)).length() < 1e-5);
+        }
+    }
+
+    // Tiles share the pixels along their edges and finish in any order,
+    // but are added up in the order they were handed out
+    let empty = HitableList::new();
+    let sky = Scene::new(&empty, empty.lights(), Background::Sky);
+    renderer.set_filter(Filter::new(Shape::Mitchell, 2.0));
+    let render = || {
+        renderer
+            .render(Progress::new(16, 16, 5), &sky, |_| {})
+            .film
+            .colors()
+    };
+    let reference = render();
+    for _ in 0..4 {
+        assert_eq!(render(), reference);
+    }
+}
+
+#[test]
+fn test_seed() {
+    use integrator::NeePath;
+    use quad::Quad;
+    use sphere::MovingSphere;
+
+    // Depth of field, motion blur, every material and a light to sample
+    let (cam, mut world) = sphere_in_view(0.2);
+    world.push(Box::new(Sphere::new(
+        Vec3::new(-1.6, 0.0, -2.0),
+        0.5,
+        Material::Metal(Constant::shared(Vec3::new(0.8, 0.8, 0.8)), 0.3),
+    )));
+    world.push(Box::new(MovingSphere::new(
+        Vec3::new(1.6, 0.0, -2.0),
+        Vec3::new(1.6, 0.3, -2.0),
+        0.0,
+        1.0,
+        0.5,
+        Material::Dielectric(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 1.5),
+    )));
+    world.push(Box::new(Quad::new(
+        Vec3::new(-1.0, 2.0, -3.0),
+        Vec3::new(2.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 2.0),
+        Material::DiffuseLight(Constant::shared(Vec3::new(4.0, 4.0, 4.0)), 1.0),
+    )));
+    let scene = Scene::new(&world, world.lights(), Background::Sky);
+
+    let mut renderer = Renderer::new(cam);
+    renderer.set_integrator(Box::new(NeePath::default()));
+    renderer.set_sampling(Sampling::Fixed(8));
+    renderer.set_tiles(4, TileOrder::Spiral);
+    let render = |renderer: &Renderer, seed| {
+        renderer
+            .render(Progress::new(12, 12, seed), &scene, |_| {})
+            .film
+            .colors()
+    };
+
+    // The same seed gives the same image, another seed a different one
+    let reference = render(&renderer, 3);
+    assert_eq!(render(&renderer, 3), reference);
+    assert!(render(&renderer, 4) != reference);
+
+    // Samples don't depend on the pass they are taken in. Only the order
+    // in which they are summed up changes
+    renderer.set_passes(2);
+    for (a, b) in render(&renderer, 3).iter().zip(reference.iter()) {
+        assert!((*a - *b).length() <= 1e-5 * (1.0 + b.length()));
+    }
 }
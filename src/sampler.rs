@@ -0,0 +1,122 @@
+use std::f32;
+
+use vector::Vec3;
+
+/// The source of random numbers for the integrators. It is a PCG32
+/// generator, which is small, fast and has a state we fully control, so
+/// the same seed always gives the same sequence
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sampler {
+    state: u64,
+    increment: u64,
+}
+
+const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+impl Sampler {
+    /// Generators with the same seed but different streams produce
+    /// independent sequences
+    pub fn new(seed: u64, stream: u64) -> Sampler {
+        let mut sampler = Sampler {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        sampler.next_u32();
+        sampler.state = sampler.state.wrapping_add(seed);
+        sampler.next_u32();
+        sampler
+    }
+
+    /// The generator for one sample of a pixel. Every sample has its own
+    /// sequence that only depends on the seed, so it doesn't matter which
+    /// thread takes it or in which pass
+    pub fn for_sample(seed: u64, pixel: u64, sample: u32) -> Sampler {
+        // Jumps straight to the sample's place in a splitmix64 sequence
+        let mut state = seed.wrapping_add((sample as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        Sampler::new(splitmix64(&mut state), pixel)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(MULTIPLIER).wrapping_add(self.increment);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// A uniformly distributed number in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        // 24 bits is all the precision a float has below one
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+
+    /// A uniformly distributed index below the bound
+    pub fn next_index(&mut self, bound: usize) -> usize {
+        ((self.next_u32() as u64 * bound as u64) >> 32) as usize
+    }
+}
+
+/// A small and fast generator. Good for shuffling tables and for turning
+/// one seed into many unrelated ones
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Maps two uniform numbers to a direction around the normal. Directions
+/// close to the normal are more likely, proportional to the cosine
+pub fn cosine_direction(normal: Vec3, r1: f32, r2: f32) -> Vec3 {
+    let phi = 2.0 * f32::consts::PI * r1;
+    let (a, b) = normal.orthonormal_basis();
+    a * (phi.cos() * r2.sqrt()) + b * (phi.sin() * r2.sqrt()) + normal * (1.0 - r2).sqrt()
+}
+
+#[test]
+fn test_sampler() {
+    let mut a = Sampler::new(42, 0);
+    let mut b = Sampler::new(42, 0);
+    let mut c = Sampler::new(42, 1);
+    let first: Vec<u32> = (0..8).map(|_| a.next_u32()).collect();
+    let second: Vec<u32> = (0..8).map(|_| b.next_u32()).collect();
+    let other: Vec<u32> = (0..8).map(|_| c.next_u32()).collect();
+    assert_eq!(first, second);
+    assert!(first != other);
+
+    // The distribution should be roughly uniform
+    let mut sum = 0.0;
+    for _ in 0..10000 {
+        let x = a.next_f32();
+        assert!((0.0..1.0).contains(&x));
+        sum += x;
+    }
+    assert!((sum / 10000.0 - 0.5).abs() < 0.02);
+
+    for _ in 0..100 {
+        assert!(a.next_index(3) < 3);
+    }
+
+    // Samples are independent of each other and of the pixels around them
+    let first = |seed, pixel, sample| Sampler::for_sample(seed, pixel, sample).next_u32();
+    assert_eq!(first(9, 4, 2), first(9, 4, 2));
+    assert!(first(9, 4, 2) != first(9, 4, 3));
+    assert!(first(9, 4, 2) != first(9, 5, 2));
+    assert!(first(9, 4, 2) != first(10, 4, 2));
+}
+
+#[test]
+fn test_cosine_direction() {
+    let normal = Vec3::new(0.0, 1.0, 0.0);
+    let mut sampler = Sampler::new(1, 0);
+    let mut cosine = 0.0;
+    for _ in 0..10000 {
+        let direction = cosine_direction(normal, sampler.next_f32(), sampler.next_f32());
+        assert!((direction.length() - 1.0).abs() < 1e-4);
+        assert!(direction.dot(&normal) >= 0.0);
+        cosine += direction.dot(&normal);
+    }
+    // The average cosine of a cosine weighted hemisphere is 2/3
+    assert!((cosine / 10000.0 - 2.0 / 3.0).abs() < 0.02);
+}
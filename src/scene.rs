@@ -0,0 +1,232 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::f32;
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use hitable::{HitRecord, Hitable};
+use material::Material;
+use ray::Ray;
+use vector::Vec3;
+
+/// What a ray sees when it leaves the scene
+#[derive(Copy, Clone, Debug)]
+pub enum Background {
+    /// A gradient from white at the horizon to blue at the top
+    Sky,
+    /// The same color in every direction. Black makes sure that all of
+    /// the light comes from emissive surfaces
+    Constant(Vec3),
+}
+
+thread_local! {
+    /// Rays traced by the current thread. Every thread counts on its own so
+    /// they never have to wait for each other
+    static RAYS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// How many rays the current thread has traced so far
+pub fn traced_rays() -> u64 {
+    RAYS.with(|rays| rays.get())
+}
+
+/// Everything an integrator needs to know about the world. The geometry
+/// can be any Hitable, which lets the caller decide how it is accelerated.
+/// The lights are the emitters in it that get sampled directly
+pub struct Scene<'a> {
+    pub world: &'a dyn Hitable,
+    pub lights: Vec<&'a dyn Hitable>,
+    pub background: Background,
+    /// Object and material IDs by the address of the object's material
+    ids: HashMap<usize, (u32, u32)>,
+}
+
+impl<'a> Scene<'a> {
+    pub fn new(
+        world: &'a dyn Hitable,
+        lights: Vec<&'a dyn Hitable>,
+        background: Background,
+    ) -> Scene<'a> {
+        Scene {
+            world,
+            lights,
+            background,
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Numbers the objects and their materials in the order they are
+    /// listed, for the ID AOVs. Objects are told apart by the material
+    /// they own, and materials by their kind, texture and parameters
+    pub fn number_objects(&mut self, objects: &[Box<dyn Hitable>]) {
+        let mut materials = HashMap::new();
+        for material in objects.iter().filter_map(|obj| obj.material()) {
+            let address = material as *const Material as usize;
+            if self.ids.contains_key(&address) {
+                continue;
+            }
+            let count = materials.len() as u32;
+            let material_id = *materials.entry(material_key(material)).or_insert(count + 1);
+            let object_id = self.ids.len() as u32 + 1;
+            self.ids.insert(address, (object_id, material_id));
+        }
+    }
+
+    /// The object and material ID of whatever owns the material. Zero for
+    /// both if the objects were never numbered
+    pub fn ids(&self, material: &Material) -> (u32, u32) {
+        if self.ids.is_empty() {
+            return (0, 0);
+        }
+        let address = material as *const Material as usize;
+        self.ids.get(&address).cloned().unwrap_or((0, 0))
+    }
+
+    /// The closest hit along the ray, ignoring anything right at its origin
+    pub fn hit(&self, r: &Ray) -> Option<HitRecord<'_>> {
+        RAYS.with(|rays| rays.set(rays.get() + 1));
+        self.world.hit(r, 0.001, f32::MAX)
+    }
+
+    /// Whether anything is in the way along the ray before `t_max`
+    pub fn any_hit(&self, r: &Ray, t_max: f32) -> bool {
+        RAYS.with(|rays| rays.set(rays.get() + 1));
+        self.world.any_hit(r, 0.001, t_max)
+    }
+
+    pub fn background(&self, r: &Ray) -> Vec3 {
+        match self.background {
+            Background::Sky => {
+                let unit_direction = Vec3::unit_vec(r.direction());
+                let t: f32 = (unit_direction.y() + 1.0) * 0.5;
+                Vec3::new(1.0, 1.0, 1.0) * (1.0 - t) + t * Vec3::new(0.5, 0.7, 1.0)
+            }
+            Background::Constant(color) => color,
+        }
+    }
+
+    /// Feeds the layout of the scene into the hasher: the background, the
+    /// number of lights and the bounds and material of every object in the
+    /// order they are listed. Textures can't be compared, so whatever they
+    /// were loaded or generated from has to be hashed by the caller
+    pub fn fingerprint<H: Hasher>(&self, objects: &[Box<dyn Hitable>], state: &mut H) {
+        match self.background {
+            Background::Sky => state.write_u8(0),
+            Background::Constant(color) => {
+                state.write_u8(1);
+                hash_vec(state, color);
+            }
+        }
+        state.write_usize(self.lights.len());
+
+        state.write_usize(objects.len());
+        for obj in objects {
+            match obj.bounding_box() {
+                Some(bb) => {
+                    state.write_u8(1);
+                    hash_vec(state, bb.min());
+                    hash_vec(state, bb.max());
+                }
+                None => state.write_u8(0),
+            }
+            let (kind, parameter) = match obj.material() {
+                None => (0, 0.0),
+                Some(Material::Lambertian(_)) => (1, 0.0),
+                Some(Material::Metal(_, fuzz)) => (2, *fuzz),
+                Some(Material::Dielectric(_, index)) => (3, *index),
+                Some(Material::DiffuseLight(_, intensity)) => (4, *intensity),
+            };
+            state.write_u8(kind);
+            state.write_u32(parameter.to_bits());
+        }
+    }
+
+    /// The density of picking the direction when a light is chosen uniformly
+    /// and then sampled. Lights that the direction misses contribute zero
+    pub fn light_pdf(&self, origin: Vec3, direction: Vec3) -> f32 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self
+            .lights
+            .iter()
+            .map(|light| light.pdf_value(origin, direction))
+            .sum();
+        sum / self.lights.len() as f32
+    }
+}
+
+/// Materials are the same if they are of the same kind, share their
+/// texture and have the same parameters
+fn material_key(material: &Material) -> (u8, usize, u32) {
+    let (kind, texture, parameter) = match material {
+        Material::Lambertian(texture) => (0, texture, 0.0),
+        Material::Metal(texture, fuzz) => (1, texture, *fuzz),
+        Material::Dielectric(texture, index) => (2, texture, *index),
+        Material::DiffuseLight(texture, intensity) => (3, texture, *intensity),
+    };
+    (
+        kind,
+        Arc::as_ptr(texture) as *const u8 as usize,
+        parameter.to_bits(),
+    )
+}
+
+fn hash_vec<H: Hasher>(state: &mut H, v: Vec3) {
+    state.write_u32(v.x().to_bits());
+    state.write_u32(v.y().to_bits());
+    state.write_u32(v.z().to_bits());
+}
+
+#[test]
+fn test_ids() {
+    use hitable_list::HitableList;
+    use mesh::TriangleMesh;
+    use sphere::Sphere;
+    use texture::Constant;
+
+    let red = Material::Lambertian(Constant::shared(Vec3::new(1.0, 0.0, 0.0)));
+    let mut world = HitableList::new();
+    world.push(Box::new(Sphere::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        1.0,
+        red.clone(),
+    )));
+    world.push(Box::new(Sphere::new(
+        Vec3::new(3.0, 0.0, 0.0),
+        1.0,
+        red.clone(),
+    )));
+    world.push(Box::new(Sphere::new(
+        Vec3::new(6.0, 0.0, 0.0),
+        1.0,
+        Material::Metal(Constant::shared(Vec3::new(1.0, 0.0, 0.0)), 0.5),
+    )));
+    world.push_mesh(TriangleMesh::new(
+        vec![
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::new(1.0, 0.0, 5.0),
+            Vec3::new(0.0, 1.0, 5.0),
+            Vec3::new(1.0, 1.0, 5.0),
+        ],
+        Vec::new(),
+        Vec::new(),
+        vec![0, 1, 2, 1, 3, 2],
+        red,
+    ));
+
+    let mut scene = Scene::new(&world, Vec::new(), Background::Sky);
+    let ids = |scene: &Scene| -> Vec<(u32, u32)> {
+        world
+            .objs
+            .iter()
+            .map(|obj| scene.ids(obj.material().unwrap()))
+            .collect()
+    };
+    assert!(ids(&scene).iter().all(|id| *id == (0, 0)));
+
+    // Copies of a material are the same material, but every sphere is
+    // its own object. The triangles of a mesh make up a single object
+    scene.number_objects(&world.objs);
+    assert_eq!(ids(&scene), vec![(1, 1), (2, 1), (3, 2), (4, 1), (4, 1)]);
+}
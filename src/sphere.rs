@@ -1,7 +1,12 @@
+use std::f32;
+
 use aabb::AABB;
 use hitable::{HitRecord, Hitable};
 use material::Material;
 use ray::Ray;
+use sampler::Sampler;
+#[cfg(test)]
+use texture::Constant;
 use vector::Vec3;
 
 pub struct Sphere {
@@ -15,7 +20,7 @@ pub struct Sphere {
  * can improve the performance of the intersection test code somehow.
  */
 impl Hitable for Sphere {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = r.origin() - self.position;
         let r_dir = r.direction();
         let a = r_dir.dot(&r_dir);
@@ -26,12 +31,14 @@ impl Hitable for Sphere {
         if discriminant > 0.0 {
             let sqt = discriminant.sqrt();
             let mut temp = (-b - sqt) / a;
-            let mut record = HitRecord::new();
+            let mut record = HitRecord::new(&self.material);
             if t_min < temp && temp < t_max {
                 record.t = temp;
                 record.p = r.point_at_t(temp);
                 record.normal = (record.p - self.position) / self.radius;
-                record.material = self.material;
+                let (u, v) = sphere_uv(&record.normal);
+                record.u = u;
+                record.v = v;
                 return Some(record);
             }
             temp = (-b + sqt) / a;
@@ -39,7 +46,9 @@ impl Hitable for Sphere {
                 record.t = temp;
                 record.p = r.point_at_t(temp);
                 record.normal = (record.p - self.position) / self.radius;
-                record.material = self.material;
+                let (u, v) = sphere_uv(&record.normal);
+                record.u = u;
+                record.v = v;
                 return Some(record);
             }
         }
@@ -52,6 +61,35 @@ impl Hitable for Sphere {
             self.position + Vec3::new(self.radius, self.radius, self.radius),
         ))
     }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
+
+    fn is_emitter(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    /// Samples the cone of directions in which the sphere is visible
+    /// instead of its surface, since half of the surface is hidden anyway
+    fn sample_direction(&self, origin: Vec3, sampler: &mut Sampler) -> Option<Vec3> {
+        let cos_max = self.cos_theta_max(origin)?;
+        let phi = 2.0 * f32::consts::PI * sampler.next_f32();
+        let z = 1.0 + sampler.next_f32() * (cos_max - 1.0);
+        let sin = (1.0 - z * z).max(0.0).sqrt();
+
+        let w = Vec3::unit_vec(self.position - origin);
+        let (a, b) = w.orthonormal_basis();
+        Some(a * (phi.cos() * sin) + b * (phi.sin() * sin) + w * z)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3) -> f32 {
+        let ray = Ray::new(origin, direction, 0.0);
+        match (self.cos_theta_max(origin), self.hit(&ray, 0.001, f32::MAX)) {
+            (Some(cos_max), Some(_)) => 1.0 / (2.0 * f32::consts::PI * (1.0 - cos_max)),
+            _ => 0.0,
+        }
+    }
 }
 
 impl Sphere {
@@ -62,6 +100,27 @@ impl Sphere {
             material,
         }
     }
+
+    /// Cosine of the half angle of the cone that the sphere covers when
+    /// seen from the point. None if the point is inside
+    fn cos_theta_max(&self, origin: Vec3) -> Option<f32> {
+        let distance_squared = (self.position - origin).squared_length();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return None;
+        }
+        Some((1.0 - radius_squared / distance_squared).sqrt())
+    }
+}
+
+/// Maps a point on the unit sphere to texture coordinates. u goes around
+/// the y axis starting at -x and v goes from the bottom to the top pole
+fn sphere_uv(p: &Vec3) -> (f32, f32) {
+    let phi = p.z().atan2(p.x());
+    let theta = p.y().clamp(-1.0, 1.0).asin();
+    let u = 1.0 - (phi + f32::consts::PI) / (2.0 * f32::consts::PI);
+    let v = (theta + f32::consts::FRAC_PI_2) / f32::consts::PI;
+    (u, v)
 }
 
 pub struct MovingSphere {
@@ -74,7 +133,7 @@ pub struct MovingSphere {
 }
 
 impl Hitable for MovingSphere {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = r.origin() - self.center(r.time());
         let r_dir = r.direction();
         let a = r_dir.dot(&r_dir);
@@ -85,12 +144,14 @@ impl Hitable for MovingSphere {
         if discriminant > 0.0 {
             let sqt = discriminant.sqrt();
             let mut temp = (-b - sqt) / a;
-            let mut record = HitRecord::new();
+            let mut record = HitRecord::new(&self.material);
             if t_min < temp && temp < t_max {
                 record.t = temp;
                 record.p = r.point_at_t(temp);
                 record.normal = (record.p - self.center(r.time())) / self.radius;
-                record.material = self.material;
+                let (u, v) = sphere_uv(&record.normal);
+                record.u = u;
+                record.v = v;
                 return Some(record);
             }
             temp = (-b + sqt) / a;
@@ -98,15 +159,26 @@ impl Hitable for MovingSphere {
                 record.t = temp;
                 record.p = r.point_at_t(temp);
                 record.normal = (record.p - self.center(r.time())) / self.radius;
-                record.material = self.material;
+                let (u, v) = sphere_uv(&record.normal);
+                record.u = u;
+                record.v = v;
                 return Some(record);
             }
         }
         None
     }
 
+    /// The box has to enclose the sphere over the whole shutter interval,
+    /// so we take the union of the boxes at both ends of the motion
     fn bounding_box(&self) -> Option<AABB> {
-        None
+        let radius = Vec3::new(self.radius, self.radius, self.radius);
+        let box0 = AABB::new(self.center0 - radius, self.center0 + radius);
+        let box1 = AABB::new(self.center1 - radius, self.center1 + radius);
+        Some(AABB::surrounding_box(&box0, &box1))
+    }
+
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
     }
 }
 
@@ -134,3 +206,23 @@ impl MovingSphere {
             + ((time - self.time0) / (self.time1 - self.time0)) * (self.center1 - self.center0)
     }
 }
+
+#[test]
+fn test_uv() {
+    let sphere = Sphere::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        2.0,
+        Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0))),
+    );
+
+    // Hitting the sphere from the +z side lands a quarter of the way around
+    let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let hit = sphere.hit(&ray, 0.001, 10.0).unwrap();
+    assert!((hit.u - 0.25).abs() < 1e-6);
+    assert!((hit.v - 0.5).abs() < 1e-6);
+
+    // The top pole
+    let ray = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+    let hit = sphere.hit(&ray, 0.001, 10.0).unwrap();
+    assert!((hit.v - 1.0).abs() < 1e-6);
+}
@@ -1,16 +1,27 @@
+extern crate png;
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use self::png::{BitDepth, ColorType, DecodingError, HasParameters, Transformations};
 use vector::Vec3;
 
 pub trait Texture {
     fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3;
 }
 
+/// A handle to a texture that can be shared between materials and threads
+pub type TextureRef = Arc<dyn Texture + Send + Sync>;
+
 #[derive(Copy, Clone)]
 pub struct Constant {
     color: Vec3,
 }
 
 impl Texture for Constant {
-    fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
+    fn value(&self, _u: f32, _v: f32, _p: Vec3) -> Vec3 {
         self.color
     }
 }
@@ -19,30 +30,282 @@ impl Constant {
     pub fn new(v: Vec3) -> Constant {
         Constant { color: v }
     }
+
+    /// Shorthand for a shareable texture of a single color
+    pub fn shared(v: Vec3) -> TextureRef {
+        Arc::new(Constant::new(v))
+    }
 }
 
-#[derive(Copy, Clone)]
+/// A 3D checker pattern that alternates between two textures. The pattern
+/// is a function of the position in space rather than of the surface
+/// coordinates, so it looks the same on any kind of geometry
+#[derive(Clone)]
 pub struct Checker {
-    odd: Box<Texture>,
-    even: Box<Texture>,
+    odd: TextureRef,
+    even: TextureRef,
+    scale: f32,
 }
 
 impl Texture for Checker {
     fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
-        let sines = f32::sin(f32::sin(10 * p.x()) * f32::sin(10 * p.y()) * f32::sin(10 * p.z()));
-        if sines < 0.0 {
-            self.odd.value(u, v, p);
+        let cell = (p.x() / self.scale).floor()
+            + (p.y() / self.scale).floor()
+            + (p.z() / self.scale).floor();
+        if cell as i64 % 2 == 0 {
+            self.even.value(u, v, p)
         } else {
-            self.even.value(u, v, p);
+            self.odd.value(u, v, p)
         }
     }
 }
 
 impl Checker {
-    pub fn new(t0: &Box<Texture>, t1: &Box<Texture>) -> Checker {
-        Checker {
-            odd: t0,
-            even: t1,
+    /// The scale is the edge length of a single checker cube
+    pub fn new(odd: TextureRef, even: TextureRef, scale: f32) -> Checker {
+        Checker { odd, even, scale }
+    }
+}
+
+/// How texture coordinates outside of [0, 1] are mapped back onto the image
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WrapMode {
+    /// The image tiles endlessly
+    Repeat,
+    /// The pixels on the border get stretched out
+    Clamp,
+    /// The image tiles but every other copy is flipped
+    Mirror,
+}
+
+/// A texture that looks up its color in an image. The pixels are kept in
+/// linear space and are filtered bilinearly. The v coordinate points up,
+/// so (0, 0) is the lower left corner of the image like in OBJ files
+pub struct ImageTexture {
+    width: u32,
+    height: u32,
+    // Rows are stored from top to bottom
+    pixels: Vec<Vec3>,
+    wrap: WrapMode,
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _p: Vec3) -> Vec3 {
+        // Pixel centers sit at half integer coordinates
+        let x = u * self.width as f32 - 0.5;
+        let y = (1.0 - v) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+impl ImageTexture {
+    /// Creates a texture from linear pixels that are laid out row by row,
+    /// starting with the top row
+    pub fn new(width: u32, height: u32, pixels: Vec<Vec3>, wrap: WrapMode) -> ImageTexture {
+        assert!(width > 0 && height > 0, "Image texture has no pixels");
+        assert_eq!(pixels.len(), (width * height) as usize);
+        ImageTexture {
+            width,
+            height,
+            pixels,
+            wrap,
+        }
+    }
+
+    /// Loads a PNG file. Panics if the file can not be read
+    pub fn open(path: &Path, wrap: WrapMode) -> ImageTexture {
+        let file = match File::open(path) {
+            Err(_) => panic!("Couldn't open {:?}", path),
+            Ok(file) => file,
+        };
+        match ImageTexture::from_png(BufReader::new(file), wrap) {
+            Err(e) => panic!("Couldn't decode {:?}: {:?}", path, e),
+            Ok(texture) => texture,
+        }
+    }
+
+    /// Decodes an 8 or 16-bit PNG image. The colors are assumed to be sRGB
+    /// encoded and get converted to linear space. Alpha is ignored
+    pub fn from_png<R: Read>(reader: R, wrap: WrapMode) -> Result<ImageTexture, DecodingError> {
+        let mut decoder = png::Decoder::new(reader);
+        // The default transformations would strip 16-bit samples down to 8
+        decoder.set(Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info()?;
+        let mut buffer = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buffer)?;
+
+        let channels = match info.color_type {
+            ColorType::Grayscale => 1,
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::RGB => 3,
+            ColorType::RGBA => 4,
+            ColorType::Indexed => {
+                return Err(DecodingError::Format(
+                    "Indexed images are not supported".into(),
+                ))
+            }
+        };
+        let samples: Vec<f32> = match info.bit_depth {
+            BitDepth::Eight => buffer.iter().map(|s| *s as f32 / 255.0).collect(),
+            BitDepth::Sixteen => buffer
+                .chunks(2)
+                .map(|s| ((s[0] as u16) << 8 | s[1] as u16) as f32 / 65535.0)
+                .collect(),
+            _ => return Err(DecodingError::Format("Bit depth is not supported".into())),
+        };
+
+        let pixels = samples
+            .chunks(channels)
+            .map(|s| {
+                let color = if channels < 3 {
+                    Vec3::new(s[0], s[0], s[0])
+                } else {
+                    Vec3::new(s[0], s[1], s[2])
+                };
+                srgb_to_linear(color)
+            })
+            .collect();
+        Ok(ImageTexture::new(info.width, info.height, pixels, wrap))
+    }
+
+    /// Fetches a single pixel. Coordinates outside of the image are
+    /// resolved according to the wrap mode
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = wrap_coordinate(x, self.width as i64, self.wrap);
+        let y = wrap_coordinate(y, self.height as i64, self.wrap);
+        self.pixels[(y * self.width as i64 + x) as usize]
+    }
+}
+
+fn wrap_coordinate(i: i64, size: i64, wrap: WrapMode) -> i64 {
+    match wrap {
+        WrapMode::Repeat => i.rem_euclid(size),
+        WrapMode::Clamp => i.clamp(0, size - 1),
+        WrapMode::Mirror => {
+            let i = i.rem_euclid(2 * size);
+            if i < size {
+                i
+            } else {
+                2 * size - 1 - i
+            }
         }
     }
 }
+
+/// The exact sRGB transfer function, applied to every channel
+pub fn srgb_to_linear(color: Vec3) -> Vec3 {
+    let convert = |c: f32| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    Vec3::new(convert(color.x()), convert(color.y()), convert(color.z()))
+}
+
+#[test]
+fn test_constant() {
+    let texture = Constant::new(Vec3::new(0.1, 0.2, 0.3));
+    assert_eq!(
+        texture.value(0.5, 0.5, Vec3::new(1.0, 2.0, 3.0)),
+        Vec3::new(0.1, 0.2, 0.3)
+    );
+}
+
+#[test]
+fn test_checker() {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let checker = Checker::new(Constant::shared(black), Constant::shared(white), 0.5);
+
+    assert_eq!(checker.value(0.0, 0.0, Vec3::new(0.1, 0.1, 0.1)), white);
+    assert_eq!(checker.value(0.0, 0.0, Vec3::new(0.6, 0.1, 0.1)), black);
+    assert_eq!(checker.value(0.0, 0.0, Vec3::new(0.6, 0.6, 0.1)), white);
+    assert_eq!(checker.value(0.0, 0.0, Vec3::new(-0.1, 0.1, 0.1)), black);
+    assert_eq!(checker.value(0.0, 0.0, Vec3::new(0.6, 0.6, 0.6)), black);
+}
+
+/// Encodes a small PNG in memory so the tests do not need image files
+#[cfg(test)]
+pub fn encode_png(
+    width: u32,
+    height: u32,
+    color: ColorType,
+    depth: BitDepth,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set(color).set(depth);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+    }
+    bytes
+}
+
+#[test]
+fn test_image_decoding() {
+    // A black and a white pixel on top of a red and a mid gray one
+    let data = [0, 0, 0, 255, 255, 255, 255, 0, 0, 188, 188, 188];
+    let bytes = encode_png(2, 2, ColorType::RGB, BitDepth::Eight, &data);
+    let image = ImageTexture::from_png(&bytes[..], WrapMode::Clamp).unwrap();
+    assert_eq!((image.width, image.height), (2, 2));
+
+    let p = Vec3::new(0.0, 0.0, 0.0);
+    assert_eq!(image.value(0.25, 0.75, p), Vec3::new(0.0, 0.0, 0.0));
+    assert_eq!(image.value(0.75, 0.75, p), Vec3::new(1.0, 1.0, 1.0));
+    assert_eq!(image.value(0.25, 0.25, p), Vec3::new(1.0, 0.0, 0.0));
+    // sRGB 188 is about half as bright in linear space
+    assert!((image.value(0.75, 0.25, p).x() - 0.5).abs() < 0.01);
+
+    // 16-bit samples with alpha decode to the same colors
+    let data: Vec<u8> = [0, 0, 0, 65535, 65535, 65535, 65535, 65535]
+        .iter()
+        .flat_map(|s: &u16| vec![(s >> 8) as u8, *s as u8])
+        .collect();
+    let bytes = encode_png(2, 1, ColorType::RGBA, BitDepth::Sixteen, &data);
+    let image = ImageTexture::from_png(&bytes[..], WrapMode::Clamp).unwrap();
+    assert_eq!(image.value(0.25, 0.5, p), Vec3::new(0.0, 0.0, 0.0));
+    assert_eq!(image.value(0.75, 0.5, p), Vec3::new(1.0, 1.0, 1.0));
+}
+
+#[test]
+fn test_image_filtering() {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let p = black;
+    let image = |wrap| ImageTexture::new(2, 1, vec![black, white], wrap);
+
+    // Halfway between two pixel centers we get the average
+    assert_eq!(image(WrapMode::Clamp).value(0.5, 0.5, p), white * 0.5);
+
+    // Left of the first pixel center the modes disagree
+    assert_eq!(image(WrapMode::Clamp).value(0.0, 0.5, p), black);
+    assert_eq!(image(WrapMode::Repeat).value(0.0, 0.5, p), white * 0.5);
+    assert_eq!(image(WrapMode::Mirror).value(0.0, 0.5, p), black);
+
+    // Outside of the texture
+    assert_eq!(image(WrapMode::Clamp).value(1.75, 0.5, p), white);
+    assert_eq!(image(WrapMode::Repeat).value(1.25, 0.5, p), black);
+    assert_eq!(image(WrapMode::Mirror).value(1.25, 0.5, p), white);
+    assert_eq!(image(WrapMode::Mirror).value(-0.25, 0.5, p), black);
+}
+
+#[test]
+fn test_wrap_coordinate() {
+    assert_eq!(wrap_coordinate(-1, 4, WrapMode::Repeat), 3);
+    assert_eq!(wrap_coordinate(5, 4, WrapMode::Repeat), 1);
+    assert_eq!(wrap_coordinate(-1, 4, WrapMode::Clamp), 0);
+    assert_eq!(wrap_coordinate(5, 4, WrapMode::Clamp), 3);
+    assert_eq!(wrap_coordinate(-1, 4, WrapMode::Mirror), 0);
+    assert_eq!(wrap_coordinate(5, 4, WrapMode::Mirror), 2);
+    assert_eq!(wrap_coordinate(8, 4, WrapMode::Mirror), 0);
+}
@@ -0,0 +1,166 @@
+/// A rectangle of pixels that is rendered as one unit of work. The end
+/// coordinates are exclusive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+/// The order in which tiles are handed out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    /// Row by row from the top left
+    Scanline,
+    /// Outwards from the center, which is usually the interesting part
+    Spiral,
+    /// Along a Hilbert curve, so neighbouring tiles are rendered close
+    /// together in time
+    Hilbert,
+}
+
+/// Splits an image into tiles of at most `size` pixels per side. Tiles
+/// along the right and bottom edges are cut to fit
+pub fn tiles(width: u32, height: u32, size: u32, order: TileOrder) -> Vec<Tile> {
+    let size = size.max(1);
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+
+    let coordinates = match order {
+        TileOrder::Scanline => (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .collect(),
+        TileOrder::Spiral => spiral(columns, rows),
+        TileOrder::Hilbert => hilbert(columns, rows),
+    };
+
+    coordinates
+        .into_iter()
+        .map(|(column, row)| Tile {
+            x0: column * size,
+            y0: row * size,
+            x1: ((column + 1) * size).min(width),
+            y1: ((row + 1) * size).min(height),
+        })
+        .collect()
+}
+
+/// Walks a square spiral around the center tile, keeping the steps that
+/// land inside the grid
+fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let total = columns as usize * rows as usize;
+    let mut order = Vec::with_capacity(total);
+    let (mut x, mut y) = (((columns as i64) - 1) / 2, ((rows as i64) - 1) / 2);
+    // Right, down, left, up
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut direction = 0;
+    let mut length = 1;
+
+    while order.len() < total {
+        // Every length is walked twice before it grows
+        for _ in 0..2 {
+            let (dx, dy) = directions[direction];
+            for _ in 0..length {
+                if x >= 0 && y >= 0 && x < columns as i64 && y < rows as i64 {
+                    order.push((x as u32, y as u32));
+                }
+                x += dx;
+                y += dy;
+            }
+            direction = (direction + 1) % 4;
+        }
+        length += 1;
+    }
+
+    order
+}
+
+/// Follows a Hilbert curve over the smallest power of two square that
+/// covers the grid, keeping the cells inside of it
+fn hilbert(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let n = columns.max(rows).next_power_of_two() as u64;
+    (0..n * n)
+        .map(|d| hilbert_point(n, d))
+        .filter(|&(x, y)| x < columns as u64 && y < rows as u64)
+        .map(|(x, y)| (x as u32, y as u32))
+        .collect()
+}
+
+/// The cell at distance `d` along a Hilbert curve over an n by n grid
+fn hilbert_point(n: u64, d: u64) -> (u64, u64) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        // Rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+#[test]
+fn test_tiles() {
+    for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+        let tiles = tiles(70, 45, 16, *order);
+        assert_eq!(tiles.len(), 5 * 3);
+
+        // Every pixel is covered exactly once
+        let mut covered = vec![0; 70 * 45];
+        for tile in tiles.iter() {
+            assert!(tile.x1 - tile.x0 <= 16 && tile.y1 - tile.y0 <= 16);
+            for y in tile.y0..tile.y1 {
+                for x in tile.x0..tile.x1 {
+                    covered[y as usize * 70 + x as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|c| *c == 1));
+    }
+
+    let scanline = tiles(70, 45, 16, TileOrder::Scanline);
+    assert_eq!(scanline[1].x0, 16);
+    assert_eq!(scanline[14].x1, 70);
+    assert_eq!(scanline[14].y1, 45);
+}
+
+#[test]
+fn test_orders() {
+    // The spiral starts in the middle and circles around it
+    assert_eq!(
+        spiral(3, 3),
+        vec![
+            (1, 1),
+            (2, 1),
+            (2, 2),
+            (1, 2),
+            (0, 2),
+            (0, 1),
+            (0, 0),
+            (1, 0),
+            (2, 0)
+        ]
+    );
+
+    // Consecutive cells on the Hilbert curve are always neighbours
+    let curve = hilbert(8, 8);
+    assert_eq!(curve.len(), 64);
+    assert_eq!(curve[0], (0, 0));
+    for pair in curve.windows(2) {
+        let dx = (pair[0].0 as i64 - pair[1].0 as i64).abs();
+        let dy = (pair[0].1 as i64 - pair[1].1 as i64).abs();
+        assert_eq!(dx + dy, 1);
+    }
+}
@@ -0,0 +1,246 @@
+use vector::Vec3;
+
+/// Compresses scene brightness into the displayable range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    /// Only clips what is too bright
+    Clamp,
+    /// L / (1 + L) on the luminance, which never quite reaches white
+    Reinhard,
+    /// Reinhard that maps the given luminance to white
+    ExtendedReinhard(f32),
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve
+    Aces,
+    /// John Hable's filmic curve from Uncharted 2
+    Hable,
+}
+
+/// The space the colors are encoded in on their way out. Rendering happens
+/// in linear sRGB, or Rec. 709, which shares its primaries
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace {
+    /// sRGB primaries and transfer function
+    Srgb,
+    /// P3 primaries with the sRGB transfer function
+    DisplayP3,
+    /// Rec. 2020 primaries and transfer function
+    Rec2020,
+    /// sRGB primaries without any transfer function
+    Linear,
+}
+
+impl ColorSpace {
+    /// Converts linear sRGB into linear values with our primaries
+    fn convert(self, c: Vec3) -> Vec3 {
+        let m = match self {
+            ColorSpace::Srgb | ColorSpace::Linear => return c,
+            ColorSpace::DisplayP3 => [
+                [0.822_462, 0.177_538, 0.0],
+                [0.033_194, 0.966_806, 0.0],
+                [0.017_083, 0.072_397, 0.910_520],
+            ],
+            ColorSpace::Rec2020 => [
+                [0.627_404, 0.329_282, 0.043_314],
+                [0.069_097, 0.919_540, 0.011_361],
+                [0.016_392, 0.088_013, 0.895_595],
+            ],
+        };
+        Vec3::new(
+            m[0][0] * c.x() + m[0][1] * c.y() + m[0][2] * c.z(),
+            m[1][0] * c.x() + m[1][1] * c.y() + m[1][2] * c.z(),
+            m[2][0] * c.x() + m[2][1] * c.y() + m[2][2] * c.z(),
+        )
+    }
+
+    /// The transfer function from linear light to the encoded signal
+    fn encode(self, v: f32) -> f32 {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => srgb_encode(v),
+            ColorSpace::Rec2020 => {
+                if v < 0.018_053_97 {
+                    4.5 * v
+                } else {
+                    1.099_297 * v.powf(0.45) - 0.099_297
+                }
+            }
+            ColorSpace::Linear => v,
+        }
+    }
+}
+
+/// The exact piecewise sRGB transfer function, with its linear toe
+pub fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Everything that happens to the linear colors of the film before they
+/// are written out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneMapping {
+    /// In stops, so every step doubles the brightness
+    pub exposure: f32,
+    pub operator: Operator,
+    pub color_space: ColorSpace,
+}
+
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping {
+            exposure: 0.0,
+            operator: Operator::Clamp,
+            color_space: ColorSpace::Srgb,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Exposure and primaries only, for formats that keep the full range
+    pub fn linear(&self, color: Vec3) -> Vec3 {
+        self.color_space.convert(color * 2f32.powf(self.exposure))
+    }
+
+    /// Exposure, tone mapping, primaries and transfer function. The result
+    /// is within [0, 1] and ready to be quantized
+    pub fn display(&self, color: Vec3) -> Vec3 {
+        let color = color * 2f32.powf(self.exposure);
+        let mapped = match self.operator {
+            Operator::Clamp => color,
+            Operator::Reinhard => scale_luminance(color, |l| l / (1.0 + l)),
+            Operator::ExtendedReinhard(white) => {
+                let white = white.max(1e-4);
+                scale_luminance(color, |l| l * (1.0 + l / (white * white)) / (1.0 + l))
+            }
+            Operator::Aces => per_channel(color, aces),
+            Operator::Hable => {
+                // The curve is applied to twice the exposure, and
+                // normalized so 11.2 ends up white
+                let white = hable(11.2);
+                per_channel(color, |v| hable(2.0 * v) / white)
+            }
+        };
+
+        let color = self.color_space.convert(mapped);
+        per_channel(color, |v| {
+            // NaN ends up as black
+            let v = if v > 0.0 { v.min(1.0) } else { 0.0 };
+            self.color_space.encode(v)
+        })
+    }
+}
+
+fn per_channel<F: Fn(f32) -> f32>(color: Vec3, f: F) -> Vec3 {
+    Vec3::new(f(color.x()), f(color.y()), f(color.z()))
+}
+
+/// Maps the luminance and scales the color along with it, which keeps
+/// the hue intact
+fn scale_luminance<F: Fn(f32) -> f32>(color: Vec3, f: F) -> Vec3 {
+    let luminance = color.luminance();
+    if luminance <= 0.0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    color * (f(luminance) / luminance)
+}
+
+fn aces(v: f32) -> f32 {
+    (v * (2.51 * v + 0.03)) / (v * (2.43 * v + 0.59) + 0.14)
+}
+
+fn hable(v: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.5, 0.1, 0.2, 0.02, 0.3);
+    ((v * (a * v + c * b) + d * e) / (v * (a * v + b) + d * f)) - e / f
+}
+
+#[test]
+fn test_transfer() {
+    assert_eq!(srgb_encode(0.0), 0.0);
+    assert!((srgb_encode(1.0) - 1.0).abs() < 1e-6);
+    // Mid gray and both sides of the toe
+    assert!((srgb_encode(0.18) - 0.461_356).abs() < 1e-4);
+    assert!((srgb_encode(0.003_130_8) - 0.040_45).abs() < 1e-4);
+    assert!((srgb_encode(0.003_130_9) - 0.040_45).abs() < 1e-4);
+
+    let rec2020 = ColorSpace::Rec2020;
+    assert!((rec2020.encode(1.0) - 1.0).abs() < 1e-5);
+    assert!((rec2020.encode(0.01) - 0.045).abs() < 1e-6);
+}
+
+#[test]
+fn test_operators() {
+    let tone = |operator| ToneMapping {
+        operator,
+        color_space: ColorSpace::Linear,
+        ..ToneMapping::default()
+    };
+    let gray = |v| Vec3::new(v, v, v);
+    let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-3;
+
+    assert_eq!(tone(Operator::Clamp).display(gray(4.0)), gray(1.0));
+    assert!(close(
+        tone(Operator::Reinhard).display(gray(1.0)),
+        gray(0.5)
+    ));
+    assert!(close(
+        tone(Operator::Reinhard).display(gray(3.0)),
+        gray(0.75)
+    ));
+    assert!(close(
+        tone(Operator::ExtendedReinhard(4.0)).display(gray(4.0)),
+        gray(1.0)
+    ));
+    assert!(close(tone(Operator::Aces).display(gray(0.0)), gray(0.0)));
+    assert!(close(tone(Operator::Aces).display(gray(1000.0)), gray(1.0)));
+    assert!(close(tone(Operator::Hable).display(gray(5.6)), gray(1.0)));
+
+    // Every operator keeps getting brighter
+    for operator in [
+        Operator::Reinhard,
+        Operator::ExtendedReinhard(8.0),
+        Operator::Aces,
+        Operator::Hable,
+    ]
+    .iter()
+    {
+        let mut previous = 0.0;
+        for i in 1..50 {
+            let v = tone(*operator).display(gray(i as f32 * 0.1)).x();
+            assert!(v > previous);
+            previous = v;
+        }
+    }
+
+    // Reinhard keeps the hue
+    let color = tone(Operator::Reinhard).display(Vec3::new(2.0, 1.0, 0.0));
+    assert!((color.x() / color.y() - 2.0).abs() < 1e-5);
+}
+
+#[test]
+fn test_color_spaces() {
+    // Every step of exposure doubles the brightness
+    let tone = ToneMapping {
+        exposure: 2.0,
+        color_space: ColorSpace::Linear,
+        ..ToneMapping::default()
+    };
+    assert_eq!(
+        tone.linear(Vec3::new(0.1, 0.2, 0.25)),
+        Vec3::new(0.4, 0.8, 1.0)
+    );
+
+    // White stays white in every space and pure sRGB red is inside of
+    // the wider gamuts
+    for space in [ColorSpace::DisplayP3, ColorSpace::Rec2020].iter() {
+        let tone = ToneMapping {
+            color_space: *space,
+            ..ToneMapping::default()
+        };
+        let white = tone.linear(Vec3::new(1.0, 1.0, 1.0));
+        assert!((white - Vec3::new(1.0, 1.0, 1.0)).length() < 1e-4);
+        let red = tone.linear(Vec3::new(1.0, 0.0, 0.0));
+        assert!(red.x() < 1.0 && red.y() > 0.0 && red.z() > 0.0);
+    }
+}
@@ -1,9 +1,18 @@
+use std::f32;
+
 use aabb::AABB;
-use hitable::{HitRecord, Hitable};
+use hitable::{area_to_solid_angle, HitRecord, Hitable};
 use material::Material;
 use ray::Ray;
+use sampler::Sampler;
+#[cfg(test)]
+use texture::Constant;
 use vector::Vec3;
 
+/// Minimum thickness of a triangle's bounding box along each axis. An axis
+/// aligned triangle would otherwise get a flat box that rays can slip through
+const BOX_PADDING: f32 = 0.0001;
+
 pub struct Triangle {
     v0: Vec3,
     v1: Vec3,
@@ -13,50 +22,135 @@ pub struct Triangle {
 }
 
 impl Hitable for Triangle {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let epsilon = 0.0000001;
-        let edge1 = self.v1 - self.v0;
-        let edge2 = self.v2 - self.v0;
-        let h = &r.direction().cross(&edge2);
-        let a = edge1.dot(&h);
-
-        if a > -epsilon && a < epsilon {
-            return None;
-        }
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let (t, u, v) = intersect(self.v0, self.v1, self.v2, r, t_min, t_max)?;
 
-        let f = 1.0 / a;
-        let s = r.origin() - self.v0;
-        let u = f * (s.dot(&h));
-        if u < 0.0 || u > 1.0 {
-            return None;
-        }
+        let mut record = HitRecord::new(&self.material);
+        record.t = t;
+        record.p = r.point_at_t(t);
+        // The normal always follows the winding order no matter which
+        // side was hit. Dielectrics rely on this to tell if a ray is
+        // entering or leaving the mesh
+        record.normal = Vec3::unit_vec(self.normal());
+        record.barycentric = (u, v);
+        // There are no texture coordinates, so the barycentrics are the
+        // best parameterization we have
+        record.u = u;
+        record.v = v;
+        Some(record)
+    }
 
-        let q = s.cross(&edge1);
-        let v = f * r.direction().dot(&q);
-        if v < 0.0 || u + v > 1.0 {
-            return None;
-        }
+    fn bounding_box(&self) -> Option<AABB> {
+        Some(bounding_box(self.v0, self.v1, self.v2))
+    }
 
-        let t = f * edge2.dot(&q);
-        if t_min < t && t < t_max {
-            let mut record = HitRecord::new();
-            record.p = r.point_at_t(t);
-            record.normal = self.normal();
-            record.material = self.material;
-            return Some(record);
-        }
+    fn material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
 
-        // There is a line intersection but not a ray intersection
-        // according to the wikipedia page of the Moeller-Trumbore algorithm
-        None
+    fn is_emitter(&self) -> bool {
+        self.material.is_emissive()
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
-        // TODO Implement this
-        panic!("Not implemented yet");
+    fn sample_direction(&self, origin: Vec3, sampler: &mut Sampler) -> Option<Vec3> {
+        Some(sample(self.v0, self.v1, self.v2, sampler) - origin)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3) -> f32 {
+        pdf(self.v0, self.v1, self.v2, origin, direction)
+    }
+}
+
+/// Moeller-Trumbore ray triangle intersection. Returns the ray parameter
+/// and the barycentric coordinates of the hit with respect to v1 and v2.
+/// Shared between standalone triangles and the ones living in a mesh
+pub fn intersect(
+    v0: Vec3,
+    v1: Vec3,
+    v2: Vec3,
+    r: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, f32, f32)> {
+    let epsilon = 0.0000001;
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let h = r.direction().cross(&edge2);
+    let a = edge1.dot(&h);
+
+    if a > -epsilon && a < epsilon {
+        return None;
+    }
+
+    let f = 1.0 / a;
+    let s = r.origin() - v0;
+    let u = f * (s.dot(&h));
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(&edge1);
+    let v = f * r.direction().dot(&q);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = f * edge2.dot(&q);
+    if t_min < t && t < t_max {
+        return Some((t, u, v));
+    }
+
+    // There is a line intersection but not a ray intersection
+    // according to the wikipedia page of the Moeller-Trumbore algorithm
+    None
+}
+
+/// Picks a point that is uniformly distributed over the triangle
+pub fn sample(v0: Vec3, v1: Vec3, v2: Vec3, sampler: &mut Sampler) -> Vec3 {
+    let r1 = sampler.next_f32().sqrt();
+    let r2 = sampler.next_f32();
+    v0 * (1.0 - r1) + v1 * (r1 * (1.0 - r2)) + v2 * (r1 * r2)
+}
+
+/// The solid angle density of `sample` ending up in the direction
+pub fn pdf(v0: Vec3, v1: Vec3, v2: Vec3, origin: Vec3, direction: Vec3) -> f32 {
+    let ray = Ray::new(origin, direction, 0.0);
+    match intersect(v0, v1, v2, &ray, 0.001, f32::MAX) {
+        Some((t, _, _)) => {
+            let normal = (v1 - v0).cross(&(v2 - v0));
+            let area = 0.5 * normal.length();
+            area_to_solid_angle(area, direction, t, Vec3::unit_vec(normal))
+        }
+        None => 0.0,
     }
 }
 
+/// The bounding box of the triangle spanned by the three vertices
+pub fn bounding_box(v0: Vec3, v1: Vec3, v2: Vec3) -> AABB {
+    let min = Vec3::new(
+        v0.x().min(v1.x()).min(v2.x()),
+        v0.y().min(v1.y()).min(v2.y()),
+        v0.z().min(v1.z()).min(v2.z()),
+    );
+    let max = Vec3::new(
+        v0.x().max(v1.x()).max(v2.x()),
+        v0.y().max(v1.y()).max(v2.y()),
+        v0.z().max(v1.z()).max(v2.z()),
+    );
+
+    // Pad the axes on which the triangle is flat
+    let pad = |axis: usize| {
+        if max[axis] - min[axis] < BOX_PADDING {
+            BOX_PADDING * 0.5
+        } else {
+            0.0
+        }
+    };
+    let padding = Vec3::new(pad(0), pad(1), pad(2));
+
+    AABB::new(min - padding, max + padding)
+}
+
 impl Triangle {
     pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Material) -> Triangle {
         let u = v1 - v0;
@@ -87,9 +181,79 @@ fn test_norm() {
         Vec3::new(1.0, 0.0, 0.0),
         Vec3::new(0.0, 1.0, 0.0),
         Vec3::new(0.0, 0.0, 1.0),
-        Material::Lambertian(Vec3::new(1.0, 1.0, 1.0)),
+        Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0))),
     );
 
     let norm = triangle.normal();
     assert_eq!(norm, Vec3::new(1.0, 1.0, 1.0));
 }
+
+#[test]
+fn test_hit_record() {
+    let triangle = Triangle::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(1.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0))),
+    );
+
+    let ray = Ray::new(Vec3::new(0.25, 0.5, 2.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    let front = triangle.hit(&ray, 0.001, 10.0).unwrap();
+    assert_eq!(front.t, 2.0);
+    assert_eq!(front.p, Vec3::new(0.25, 0.5, 0.0));
+    assert_eq!(front.barycentric, (0.25, 0.5));
+    assert_eq!(front.normal, Vec3::new(0.0, 0.0, 1.0));
+
+    // Hitting the back side keeps the orientation of the normal
+    let ray = Ray::new(Vec3::new(0.25, 0.5, -2.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+    let back = triangle.hit(&ray, 0.001, 10.0).unwrap();
+    assert_eq!(back.normal, front.normal);
+
+    // Out of range
+    assert!(triangle.hit(&ray, 0.001, 1.0).is_none());
+}
+
+#[test]
+fn test_bounding_box() {
+    // The triangle lies in the z = 1 plane so its box has to be padded
+    let triangle = Triangle::new(
+        Vec3::new(-1.0, 0.0, 1.0),
+        Vec3::new(2.0, 0.0, 1.0),
+        Vec3::new(0.0, 3.0, 1.0),
+        Material::Lambertian(Constant::shared(Vec3::new(1.0, 1.0, 1.0))),
+    );
+
+    let bb = triangle.bounding_box().unwrap();
+    assert_eq!(bb.min().x(), -1.0);
+    assert_eq!(bb.max().y(), 3.0);
+    assert!(bb.min().z() < 1.0 && bb.max().z() > 1.0);
+
+    let ray = Ray::new(Vec3::new(0.0, 1.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+    assert!(bb.hit(&ray, 0.001, 10.0));
+}
+
+#[test]
+fn test_sampling() {
+    // A small triangle far away covers a solid angle of about its area
+    // over the squared distance
+    let triangle = Triangle::new(
+        Vec3::new(0.0, 0.0, -10.0),
+        Vec3::new(1.0, 0.0, -10.0),
+        Vec3::new(0.0, 1.0, -10.0),
+        Material::DiffuseLight(Constant::shared(Vec3::new(1.0, 1.0, 1.0)), 1.0),
+    );
+    assert!(triangle.is_emitter());
+
+    let origin = Vec3::new(0.0, 0.0, 0.0);
+    let mut sampler = Sampler::new(3, 0);
+    let mut solid_angle = 0.0;
+    for _ in 0..1000 {
+        let direction = triangle.sample_direction(origin, &mut sampler).unwrap();
+        assert!(triangle
+            .hit(&Ray::new(origin, direction, 0.0), 0.001, 10.0)
+            .is_some());
+        solid_angle += 1.0 / triangle.pdf_value(origin, direction);
+    }
+    assert!((solid_angle / 1000.0 - 0.005).abs() < 0.0002);
+    assert_eq!(triangle.pdf_value(origin, Vec3::new(0.0, 0.0, 1.0)), 0.0);
+}
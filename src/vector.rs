@@ -1,6 +1,6 @@
 use std::{cmp, ops, panic};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone)]
 pub struct Vec3 {
     x: f32,
     y: f32,
@@ -190,7 +190,25 @@ impl Vec3 {
 
     pub fn reflect(&self, normal: Vec3) -> Vec3 {
         let r = normal * (self.dot(&normal) * 2.0);
-        self.clone() - r
+        *self - r
+    }
+
+    /// Two unit vectors that are perpendicular to each other and to this
+    /// one, which has to be of unit length. Follows "Building an
+    /// Orthonormal Basis, Revisited" by Duff et al.
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let sign = 1.0f32.copysign(self.z);
+        let a = -1.0 / (sign + self.z);
+        let b = self.x * self.y * a;
+        (
+            Vec3::new(1.0 + sign * self.x * self.x * a, sign * b, -sign * self.x),
+            Vec3::new(b, sign + self.y * self.y * a, -self.y),
+        )
+    }
+
+    /// Perceived brightness of a linear RGB color, using the Rec. 709 weights
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
     }
 
     pub fn squared_length(&self) -> f32 {
@@ -259,6 +277,18 @@ fn test_undefined() {
 }
 
 #[test]
+fn test_luminance() {
+    // The Rec. 709 weights of the primaries add up to one
+    assert_eq!(Vec3::new(1.0, 0.0, 0.0).luminance(), 0.2126);
+    assert_eq!(Vec3::new(0.0, 1.0, 0.0).luminance(), 0.7152);
+    assert_eq!(Vec3::new(0.0, 0.0, 1.0).luminance(), 0.0722);
+    assert!((Vec3::new(1.0, 1.0, 1.0).luminance() - 1.0).abs() < 1e-6);
+    assert_eq!(Vec3::new(0.0, 0.0, 0.0).luminance(), 0.0);
+}
+
+#[test]
+// The expected length is spelled out, not taken from the constants
+#[allow(clippy::approx_constant)]
 fn test_functionality() {
     let v1 = Vec3::new(1.0, 0.0, 1.0);
     let v2 = Vec3::new(1.0, 1.0, 1.0);
@@ -274,3 +304,22 @@ fn test_functionality() {
     assert_eq!(v2.squared_length(), 3.0);
     assert_eq!(Vec3::unit_vec(v1), Vec3::new(0.70710677, 0.0, 0.70710677));
 }
+
+#[test]
+fn test_orthonormal_basis() {
+    // Including the flipped pole
+    let normals = [
+        Vec3::new(1.0, 0.0, 1.0),
+        Vec3::new(1.0, 1.0, 1.0),
+        Vec3::new(0.0, 0.0, -1.0),
+    ];
+    for n in normals.iter() {
+        let n = Vec3::unit_vec(*n);
+        let (a, b) = n.orthonormal_basis();
+        assert!((a.length() - 1.0).abs() < 1e-6);
+        assert!((b.length() - 1.0).abs() < 1e-6);
+        assert!(a.dot(&b).abs() < 1e-6);
+        assert!(a.dot(&n).abs() < 1e-6);
+        assert!(b.dot(&n).abs() < 1e-6);
+    }
+}